@@ -40,6 +40,11 @@ case $key in
     shift # past argument
     shift # past value
     ;;
+    -f|-flight-recorder)
+    FLIGHT_RECORDER="$2"
+    shift # past argument
+    shift # past value
+    ;;
     *)    # unknown option
     POSITIONAL+=("$1") # save it in an array for later
     shift # past argument
@@ -70,6 +75,9 @@ ATTACHER_PATH=$PROJECT_PATH/lib/flare-attacher-jar-with-dependencies.jar
 AGENT_PATH=$PROJECT_PATH/lib/libflareagent$LIB_SUFFIX
 
 AGENT_OPTS="trace=on,interval=$INTERVAL,address=$ADDRESS"
+if [[ "$FLIGHT_RECORDER" != "" ]];then
+    AGENT_OPTS="$AGENT_OPTS,flight_recorder=$FLIGHT_RECORDER"
+fi
 
 echo "AGENT_PATH: $AGENT_PATH"
 echo "AGENT_OPTS: $AGENT_OPTS"
@@ -88,6 +96,7 @@ echo "Usage: ./start-agent.sh <pid> [options]"
 echo "Options:"
 echo "     -interval <sample interval>    # sample interval(ms), default value is 5"
 echo "     -address  <agent address>      # agent bind address, default value is 0.0.0.0:3333"
+echo "     -flight-recorder <minutes>     # keep samples of last minutes in memory, dump or replay them later"
 exit 1
 
 # end: #
@@ -102,6 +102,7 @@ impl Agent {
         self.jvm_env.set_event_notification_mode(VMEvent::GarbageCollectionStart, false);
         self.jvm_env.set_event_notification_mode(VMEvent::GarbageCollectionFinish, false);
         self.jvm_env.set_event_notification_mode(VMEvent::ClassFileLoadHook, false);
+        self.jvm_env.set_event_notification_mode(VMEvent::ClassPrepare, false);
         println!("Jvmti event tracing is stopped.")
     }
 
@@ -154,6 +155,7 @@ impl Agent {
                 self.jvm_env.set_event_notification_mode(VMEvent::GarbageCollectionStart, self.callbacks.garbage_collection_start.is_some());
                 self.jvm_env.set_event_notification_mode(VMEvent::GarbageCollectionFinish, self.callbacks.garbage_collection_finish.is_some());
                 self.jvm_env.set_event_notification_mode(VMEvent::ClassFileLoadHook, self.callbacks.class_file_load_hook.is_some());
+                self.jvm_env.set_event_notification_mode(VMEvent::ClassPrepare, self.callbacks.class_prepare.is_some());
                 println!("Jvmti event tracing is started.")
             },
             Some(error) => println!("Couldn't register callbacks: {}", translate_error(&error))
@@ -277,4 +279,8 @@ impl Agent {
     pub fn on_class_file_load(&mut self, handler: Option<FnClassFileLoad>) {
         self.callbacks.class_file_load_hook = handler;
     }
+
+    pub fn on_class_prepare(&mut self, handler: Option<FnClassPrepare>) {
+        self.callbacks.class_prepare = handler;
+    }
 }
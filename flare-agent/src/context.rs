@@ -1,5 +1,6 @@
 use super::config::Config;
 use super::thread::ThreadId;
+use native::JavaLong;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use time::Duration;
@@ -53,20 +54,25 @@ impl AgentContext {
         self.config.read().unwrap().trace_enable
     }
 
-    pub fn thread_start(&self, thread_id: &ThreadId) {
+    ///
+    /// Thread lifetimes are keyed by java thread id, the native `jthread` is a local reference
+    /// and differs between the ThreadStart and ThreadEnd callbacks of the same thread.
+    ///
+    pub fn thread_start(&self, thread_id: JavaLong) {
         match self.context.write() {
             Ok(mut ctx) => {
-                (*ctx).thread_lifetime.insert((*thread_id).clone(), now());
+                (*ctx).thread_lifetime.insert(thread_id, now());
             },
             Err(_) => { /* TODO: Ignore for now */ }
         }
     }
 
-    pub fn thread_end(&self, thread_id: &ThreadId) -> Option<Duration> {
+    /// Returns the lifetime of the thread, or `None` if it was started before the agent was loaded
+    pub fn thread_end(&self, thread_id: JavaLong) -> Option<Duration> {
         match self.context.write() {
             Ok(mut ctx) => {
                 let now = now();
-                Some((*ctx).thread_lifetime.remove(thread_id).unwrap_or(now) - now)
+                (*ctx).thread_lifetime.remove(&thread_id).map(|start| now - start)
             },
             Err(_) => { None /* TODO: Ignore for now */ }
         }
@@ -153,7 +159,7 @@ impl AgentContext {
 }
 
 pub struct Context {
-    pub thread_lifetime: HashMap<ThreadId, Tm>,
+    pub thread_lifetime: HashMap<JavaLong, Tm>,
     pub monitor_queue: HashMap<ThreadId, Tm>,
     pub thread_wait: HashMap<ThreadId, Tm>,
     pub method_times: HashMap<ThreadId, Vec<Tm>>,
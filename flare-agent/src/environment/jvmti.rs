@@ -9,8 +9,9 @@ use super::super::thread::{ThreadId, Thread};
 use super::super::util::stringify;
 use super::super::version::VersionNumber;
 use super::super::native::{MutString, MutByteArray, JavaClass, JavaObject, JavaInstance, JavaLong, JavaThread, JVMTIEnvPtr, JavaInt};
-use super::super::native::jvmti_native::{Struct__jvmtiThreadInfo, jvmtiCapabilities, jint, jvmtiStackInfo, jthread, jvmtiFrameInfo, jlong, jvmtiTimerInfo};
+use super::super::native::jvmti_native::{Struct__jvmtiThreadInfo, jvmtiCapabilities, jint, jvmtiStackInfo, jthread, jvmtiFrameInfo, jlong, jvmtiTimerInfo, jmethodID};
 use std::ptr;
+use std::ffi::CString;
 use native::jvmti_native::*;
 use std::os::raw::{c_char, c_uchar, c_void};
 use native::{JavaMethod, JNIEnvPtr};
@@ -52,6 +53,8 @@ pub trait JVMTI {
     fn get_method_declaring_class(&self, method_id: &MethodId) -> Result<ClassId, NativeError>;
     fn get_method_name(&self, method_id: &MethodId) -> Result<MethodSignature, NativeError>;
     fn get_class_signature(&self, class_id: &ClassId) -> Result<ClassSignature, NativeError>;
+    fn get_class_methods(&self, class_id: &ClassId) -> Result<Vec<MethodId>, NativeError>;
+    fn get_system_property(&self, property: &str) -> Result<String, NativeError>;
     fn allocate(&self, len: usize) -> Result<MemoryAllocation, NativeError>;
     fn deallocate(&self, ptr: *mut i8);
 
@@ -151,6 +154,7 @@ impl JVMTI for JVMTIEnvironment {
         register_garbage_collection_start(callbacks.garbage_collection_start);
         register_garbage_collection_finish(callbacks.garbage_collection_finish);
         register_class_file_load_hook(callbacks.class_file_load_hook);
+        register_class_prepare_callback(callbacks.class_prepare);
 
         let (native_callbacks, callbacks_size) = registered_callbacks();
 
@@ -265,6 +269,44 @@ impl JVMTI for JVMTIEnvironment {
         }
     }
 
+    fn get_class_methods(&self, class_id: &ClassId) -> Result<Vec<MethodId>, NativeError> {
+        let mut method_count: jint = 0;
+        let mut methods_ptr: *mut jmethodID = ptr::null_mut();
+
+        unsafe {
+            match wrap_error((**self.jvmti).GetClassMethods.unwrap()(self.jvmti, class_id.native_id, &mut method_count, &mut methods_ptr)) {
+                NativeError::NoError => {
+                    let mut methods = vec![];
+                    if method_count > 0 {
+                        let methods_array = std::slice::from_raw_parts(methods_ptr, method_count as usize);
+                        for method in methods_array {
+                            methods.push(MethodId { native_id: *method })
+                        }
+                    }
+                    self.deallocate(methods_ptr as *mut i8);
+                    Ok(methods)
+                },
+                err @ _ => Err(err)
+            }
+        }
+    }
+
+    fn get_system_property(&self, property: &str) -> Result<String, NativeError> {
+        let property = CString::new(property).expect("CString::new failed");
+        let mut value: MutString = ptr::null_mut();
+
+        unsafe {
+            match wrap_error((**self.jvmti).GetSystemProperty.unwrap()(self.jvmti, property.as_ptr(), &mut value)) {
+                NativeError::NoError => {
+                    let result = stringify(value);
+                    self.deallocate(value);
+                    Ok(result)
+                },
+                err @ _ => Err(err)
+            }
+        }
+    }
+
     fn allocate(&self, len: usize) -> Result<MemoryAllocation, NativeError> {
         let size: JavaLong = len as JavaLong;
         let mut ptr: MutByteArray = ptr::null_mut();
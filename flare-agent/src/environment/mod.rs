@@ -142,6 +142,14 @@ impl Environment {
         self.jvmti.get_class_signature(class_id)
     }
 
+    pub fn get_class_methods(&self, class_id: &ClassId) -> Result<Vec<MethodId>, NativeError> {
+        self.jvmti.get_class_methods(class_id)
+    }
+
+    pub fn get_system_property(&self, property: &str) -> Result<String, NativeError> {
+        self.jvmti.get_system_property(property)
+    }
+
     pub fn allocate(&self, len: usize) -> Result<MemoryAllocation, NativeError> {
         self.jvmti.allocate(len)
     }
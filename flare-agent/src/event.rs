@@ -23,7 +23,7 @@ pub type FnGarbageCollectionStart = fn() -> ();
 pub type FnGarbageCollectionFinish = fn() -> ();
 pub type FnClassFileLoad = fn(event: ClassFileLoadEvent) -> Option<Vec<u8>>;
 pub type FnClassLoad = fn() -> ();
-pub type FnClassPrepare = fn() -> ();
+pub type FnClassPrepare = fn(event: ClassPrepareEvent) -> ();
 pub type FnSingleStep = fn() -> ();
 pub type FnFramePop = fn() -> ();
 pub type FnBreakpoint = fn() -> ();
@@ -3,6 +3,7 @@ use super::environment::jni::{JNI, JNIEnvironment};
 use super::environment::jvmti::{JVMTI, JVMTIEnvironment};
 use super::error::{translate_error, NativeError};
 use super::event::*;
+use super::class::ClassId;
 use super::method::MethodId;
 use super::native::*;
 use super::native::jvmti_native::*;
@@ -128,6 +129,10 @@ pub fn register_class_file_load_hook(callback: Option<FnClassFileLoad>) {
     unsafe { CALLBACK_TABLE.class_file_load_hook = callback; }
 }
 
+pub fn register_class_prepare_callback(callback: Option<FnClassPrepare>) {
+    unsafe { CALLBACK_TABLE.class_prepare = callback; }
+}
+
 pub fn registered_callbacks() -> (jvmtiEventCallbacks, i32) {
     (local_event_callbacks(), size_of::<jvmtiEventCallbacks>() as i32)
 }
@@ -370,7 +375,10 @@ unsafe extern "C" fn local_cb_thread_start(jvmti_env: *mut jvmtiEnv, jni_env: *m
         Some(function) => {
             let env = get_env_api(jvmti_env, jni_env);
             match env.get_thread_info(&thread) {
-                Ok(current_thread) => function(current_thread),
+                Ok(mut current_thread) => {
+                    current_thread.thread_id = env.get_thread_id(&thread);
+                    function(current_thread)
+                },
                 Err(err) => {
                     match err {
                         NativeError::WrongPhase => { /* we're in the wrong phase, just ignore this */ },
@@ -390,7 +398,10 @@ unsafe extern "C" fn local_cb_thread_end(jvmti_env: *mut jvmtiEnv, jni_env: *mut
         Some(function) => {
             let env = get_env_api(jvmti_env, jni_env);
             match env.get_thread_info(&thread) {
-                Ok(current_thread) => function(current_thread),
+                Ok(mut current_thread) => {
+                    current_thread.thread_id = env.get_thread_id(&thread);
+                    function(current_thread)
+                },
                 Err(err) => {
                     match err {
                         NativeError::WrongPhase => { /* wrong phase, just ignore this */ },
@@ -505,7 +516,17 @@ unsafe extern "C" fn local_cb_class_load(jvmti_env: *mut jvmtiEnv, jni_env: *mut
 
 #[allow(unused_variables)]
 unsafe extern "C" fn local_cb_class_prepare(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, thread: jthread, klass: jclass) -> () {
-
+    match CALLBACK_TABLE.class_prepare {
+        Some(function) => {
+            let env = get_env_api(jvmti_env, jni_env);
+            let class_id = ClassId { native_id: klass };
+            match env.get_class_methods(&class_id) {
+                Ok(methods) => function(ClassPrepareEvent { class_id, methods }),
+                Err(err) => println!("Couldn't get class methods: {}", translate_error(&err))
+            }
+        },
+        None => println!("No dynamic callback method was found for class prepare events")
+    }
 }
 
 #[allow(unused_variables)]
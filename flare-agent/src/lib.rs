@@ -16,6 +16,7 @@ extern crate serde;
 extern crate resp;
 extern crate timer;
 extern crate chrono;
+extern crate flare_utils;
 
 pub mod agent;
 pub mod bytecode;
@@ -82,6 +83,11 @@ lazy_static! {
 
 static mut TRACE_RUNNING: bool = false;
 
+//max methods resolved per batch, release sampler lock between batches
+const RESOLVE_METHODS_BATCH_SIZE: usize = 500;
+//default max memory of flight recorder
+const DEFAULT_FLIGHT_RECORDER_MB: usize = 64;
+
 
 fn is_trace_running() -> bool {
     //avoid dead lock in gc event callback function
@@ -150,24 +156,34 @@ fn on_thread_start(thread: Thread) {
     if !is_trace_running() {
         return;
     }
-    println!("[{}] thread start [{}] [{}]", nowTime(), thread.id, thread.name);
+    println!("[{}] thread start [{}] [{}]", nowTime(), thread.thread_id, thread.name);
 
-    static_context().thread_start(&thread.id);
+    static_context().thread_start(thread.thread_id);
+    SAMPLER.lock().unwrap().on_thread_start(&thread);
 }
 
 fn on_thread_end(thread: Thread) {
     if !is_trace_running() {
         return;
     }
-    println!("[{}] thread end [{}] [{}]", nowTime(), thread.id, thread.name);
+    println!("[{}] thread end [{}] [{}]", nowTime(), thread.thread_id, thread.name);
 
-    match static_context().thread_end(&thread.id) {
+    let lifetime = static_context().thread_end(thread.thread_id);
+    match lifetime {
         Some(duration) => {
             println!("[{}] Thread {} lived {}", nowTime(), thread.name, duration);
             //TREE_ARENA.lock().unwrap().print_call_tree(&thread);
         },
         None => println!("[{}] Thread {} has no start", nowTime(), thread.name)
     }
+    SAMPLER.lock().unwrap().on_thread_end(&thread, lifetime);
+}
+
+fn on_class_prepare(event: ClassPrepareEvent) {
+    if !is_trace_running() {
+        return;
+    }
+    add_prepared_methods(&event.methods);
 }
 
 fn on_monitor_wait(thread: Thread) {
@@ -308,6 +324,9 @@ pub extern fn Agent_OnLoad(vm: JavaVMPtr, options: MutString, reserved: VoidPtr)
     let mut agent = Agent::new(vm);
     init_agent(&mut agent);
     start_trace(interval, &bind_host, bind_port);
+    if let Some((max_age_ms, max_bytes)) = parse_flight_recorder_options(&options) {
+        SAMPLER.lock().unwrap().enable_flight_recorder(max_age_ms, max_bytes);
+    }
 
     return 0;
 }
@@ -368,17 +387,42 @@ pub extern fn Agent_OnAttach(vm: JavaVMPtr, options: MutString, reserved: VoidPt
                     }
                 }
 
+                let flight_recorder = parse_flight_recorder_options(&options);
                 let vm_ptr = vm as usize;
                 //TODO how to pass vm or agent to thread safely?
                 let handle = std::thread::spawn( move||{
                     println!("Trace agent is running ...");
                     start_trace(interval, &bind_host, bind_port);
+                    if let Some((max_age_ms, max_bytes)) = flight_recorder {
+                        SAMPLER.lock().unwrap().enable_flight_recorder(max_age_ms, max_bytes);
+                    }
                     let vm = vm_ptr as JavaVMPtr;
                     println!("create agent ..");
                     let mut agent = Agent::new_attach(vm, "Flare-Profiler");
                     println!("init_agent ..");
                     init_agent(&mut agent);
                     let jvmenv = &agent.jvm_env;
+                    //main class or jar of the command line
+                    if let Ok(command) = jvmenv.get_system_property("sun.java.command") {
+                        SAMPLER.lock().unwrap().set_main_class(command.split_whitespace().next().unwrap_or(""));
+                    }
+
+                    //resolve method symbols off the sampling thread
+                    std::thread::spawn(move || {
+                        let vm = vm_ptr as JavaVMPtr;
+                        let agent = Agent::new_attach(vm, "Flare-Profiler-Resolver");
+                        let jvmenv = &agent.jvm_env;
+                        while is_trace_running() {
+                            let methods = SAMPLER.lock().unwrap().get_unresolved_methods(RESOLVE_METHODS_BATCH_SIZE);
+                            if methods.is_empty() {
+                                std::thread::sleep(std::time::Duration::from_millis(20));
+                                continue;
+                            }
+                            let resolved_methods = resolve_methods(jvmenv, &methods);
+                            SAMPLER.lock().unwrap().add_resolved_methods(&methods, resolved_methods);
+                        }
+                        println!("Method resolver is stopped.");
+                    });
 
                     let mut samples=0i64;
                     let mut thread_info_map: HashMap<JavaLong, ThreadInfo> = HashMap::new();
@@ -395,7 +439,7 @@ pub extern fn Agent_OnAttach(vm: JavaVMPtr, options: MutString, reserved: VoidPt
 //                        match get_stack_traces(jvmenv, &mut thread_info_map, update_cpu_time) {
                             Ok(stack_traces) => {
                                 let t1 = time::now();
-                                SAMPLER.lock().unwrap().add_stack_traces(jvmenv, &stack_traces);
+                                SAMPLER.lock().unwrap().add_stack_traces(&stack_traces);
                                 let t2 = time::now();
                             },
                             Err(e) => {
@@ -416,6 +460,15 @@ pub extern fn Agent_OnAttach(vm: JavaVMPtr, options: MutString, reserved: VoidPt
                     println!("Trace agent is stopped.");
                 });
             },
+            "dump" => {
+                //write flight recorder of the running agent to dump_dir
+                if is_trace_running() {
+                    let dump_dir = options.custom_args.get("dump_dir").map(|x| x.as_str());
+                    SAMPLER.lock().unwrap().dump_flight_recorder(dump_dir, false);
+                } else {
+                    println!("Trace agent is not running, nothing to dump.");
+                }
+            },
             _ => {
                 println!("Shutting down JVMTI agent ..");
                 stop_trace();
@@ -428,6 +481,28 @@ pub extern fn Agent_OnAttach(vm: JavaVMPtr, options: MutString, reserved: VoidPt
     return 0;
 }
 
+//keep last samples in memory: flight_recorder=<minutes>, flight_recorder_mb=<max memory>
+fn parse_flight_recorder_options(options: &Options) -> Option<(i64, usize)> {
+    let minutes: i64 = match options.custom_args.get("flight_recorder") {
+        Some(minutes_str) => match minutes_str.parse() {
+            Ok(minutes) if minutes > 0 => minutes,
+            _ => {
+                println!("invalid flight recorder minutes: {}, flight recorder is disabled", minutes_str);
+                return None;
+            }
+        },
+        None => return None
+    };
+    let mut max_mb = DEFAULT_FLIGHT_RECORDER_MB;
+    if let Some(mb_str) = options.custom_args.get("flight_recorder_mb") {
+        match mb_str.parse() {
+            Ok(mb) if mb > 0 => max_mb = mb,
+            _ => println!("invalid flight recorder memory: {}, use default: {} MB", mb_str, DEFAULT_FLIGHT_RECORDER_MB)
+        }
+    }
+    Some((minutes * 60_000, max_mb * 1024 * 1024))
+}
+
 fn parse_address(options: &Options) -> (String, u16) {
     let mut bind_host = "0.0.0.0";
     let mut bind_port = 3333;
@@ -535,8 +610,9 @@ fn init_agent(agent: &mut Agent) {
     //agent.on_class_file_load(Some(on_class_file_load));
 //    agent.on_method_entry(Some(on_method_entry));
 //    agent.on_method_exit(Some(on_method_exit));
-//    agent.on_thread_start(Some(on_thread_start));
-//    agent.on_thread_end(Some(on_thread_end));
+    agent.on_thread_start(Some(on_thread_start));
+    agent.on_thread_end(Some(on_thread_end));
+    agent.on_class_prepare(Some(on_class_prepare));
 //    agent.on_monitor_wait(Some(on_monitor_wait));
 //    agent.on_monitor_waited(Some(on_monitor_waited));
 //    agent.on_monitor_contended_enter(Some(on_monitor_contended_enter));
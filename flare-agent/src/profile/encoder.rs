@@ -1,6 +1,6 @@
 
 use resp::{Value, Decoder};
-use profile::sample::{ThreadData, MethodData};
+use profile::sample::{ThreadData, MethodData, ThreadEventData};
 
 pub fn resp_encode_thread_data(thread_data: &ThreadData) -> Value {
     Value::Array(vec![
@@ -31,6 +31,24 @@ fn resp_encode_stacktrace(thread_data: &ThreadData) -> Value {
     Value::Array(vec)
 }
 
+pub fn resp_encode_thread_event(event: &ThreadEventData) -> Value {
+    Value::Array(vec![
+        Value::String(event.event.clone()),
+        Value::String("time".to_string()),
+        Value::Integer(event.time),
+        Value::String("id".to_string()),
+        Value::Integer(event.id),
+        Value::String("name".to_string()),
+        Value::String(event.name.clone()),
+        Value::String("priority".to_string()),
+        Value::Integer(event.priority as i64),
+        Value::String("daemon".to_string()),
+        Value::Integer(event.daemon as i64),
+        Value::String("lifetime".to_string()),
+        Value::Integer(event.lifetime),
+    ])
+}
+
 pub fn resp_encode_method_data(method_data: &MethodData) -> Value {
     Value::Array(vec![
         Value::String("method".to_string()),
@@ -38,10 +56,16 @@ pub fn resp_encode_method_data(method_data: &MethodData) -> Value {
         Value::Integer(method_data.method_id),
         Value::String("name".to_string()),
         Value::String(method_data.full_name.clone()),
+        Value::String("class".to_string()),
+        Value::String(method_data.class_name.clone()),
+        Value::String("method".to_string()),
+        Value::String(method_data.method_name.clone()),
+        Value::String("signature".to_string()),
+        Value::String(method_data.signature.clone()),
     ])
 }
 
-pub fn resp_encode_sample_info(start_time: i64, sample_interval:u64, last_sample_time: i64) -> Value {
+pub fn resp_encode_sample_info(start_time: i64, sample_interval:u64, last_sample_time: i64, pid: u32, main_class: &str) -> Value {
     Value::Array(vec![
         Value::String("sample_info".to_string()),
         Value::String("start_time".to_string()),
@@ -50,5 +74,22 @@ pub fn resp_encode_sample_info(start_time: i64, sample_interval:u64, last_sample
         Value::Integer(sample_interval as i64),
         Value::String("last_sample_time".to_string()),
         Value::Integer(last_sample_time),
+        Value::String("pid".to_string()),
+        Value::Integer(pid as i64),
+        Value::String("main_class".to_string()),
+        Value::String(main_class.to_string()),
+    ])
+}
+
+//result of writing flight recorder to sample dir, error is empty if successful
+pub fn resp_encode_dump_result(dir: &str, sample_count: usize, error: &str) -> Value {
+    Value::Array(vec![
+        Value::String("flight_recorder_dump".to_string()),
+        Value::String("dir".to_string()),
+        Value::String(dir.to_string()),
+        Value::String("samples".to_string()),
+        Value::Integer(sample_count as i64),
+        Value::String("error".to_string()),
+        Value::String(error.to_string()),
     ])
 }
\ No newline at end of file
@@ -2,4 +2,5 @@
 pub mod sample;
 mod tree;
 mod encoder;
-mod server;
\ No newline at end of file
+mod server;
+mod recorder;
\ No newline at end of file
@@ -0,0 +1,181 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use flare_utils::{ValueType, hash_method_key, sample_format};
+use flare_utils::sample_format::{SampleInfo, SummaryInfo};
+use flare_utils::timeseries::{TimeSeries, TSValue, TimeSeriesFileWriter};
+use flare_utils::tuple_indexed::{TupleIndexedFile, TupleValue};
+use profile::sample::{ThreadData, MethodData};
+
+//approximate memory of a sample besides name and stack frames
+const SAMPLE_BASE_SIZE: usize = 128;
+
+/// Ring buffer of the last samples, kept in memory while no collector is connected.
+/// The oldest samples are evicted when exceeded max age or max memory.
+pub struct FlightRecorder {
+    max_age_ms: i64,
+    max_bytes: usize,
+    samples: VecDeque<ThreadData>,
+    bytes: usize,
+}
+
+impl FlightRecorder {
+    pub fn new(max_age_ms: i64, max_bytes: usize) -> FlightRecorder {
+        FlightRecorder {
+            max_age_ms,
+            max_bytes,
+            samples: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn add_sample(&mut self, thread_data: ThreadData) {
+        let now = thread_data.sample_time;
+        self.bytes += estimate_sample_size(&thread_data);
+        self.samples.push_back(thread_data);
+        while let Some(first) = self.samples.front() {
+            if now - first.sample_time <= self.max_age_ms && self.bytes <= self.max_bytes {
+                break;
+            }
+            self.bytes -= estimate_sample_size(first);
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn get_samples(&self) -> &VecDeque<ThreadData> {
+        &self.samples
+    }
+
+    pub fn get_memory_bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+//thread data in the format of sample dir, stacktrace is not copied
+fn to_sample_thread_data(thread_data: &ThreadData) -> sample_format::ThreadData {
+    let mut data = sample_format::ThreadData::new(thread_data.id, &thread_data.name);
+    data.priority = thread_data.priority;
+    data.daemon = thread_data.daemon;
+    data.state = thread_data.state.clone();
+    data.cpu_time = thread_data.cpu_time;
+    data.cpu_time_delta = thread_data.cpu_time_delta;
+    data.sample_time = thread_data.sample_time;
+    data
+}
+
+fn estimate_sample_size(thread_data: &ThreadData) -> usize {
+    SAMPLE_BASE_SIZE + thread_data.name.len() + thread_data.state.len() + thread_data.stacktrace.len() * 8
+}
+
+/// Snapshot of flight recorder, written to a sample dir in flare-server's format.
+pub struct FlightRecording {
+    pub samples: Vec<ThreadData>,
+    //resolved methods of the sampled stacks
+    pub methods: HashMap<i64, MethodData>,
+    pub agent_addr: String,
+    pub start_time: i64,
+    pub sample_interval: u64,
+    pub jvm_pid: u32,
+    pub main_class: String,
+}
+
+struct ThreadSummary {
+    start_time: i64,
+    last: ThreadData,
+    sample_count: i64,
+}
+
+impl FlightRecording {
+    /// Write samples to the dir, return the count of samples.
+    pub fn write_sample_dir(&self, sample_data_dir: &str) -> io::Result<usize> {
+        if self.samples.is_empty() {
+            return Err(io::Error::new(ErrorKind::NotFound, "no samples in flight recorder"));
+        }
+        let summary_path = format!("{}/summary_info.json", sample_data_dir);
+        if Path::new(&summary_path).exists() {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, format!("sample dir already exists: {}", sample_data_dir)));
+        }
+        std::fs::create_dir_all(sample_data_dir)?;
+
+        //jmethodID is translated to stable method key like flare-server, unresolved methods keep raw id
+        let mut method_keys: HashMap<i64, i64> = HashMap::new();
+        {
+            let mut method_idx_file = TupleIndexedFile::new_writer(&format!("{}/method_info", sample_data_dir), ValueType::INT64)?;
+            for (method_id, method_data) in &self.methods {
+                let method_key = if method_data.class_name.is_empty() {
+                    hash_method_key(&method_data.full_name, "", "")
+                } else {
+                    hash_method_key(&method_data.class_name, &method_data.method_name, &method_data.signature)
+                };
+                method_keys.insert(*method_id, method_key);
+                method_idx_file.add_value(TupleValue::int64(method_key), method_data.full_name.as_bytes())?;
+            }
+        }
+
+        let unit_time = self.sample_interval.max(1) as i32;
+        let mut cpu_ts_map: HashMap<i64, TimeSeriesFileWriter> = HashMap::new();
+        let mut stack_map: HashMap<i64, TupleIndexedFile> = HashMap::new();
+        let mut thread_summaries: HashMap<i64, ThreadSummary> = HashMap::new();
+        for thread_data in &self.samples {
+            let thread_id = thread_data.id;
+            let sample_time = thread_data.sample_time;
+            if !cpu_ts_map.contains_key(&thread_id) {
+                let path = format!("{}/thread_{}_cpu_time", sample_data_dir, thread_id);
+                cpu_ts_map.insert(thread_id, TimeSeriesFileWriter::new(ValueType::INT32, unit_time, sample_time, &path)?);
+                let path = format!("{}/thread_{}_stack", sample_data_dir, thread_id);
+                stack_map.insert(thread_id, TupleIndexedFile::new_writer(&path, ValueType::UINT32)?);
+            }
+            let ts_steps = cpu_ts_map.get_mut(&thread_id).unwrap()
+                .add_value(sample_time, TSValue::int32((thread_data.cpu_time_delta / 1000) as i32))?;
+
+            let mut data = to_sample_thread_data(thread_data);
+            data.sample_count = 1;
+            data.stacktrace = thread_data.stacktrace.iter()
+                .map(|method_id| *method_keys.get(method_id).unwrap_or(method_id)).collect();
+            stack_map.get_mut(&thread_id).unwrap().add_value(TupleValue::uint32(ts_steps), &serde_json::to_vec(&data)?)?;
+
+            let summary = thread_summaries.entry(thread_id).or_insert_with(|| ThreadSummary {
+                start_time: sample_time,
+                last: thread_data.clone(),
+                sample_count: 0,
+            });
+            summary.last = thread_data.clone();
+            summary.sample_count += 1;
+        }
+        //flush thread files before summary info
+        drop(cpu_ts_map);
+        drop(stack_map);
+
+        let first_sample_time = self.samples.iter().map(|x| x.sample_time).min().unwrap_or(0);
+        let last_sample_time = self.samples.iter().map(|x| x.sample_time).max().unwrap_or(0);
+        let mut threads: Vec<sample_format::ThreadData> = thread_summaries.values().map(|summary| {
+            let mut thread = to_sample_thread_data(&summary.last);
+            thread.sample_count = summary.sample_count;
+            thread.start_time = summary.start_time;
+            thread
+        }).collect();
+        threads.sort_by_key(|x| x.id);
+        let recording_id = Path::new(sample_data_dir).file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+        let summary = SummaryInfo {
+            sample_info: SampleInfo {
+                sample_interval: self.sample_interval as i64,
+                sample_start_time: self.start_time,
+                record_start_time: first_sample_time,
+                last_record_time: last_sample_time,
+                agent_addr: self.agent_addr.clone(),
+                sample_data_dir: sample_data_dir.to_string(),
+                recording_id,
+                segment_start_time: first_sample_time,
+                jvm_pid: self.jvm_pid as i64,
+                main_class: self.main_class.clone(),
+                gaps: vec![],
+                reconnecting: false,
+                markers: vec![],
+            },
+            threads
+        };
+        std::fs::write(&summary_path, serde_json::to_string_pretty(&summary)?)?;
+        Ok(self.samples.len())
+    }
+}
@@ -14,7 +14,8 @@ use super::server::*;
 use chrono::Local;
 use profile::encoder::*;
 use std::sync::{Mutex, mpsc};
-use error::NativeError;
+use error::{NativeError, translate_error};
+use profile::recorder::{FlightRecorder, FlightRecording};
 //use std::sync::mpsc::{Sender, Receiver};
 
 #[derive(Serialize, Deserialize)]
@@ -77,11 +78,29 @@ impl SampleData for ThreadData {
 pub struct MethodData {
     pub method_id: i64,
     pub full_name: String,
-    pub hits_count: u32
+    pub hits_count: u32,
+    //components of stable method key: class name, method name, descriptor
+    pub class_name: String,
+    pub method_name: String,
+    pub signature: String
 //    pub source_file: String,
 //    pub line_num: u16
 }
 
+impl MethodData {
+    //placeholder of a method id failed to resolve, sent to server instead of retrying forever
+    pub fn unknown(method: JavaMethod) -> MethodData {
+        MethodData {
+            method_id: method as i64,
+            full_name: format!("<unknown method {}>", method as i64),
+            hits_count: 0,
+            class_name: String::new(),
+            method_name: String::new(),
+            signature: String::new()
+        }
+    }
+}
+
 impl SampleData for MethodData {
     fn encode(&self) -> Vec<u8> {
         resp_encode_method_data(self).encode()
@@ -92,6 +111,76 @@ impl SampleData for MethodData {
     }
 }
 
+//thread lifecycle event: thread_start, thread_end
+#[derive(Clone)]
+pub struct ThreadEventData {
+    pub event: String,
+    pub id: i64,
+    pub name: String,
+    pub priority: u32,
+    pub daemon: bool,
+    pub time: i64,
+    //thread lifetime ms, 0 if thread was started before agent loaded
+    pub lifetime: i64
+}
+
+impl SampleData for ThreadEventData {
+    fn encode(&self) -> Vec<u8> {
+        resp_encode_thread_event(self).encode()
+    }
+
+    fn get_type(&self) -> String {
+        self.event.clone()
+    }
+}
+
+//resolve attempts of a method id before it is cached as unknown method
+const MAX_RESOLVE_ATTEMPTS: u32 = 3;
+
+lazy_static! {
+    //method ids of the classes prepared since last sample, evict them from method cache
+    static ref PREPARED_METHODS: Mutex<Vec<usize>> = Mutex::new(vec![]);
+}
+
+/// Called on ClassPrepare: the jmethodIDs of a newly prepared class may reuse the values of an
+/// unloaded class, so the cached method info must be resolved again.
+/// Standard JVMTI has no ClassUnload event, the reuse is detected when the new class is prepared.
+pub fn add_prepared_methods(methods: &Vec<MethodId>) {
+    let mut prepared_methods = PREPARED_METHODS.lock().unwrap();
+    for method in methods {
+        prepared_methods.push(method.native_id as usize);
+    }
+}
+
+/// Resolve method symbols with jvmti, called by the resolver thread without holding the sampler lock,
+/// a big app may have tens of thousands of new methods after attach.
+pub fn resolve_methods(jvm_env: &Box<Environment>, methods: &Vec<usize>) -> Vec<MethodData> {
+    let mut resolved_methods = Vec::with_capacity(methods.len());
+    for method in methods {
+        match resolve_method(jvm_env, *method as JavaMethod) {
+            Ok(method_data) => resolved_methods.push(method_data),
+            Err(e) => println!("resolve method failed: {}, error: {}", method, translate_error(&e))
+        }
+    }
+    resolved_methods
+}
+
+fn resolve_method(jvm_env: &Box<Environment>, method: JavaMethod) -> Result<MethodData, NativeError> {
+    let method_id = MethodId { native_id: method };
+    let method_sig = jvm_env.get_method_name(&method_id)?;
+    let class_id = jvm_env.get_method_declaring_class(&method_id)?;
+    let class = jvm_env.get_class_signature(&class_id)?;
+    let full_name =  format!("{}.{}()", class.name, method_sig.name);
+    Ok(MethodData{
+        method_id: method as i64,
+        full_name,
+        hits_count: 0,
+        class_name: class.name,
+        method_name: method_sig.name,
+        signature: method_sig.signature
+    })
+}
+
 //#[derive(Clone)]
 pub struct ResponseData {
     cmd: String,
@@ -119,15 +208,23 @@ impl SampleData for ResponseData {
 
 pub struct Sampler {
     method_cache: HashMap<usize, MethodData>,
+    //method ids waiting for symbol resolving
+    unresolved_methods: HashSet<usize>,
+    //failed resolve attempts of method ids
+    resolve_failures: HashMap<usize, u32>,
     running: bool,
     sample_interval: u64,
     bind_host: String,
     bind_port: u16,
     start_time: i64,
     last_sample_time: i64,
+    jvm_pid: u32,
+    main_class: String,
     threads_map: HashMap<JavaLong, ThreadData>,
     sender: Option<mpsc::Sender<resp::Value>>,
     receiver: Option<mpsc::Receiver<resp::Value>>,
+    //keep last samples in memory, None is disabled
+    flight_recorder: Option<FlightRecorder>,
 }
 
 //pub struct MethodInfo {
@@ -141,15 +238,20 @@ impl Sampler {
     pub fn new() -> Sampler {
         Sampler {
             method_cache: HashMap::new(),
+            unresolved_methods: HashSet::new(),
+            resolve_failures: HashMap::new(),
             running: false,
             sample_interval: 0,
             bind_host: "0.0.0.0".to_string(),
             bind_port: 3333,
             start_time:0,
             last_sample_time:0,
+            jvm_pid: std::process::id(),
+            main_class: String::new(),
             sender: None,
             receiver: None,
-            threads_map: HashMap::new()
+            threads_map: HashMap::new(),
+            flight_recorder: None
         }
     }
 
@@ -189,6 +291,15 @@ impl Sampler {
         self.bind_port = bind_port;
     }
 
+    pub fn set_main_class(&mut self, main_class: &str) {
+        self.main_class = main_class.to_string();
+    }
+
+    pub fn enable_flight_recorder(&mut self, max_age_ms: i64, max_bytes: usize) {
+        println!("flight recorder is enabled, max age: {} ms, max memory: {} bytes", max_age_ms, max_bytes);
+        self.flight_recorder = Some(FlightRecorder::new(max_age_ms, max_bytes));
+    }
+
     pub fn get_sample_interval(&self) -> u64 {
         self.sample_interval
     }
@@ -197,18 +308,38 @@ impl Sampler {
         self.start_time
     }
 
-    pub fn on_thread_start(&mut self, thread: ThreadId) {
-        //self.threads.push(thread);
+    pub fn on_thread_start(&mut self, thread: &Thread) {
+        let event = ThreadEventData {
+            event: "thread_start".to_string(),
+            id: thread.thread_id,
+            name: thread.name.clone(),
+            priority: thread.priority,
+            daemon: thread.is_daemon,
+            time: Local::now().timestamp_millis(),
+            lifetime: 0
+        };
+        add_sample_data(Box::new(event));
     }
 
-    pub fn on_thread_end(&mut self, thread: &ThreadId) {
-        //self.threads.remove_item(thread);
-//        if let Some(pos) = self.threads.iter().position(|x| *x == *thread) {
-//            self.threads.remove(pos);
-//        }
+    pub fn on_thread_end(&mut self, thread: &Thread, lifetime: Option<Duration>) {
+        //release dead thread
+        self.threads_map.remove(&thread.thread_id);
+
+        let event = ThreadEventData {
+            event: "thread_end".to_string(),
+            id: thread.thread_id,
+            name: thread.name.clone(),
+            priority: thread.priority,
+            daemon: thread.is_daemon,
+            time: Local::now().timestamp_millis(),
+            lifetime: lifetime.map_or(0, |d| d.num_milliseconds())
+        };
+        add_sample_data(Box::new(event));
     }
 
-    pub fn add_stack_traces(&mut self, jvmenv: &Box<Environment>, stack_traces: &Vec<JavaStackTrace>) {
+    pub fn add_stack_traces(&mut self, stack_traces: &Vec<JavaStackTrace>) {
+        self.evict_prepared_methods();
+
         //merge to call stack tree
         let now_time = Local::now().timestamp_millis();
         self.last_sample_time = now_time;
@@ -252,15 +383,22 @@ impl Sampler {
 
             //clone thread_data and push to sample data queue
             let mut thread_data = thread_data.clone();
-            //translate method call
+            //send raw method id, symbols are resolved later by resolver thread
             for stack_frame in &stack_info.frame_buffer {
-                let method_info = self.get_method_info(jvmenv, stack_frame.method);
-                if method_info.hits_count == 1 {
-                    sample_data_vec.push(Box::new(method_info.clone()));
+                let method = stack_frame.method as usize;
+                if !self.method_cache.contains_key(&method) {
+                    self.unresolved_methods.insert(method);
                 }
-                thread_data.stacktrace.push(method_info.method_id);
+                thread_data.stacktrace.push(method as i64);
             }
 
+            if let Some(recorder) = self.flight_recorder.as_mut() {
+                recorder.add_sample(thread_data.clone());
+                //samples are replayed from flight recorder when a collector is connected
+                if !has_subscriber() {
+                    continue;
+                }
+            }
             sample_data_vec.push(Box::new(thread_data));
         }
 
@@ -268,21 +406,50 @@ impl Sampler {
         add_sample_data_batch(sample_data_vec);
     }
 
-    fn get_method_info(&mut self, jvm_env: &Box<Environment>, method: JavaMethod) -> &MethodData {
-        let method_data = self.method_cache.entry(method as usize).or_insert_with(|| {
-            let method_id = MethodId { native_id: method };
-            let method_sig = jvm_env.get_method_name(&method_id).unwrap();
-            let class_id = jvm_env.get_method_declaring_class(&method_id).unwrap();
-            let class = jvm_env.get_class_signature(&class_id).unwrap();
-            let full_name =  format!("{}.{}()", class.name, method_sig.name);
-            MethodData{
-                method_id: method as i64,
-                full_name,
-                hits_count: 0
+    //get a batch of unresolved methods for resolver thread
+    pub fn get_unresolved_methods(&self, max_count: usize) -> Vec<usize> {
+        self.unresolved_methods.iter().take(max_count).cloned().collect()
+    }
+
+    pub fn add_resolved_methods(&mut self, methods: &Vec<usize>, resolved_methods: Vec<MethodData>) {
+        for method in methods {
+            self.unresolved_methods.remove(method);
+        }
+        let mut sample_data_vec :Vec<Box<SampleData+Send>> = vec![];
+        for method_data in resolved_methods {
+            let method = method_data.method_id as usize;
+            self.resolve_failures.remove(&method);
+            sample_data_vec.push(Box::new(method_data.clone()));
+            self.method_cache.insert(method, method_data);
+        }
+        for method in methods {
+            if self.method_cache.contains_key(method) {
+                continue;
+            }
+            let attempts = self.resolve_failures.entry(*method).or_insert(0);
+            *attempts += 1;
+            if *attempts < MAX_RESOLVE_ATTEMPTS {
+                self.unresolved_methods.insert(*method);
+            } else {
+                //negative entry, resolved again only if the method id is reused
+                self.resolve_failures.remove(method);
+                let method_data = MethodData::unknown(*method as JavaMethod);
+                sample_data_vec.push(Box::new(method_data.clone()));
+                self.method_cache.insert(*method, method_data);
+            }
+        }
+        add_sample_data_batch(sample_data_vec);
+    }
+
+    fn evict_prepared_methods(&mut self) {
+        let prepared_methods: Vec<usize> = PREPARED_METHODS.lock().unwrap().drain(..).collect();
+        for method in prepared_methods {
+            //method id is reused, resolve and send method info again on next hit
+            self.resolve_failures.remove(&method);
+            if self.method_cache.remove(&method).is_some() {
+                println!("evict reused method id: {}", method);
             }
-        });
-        method_data.hits_count += 1;
-        method_data
+        }
     }
 
     pub fn handle_request(&mut self) {
@@ -310,12 +477,25 @@ impl Sampler {
             "get_method_cache" => {
                 self.send_method_cache();
             }
+            "resolve_methods" => {
+                self.resolve_methods_on_demand(options);
+            }
+            "replay_flight_recorder" => {
+                self.replay_flight_recorder();
+            }
+            "dump_flight_recorder" => {
+                let dir = match options.get("dir") {
+                    Some(resp::Value::String(dir)) | Some(resp::Value::Bulk(dir)) => Some(dir.as_str()),
+                    _ => None
+                };
+                self.dump_flight_recorder(dir, true);
+            }
             _ => { println!("unknown request cmd: {}, options: {:?}", cmd, options); }
         }
     }
 
     fn send_sample_info(&mut self) {
-        let response = resp_encode_sample_info(self.start_time, self.sample_interval, self.last_sample_time);
+        let response = resp_encode_sample_info(self.start_time, self.sample_interval, self.last_sample_time, self.jvm_pid, &self.main_class);
         //add_sample_data(ResponseData::new("sample_info".to_string(),response));
         Sampler::send_response(&self.sender, response);
     }
@@ -326,6 +506,95 @@ impl Sampler {
         }
     }
 
+    //server request names of method ids: resend resolved methods, queue the others for resolver thread
+    fn resolve_methods_on_demand(&mut self, options: &HashMap<String, resp::Value>) {
+        if let Some(resp::Value::Array(ids)) = options.get("ids") {
+            let mut sample_data_vec :Vec<Box<SampleData+Send>> = vec![];
+            for id in ids {
+                if let resp::Value::Integer(method) = id {
+                    let method = *method as usize;
+                    match self.method_cache.get(&method) {
+                        Some(method_data) => sample_data_vec.push(Box::new(method_data.clone())),
+                        None => { self.unresolved_methods.insert(method); }
+                    }
+                }
+            }
+            add_sample_data_batch(sample_data_vec);
+        }
+    }
+
+    //send samples of flight recorder to new subscriber, the queued samples are dropped to avoid duplicates,
+    //the replay is ended by a replay_end response with the count of samples
+    fn replay_flight_recorder(&mut self) {
+        remove_queued_samples("thread");
+        let mut count = 0;
+        if let Some(recorder) = &self.flight_recorder {
+            println!("replay flight recorder samples: {}, memory: {} bytes", recorder.get_samples().len(), recorder.get_memory_bytes());
+            for thread_data in recorder.get_samples() {
+                Sampler::send_response(&self.sender, resp_encode_thread_data(thread_data));
+                count += 1;
+            }
+        }
+        Sampler::send_response(&self.sender, resp::Value::Array(vec![
+            resp::Value::String("replay_end".to_string()),
+            resp::Value::String("count".to_string()),
+            resp::Value::Integer(count),
+        ]));
+    }
+
+    fn take_flight_recording(&self) -> Option<FlightRecording> {
+        let recorder = self.flight_recorder.as_ref()?;
+        let samples: Vec<ThreadData> = recorder.get_samples().iter().cloned().collect();
+        let mut methods = HashMap::new();
+        for thread_data in &samples {
+            for method_id in &thread_data.stacktrace {
+                if let Some(method_data) = self.method_cache.get(&(*method_id as usize)) {
+                    methods.entry(*method_id).or_insert_with(|| method_data.clone());
+                }
+            }
+        }
+        Some(FlightRecording {
+            samples,
+            methods,
+            agent_addr: format!("{}:{}", self.bind_host, self.bind_port),
+            start_time: self.start_time,
+            sample_interval: self.sample_interval,
+            jvm_pid: self.jvm_pid,
+            main_class: self.main_class.clone(),
+        })
+    }
+
+    /// Write flight recorder to a sample dir in a new thread, default dir is `flare-samples/<addr>-<time>`.
+    pub fn dump_flight_recorder(&mut self, dir: Option<&str>, reply: bool) {
+        let dir = match dir {
+            Some(dir) if !dir.is_empty() => dir.to_string(),
+            _ => format!("flare-samples/{}_{}-{}", self.bind_host, self.bind_port, Local::now().format("%Y%m%dT%H%M%S"))
+        };
+        let sender = if reply { self.sender.clone() } else { None };
+        let recording = match self.take_flight_recording() {
+            Some(recording) => recording,
+            None => {
+                println!("dump flight recorder failed: flight recorder is not enabled");
+                Sampler::send_response(&sender, resp_encode_dump_result(&dir, 0, "flight recorder is not enabled"));
+                return;
+            }
+        };
+        //writing files may be slow, release the sampler lock
+        std::thread::spawn(move || {
+            let response = match recording.write_sample_dir(&dir) {
+                Ok(sample_count) => {
+                    println!("dump flight recorder to dir: {}, samples: {}", dir, sample_count);
+                    resp_encode_dump_result(&dir, sample_count, "")
+                },
+                Err(e) => {
+                    println!("dump flight recorder failed: {}, error: {}", dir, e);
+                    resp_encode_dump_result(&dir, 0, &e.to_string())
+                }
+            };
+            Sampler::send_response(&sender, response);
+        });
+    }
+
     fn send_method_cache(&mut self) {
         let sender = &self.sender;
         self.method_cache.values().for_each(|method_info| {
@@ -405,6 +674,9 @@ impl Sampler {
 }
 
 
+//max queued sample data, the oldest thread samples are dropped if the client is slow
+const MAX_QUEUED_DATA: usize = 10000;
+
 pub struct SampleQueue {
     pub queue: VecDeque<Box<dyn SampleData + Send>>,
     total_count: usize,
@@ -431,8 +703,16 @@ impl SampleQueue {
         for sample_data in data_vec {
             self.queue.push_back(sample_data);
         }
-        while(self.queue.len() > 10000){
-            self.queue.pop_front();
+        self.evict_samples();
+    }
+
+    //method info and thread events are never dropped, samples depend on them
+    pub fn evict_samples(&mut self) {
+        while self.queue.len() > MAX_QUEUED_DATA {
+            match self.queue.iter().position(|x| x.get_type() == "thread") {
+                Some(idx) => { self.queue.remove(idx); },
+                None => break
+            }
         }
     }
 
@@ -10,12 +10,21 @@ use super::sample::ThreadData;
 use profile::encoder::*;
 use profile::sample::*;
 use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 lazy_static! {
     static ref DATA_QUEUE: Mutex<SampleQueue>  = Mutex::new(SampleQueue::new());
     static ref SAMPLE_SERVER: Mutex<SampleServer>  = Mutex::new(SampleServer::new());
 }
 
+//count of connected collectors
+static SUBSCRIBER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+//max wait time of writing flight recorder to sample dir
+const DUMP_TIMEOUT_MS: u64 = 60_000;
+//max wait time of the next replayed sample
+const REPLAY_TIMEOUT_MS: u64 = 5_000;
+
 pub struct SampleServer {
     sample_interval: u64,
     start_time: i64,
@@ -63,8 +72,12 @@ impl SampleServer {
     }
 
     pub fn recv_response(&self) -> Option<resp::Value> {
+        self.recv_response_timeout(50)
+    }
+
+    pub fn recv_response_timeout(&self, timeout_ms: u64) -> Option<resp::Value> {
         if let Some(rx) = &self.receiver {
-            match rx.recv_timeout(Duration::from_millis(50)) {
+            match rx.recv_timeout(Duration::from_millis(timeout_ms)) {
                 Ok(val) => {
                     return Some(val);
                 },
@@ -95,11 +108,8 @@ pub fn get_server() -> &'static Mutex<SampleServer> {
 
 pub fn add_sample_data(sample_data: Box<SampleData + Send>) {
     let mut data_queue = DATA_QUEUE.lock().unwrap();
-    let mut queue = &mut data_queue.queue;
-    queue.push_back(sample_data);
-    while(queue.len() > 10000){
-        queue.pop_front();
-    }
+    data_queue.queue.push_back(sample_data);
+    data_queue.evict_samples();
 }
 
 pub fn add_sample_data_batch(data_vec: Vec<Box<SampleData + Send>>) {
@@ -107,6 +117,16 @@ pub fn add_sample_data_batch(data_vec: Vec<Box<SampleData + Send>>) {
     data_queue.push_back(data_vec);
 }
 
+//remove queued sample data of the type, e.g. thread samples replayed from flight recorder
+pub fn remove_queued_samples(data_type: &str) {
+    let mut data_queue = DATA_QUEUE.lock().unwrap();
+    data_queue.queue.retain(|x| x.get_type() != data_type);
+}
+
+pub fn has_subscriber() -> bool {
+    SUBSCRIBER_COUNT.load(Ordering::SeqCst) > 0
+}
+
 fn set_server_running(val: bool) {
     SAMPLE_SERVER.lock().unwrap().set_running(val);
 }
@@ -277,6 +297,9 @@ fn dispatch_request(stream: &mut TcpStream, clientRequest: &Value) {
             "subscribe-events" => {
                 handle_subscribe_events_cmd(stream, &cmd_options);
             },
+            "dump-flight-recorder" => {
+                handle_dump_flight_recorder_cmd(stream, &cmd_options);
+            },
             _ => { println!("unknown request cmd: {}, options: {:?}", cmd, cmd_options); }
         }
     }
@@ -313,6 +336,25 @@ fn handle_stop_sample_cmd(stream: &mut TcpStream, cmd_options: &HashMap<String,
     stop_server();
 }
 
+fn handle_dump_flight_recorder_cmd(stream: &mut TcpStream, cmd_options: &HashMap<String, Value>) {
+    let mut request = vec![Value::String("dump_flight_recorder".to_string())];
+    if let Some(dir) = cmd_options.get("dir") {
+        request.push(Value::String("dir".to_string()));
+        request.push(dir.clone());
+    }
+    SAMPLE_SERVER.lock().unwrap().send_request(Value::Array(request));
+    match SAMPLE_SERVER.lock().unwrap().recv_response_timeout(DUMP_TIMEOUT_MS) {
+        Some(response) => {
+            if let Err(e) = stream.write_all(response.encode().as_slice()) {
+                println!("send dump flight recorder result failed: {}", e);
+            }
+        },
+        None => {
+            println!("recv dump_flight_recorder result timeout");
+        }
+    }
+}
+
 fn handle_subscribe_events_cmd(stream: &mut TcpStream, cmd_options: &HashMap<String, Value>) {
     println!("subscribe event loop start");
 
@@ -340,6 +382,8 @@ fn handle_subscribe_events_cmd(stream: &mut TcpStream, cmd_options: &HashMap<Str
         println!("recv get_sample_info result failed, stopping subscribe event")
     }
 
+    SUBSCRIBER_COUNT.fetch_add(1, Ordering::SeqCst);
+
     //send current method cache
     println!("sending method cache to new client ..");
     let request = Value::Array(vec![
@@ -357,8 +401,51 @@ fn handle_subscribe_events_cmd(stream: &mut TcpStream, cmd_options: &HashMap<Str
     }
     println!("total sent method cache: {}", method_count);
 
+    //send last samples of flight recorder
+    if let Some(Value::Integer(1)) = cmd_options.get("replay") {
+        println!("replaying flight recorder to new client ..");
+        let request = Value::Array(vec![
+            Value::String("replay_flight_recorder".to_string()),
+        ]);
+        SAMPLE_SERVER.lock().unwrap().send_request(request);
+        let mut sample_count = 0;
+        loop {
+            let response = match SAMPLE_SERVER.lock().unwrap().recv_response_timeout(REPLAY_TIMEOUT_MS) {
+                Some(response) => response,
+                None => {
+                    println!("recv replay_flight_recorder result timeout");
+                    break;
+                }
+            };
+            if let Value::Array(data_vec) = &response {
+                if let Some(Value::String(cmd)) = data_vec.get(0) {
+                    if cmd == "replay_end" {
+                        break;
+                    }
+                }
+            }
+            if let Err(e) = stream.write_all(response.encode().as_slice()) {
+                println!("send flight recorder samples failed: {}", e);
+            }
+            sample_count += 1;
+        }
+        println!("total replayed samples: {}", sample_count);
+    }
+
 //    println!("recv get_sample_info result failed, stopping subscribe event")
 
+    //read requests of server in another thread, e.g. resolve-methods
+    match stream.try_clone() {
+        Ok(read_stream) => {
+            thread::spawn(move || {
+                handle_subscribe_requests(read_stream);
+            });
+        },
+        Err(e) => {
+            println!("clone stream failed, ignore requests of subscriber: {}", e);
+        }
+    }
+
     println!("loop transmit data new client ..");
     let mut sent = false;
     loop {
@@ -380,9 +467,42 @@ fn handle_subscribe_events_cmd(stream: &mut TcpStream, cmd_options: &HashMap<Str
             std::thread::sleep(std::time::Duration::from_millis(10));
         }
     }
+    SUBSCRIBER_COUNT.fetch_sub(1, Ordering::SeqCst);
     println!("subscribe event loop exit")
 }
 
+fn handle_subscribe_requests(stream: TcpStream) {
+    let mut decoder = Decoder::new(BufReader::new(stream));
+    while let Ok(request) = decoder.decode() {
+        if let Value::Array(vec) = &request {
+            if let Some(Value::String(cmd)) = vec.first() {
+                let cmd_options = parse_request_options(vec);
+                match cmd.as_str() {
+                    "resolve-methods" => {
+                        handle_resolve_methods_cmd(&cmd_options);
+                    },
+                    _ => { println!("unknown subscriber request cmd: {}, options: {:?}", cmd, cmd_options); }
+                }
+                continue;
+            }
+        }
+        println!("invalid subscriber request: {:?}", request);
+    }
+    println!("subscriber request loop exit")
+}
+
+fn handle_resolve_methods_cmd(cmd_options: &HashMap<String, Value>) {
+    if let Some(ids) = cmd_options.get("ids") {
+        //pass to sample thread
+        let request = Value::Array(vec![
+            Value::String("resolve_methods".to_string()),
+            Value::String("ids".to_string()),
+            ids.clone(),
+        ]);
+        SAMPLE_SERVER.lock().unwrap().send_request(request);
+    }
+}
+
 fn parse_request(buf: &[u8]) -> Value {
     // echo everything!
     //stream.write(&data[0..size]).unwrap();
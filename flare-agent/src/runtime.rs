@@ -32,3 +32,10 @@ pub struct ClassFileLoadEvent {
 }
 
 impl RuntimeEvent for ClassFileLoadEvent {}
+
+pub struct ClassPrepareEvent {
+    pub class_id: ClassId,
+    pub methods: Vec<MethodId>
+}
+
+impl RuntimeEvent for ClassPrepareEvent {}
@@ -1,4 +1,5 @@
 
+use std::collections::VecDeque;
 
 fn main() {
 
@@ -12,7 +13,9 @@ pub struct TestClient {
 
 impl TestClient {
     fn new() -> TestClient {
-
+        TestClient {
+            woker: TaskWorker { queue: VecDeque::new() }
+        }
     }
 }
 
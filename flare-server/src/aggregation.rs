@@ -0,0 +1,167 @@
+use std::io;
+use std::fmt;
+use tree::TreeNode;
+use transform::{splice_children, update_depth};
+use utils::*;
+
+/// Parsed method full name, e.g. "java.util.HashMap$Node.getKey()" ->
+/// package: "java.util", class_name: "HashMap$Node", method_name: "getKey"
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MethodName {
+    pub package: String,
+    pub class_name: String,
+    pub method_name: String,
+}
+
+impl MethodName {
+    pub fn parse(full_name: &str) -> MethodName {
+        let (package, class_name, method_name) = split_method_name(full_name);
+        MethodName {
+            package: package.to_string(),
+            class_name: class_name.to_string(),
+            method_name: method_name.to_string(),
+        }
+    }
+
+    pub fn get_class_full_name(&self) -> String {
+        if self.package.is_empty() {
+            self.class_name.clone()
+        } else {
+            format!("{}.{}", self.package, self.class_name)
+        }
+    }
+
+    //first depth parts of package, e.g. depth 2 of "com.foo.bar.service" -> "com.foo"
+    pub fn get_package_prefix(&self, depth: usize) -> String {
+        self.package.split('.').take(depth).collect::<Vec<&str>>().join(".")
+    }
+}
+
+//split method full name into (package, class, method), the name without class (e.g. unresolved method id)
+//is treated as method name only
+pub fn split_method_name(full_name: &str) -> (&str, &str, &str) {
+    let name = match full_name.find('(') {
+        Some(pos) => &full_name[..pos],
+        None => full_name
+    };
+    match name.rfind('.') {
+        Some(pos) => {
+            let class_full_name = &name[..pos];
+            let method_name = &name[pos+1..];
+            match class_full_name.rfind('.') {
+                Some(pos) => (&class_full_name[..pos], &class_full_name[pos+1..], method_name),
+                None => ("", class_full_name, method_name)
+            }
+        },
+        None => ("", "", name)
+    }
+}
+
+pub fn get_package_name(full_name: &str) -> &str {
+    split_method_name(full_name).0
+}
+
+const DEFAULT_PACKAGE_LABEL: &str = "(default package)";
+
+/// Frames of stacks are grouped by method, class, package or package prefix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregationLevel {
+    METHOD,
+    CLASS,
+    PACKAGE,
+    //package prefix depth
+    PACKAGE_PREFIX(usize),
+}
+
+impl AggregationLevel {
+    /// Parse 'aggregation' option: "method", "class", "package" or "package_prefix" with 'package_depth'.
+    pub fn parse(options: &serde_json::Map<String, serde_json::Value>) -> io::Result<AggregationLevel> {
+        match get_option_as_str(options, "aggregation", "method") {
+            "method" => Ok(AggregationLevel::METHOD),
+            "class" => Ok(AggregationLevel::CLASS),
+            "package" => Ok(AggregationLevel::PACKAGE),
+            "package_prefix" => {
+                let depth = get_option_as_int(options, "package_depth", 0);
+                if depth <= 0 {
+                    return Err(new_invalid_input_error("missing or invalid option 'package_depth'"));
+                }
+                Ok(AggregationLevel::PACKAGE_PREFIX(depth as usize))
+            },
+            x => Err(new_invalid_input_error(&format!("invalid aggregation: {}", x)))
+        }
+    }
+
+    /// Frame label of the method at this level.
+    pub fn get_label(&self, full_name: &str) -> String {
+        if *self == AggregationLevel::METHOD {
+            return full_name.to_string();
+        }
+        let name = MethodName::parse(full_name);
+        if name.class_name.is_empty() {
+            return full_name.to_string();
+        }
+        let label = match self {
+            AggregationLevel::CLASS => return name.get_class_full_name(),
+            AggregationLevel::PACKAGE_PREFIX(depth) => name.get_package_prefix(*depth),
+            _ => name.package
+        };
+        if label.is_empty() { DEFAULT_PACKAGE_LABEL.to_string() } else { label }
+    }
+
+    /// Relabel tree nodes at this level and merge consecutive frames of the same group.
+    pub fn aggregate_tree(&self, root: &mut TreeNode, sequenced: bool) {
+        if *self == AggregationLevel::METHOD {
+            return;
+        }
+        for child in &mut root.children {
+            self.relabel_node(child);
+        }
+        splice_children(root, sequenced, &|parent, child| parent.label == child.label);
+        update_depth(root, root.depth);
+    }
+
+    fn relabel_node(&self, node: &mut TreeNode) {
+        node.label = self.get_label(&node.label);
+        for child in &mut node.children {
+            self.relabel_node(child);
+        }
+    }
+}
+
+impl fmt::Display for AggregationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AggregationLevel::METHOD => write!(f, "method"),
+            AggregationLevel::CLASS => write!(f, "class"),
+            AggregationLevel::PACKAGE => write!(f, "package"),
+            AggregationLevel::PACKAGE_PREFIX(depth) => write!(f, "package_prefix:{}", depth),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_method_name() {
+        assert_eq!(split_method_name("java.util.HashMap$Node.getKey()"), ("java.util", "HashMap$Node", "getKey"));
+        //dots of signature are ignored
+        assert_eq!(split_method_name("com.foo.Bar.run(Ljava.lang.String;)V"), ("com.foo", "Bar", "run"));
+        assert_eq!(split_method_name("Main.main([Ljava.lang.String;)V"), ("", "Main", "main"));
+        //unresolved method id
+        assert_eq!(split_method_name("5399501909346315"), ("", "", "5399501909346315"));
+        assert_eq!(get_package_name("java.util.HashMap.get()"), "java.util");
+    }
+
+    #[test]
+    fn test_get_label() {
+        let name = "com.foo.bar.Service.call()";
+        assert_eq!(AggregationLevel::METHOD.get_label(name), name);
+        assert_eq!(AggregationLevel::CLASS.get_label(name), "com.foo.bar.Service");
+        assert_eq!(AggregationLevel::PACKAGE.get_label(name), "com.foo.bar");
+        assert_eq!(AggregationLevel::PACKAGE_PREFIX(2).get_label(name), "com.foo");
+        assert_eq!(AggregationLevel::PACKAGE.get_label("Main.main()"), DEFAULT_PACKAGE_LABEL);
+        assert_eq!(AggregationLevel::PACKAGE.get_label("12345"), "12345");
+    }
+}
@@ -0,0 +1,209 @@
+use std::io;
+use std::io::{Read, Write, ErrorKind};
+use std::fs::File;
+use std::path::Path;
+use chrono::Local;
+use utils::*;
+
+// .flare archive layout:
+// magic(8 bytes) | manifest length(u32 le) | manifest json | file data ...
+const ARCHIVE_MAGIC: &[u8; 8] = b"FLAREARC";
+pub const ARCHIVE_VERSION: u32 = 1;
+pub const ARCHIVE_EXTENSION: &str = ".flare";
+
+/// Strip sensitive names from exported samples.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PrivacyOptions {
+    #[serde(default)]
+    pub strip_thread_names: bool,
+    #[serde(default)]
+    pub strip_package_prefixes: Vec<String>,
+}
+
+impl PrivacyOptions {
+    pub fn get_thread_name(&self, thread_id: i64, name: &str) -> String {
+        if self.strip_thread_names {
+            format!("thread-{}", thread_id)
+        } else {
+            name.to_string()
+        }
+    }
+
+    pub fn get_method_name(&self, name: &str) -> String {
+        for prefix in &self.strip_package_prefixes {
+            if !prefix.is_empty() && name.starts_with(prefix.as_str()) {
+                return name[prefix.len()..].to_string();
+            }
+        }
+        name.to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    //offset from the end of manifest
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub version: u32,
+    pub created_time: i64,
+    pub recording_id: String,
+    pub agent_addr: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub privacy: PrivacyOptions,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+/// Pack all files of the sample dir into one archive file.
+pub fn pack_sample_dir(sample_dir: &str, archive_path: &str, mut manifest: ArchiveManifest) -> io::Result<()> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(sample_dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut offset = 0;
+    manifest.entries.clear();
+    for path in &files {
+        let size = std::fs::metadata(path)?.len();
+        manifest.entries.push(ArchiveEntry {
+            name: path.file_name().and_then(|x| x.to_str()).unwrap_or("").to_string(),
+            offset,
+            size,
+        });
+        offset += size;
+    }
+    manifest.version = ARCHIVE_VERSION;
+    manifest.created_time = Local::now().timestamp_millis();
+
+    let manifest_json = serde_json::to_vec(&manifest)?;
+    let mut writer = io::BufWriter::new(File::create(archive_path)?);
+    writer.write_all(ARCHIVE_MAGIC)?;
+    writer.write_all(&(manifest_json.len() as u32).to_le_bytes())?;
+    writer.write_all(&manifest_json)?;
+    for path in &files {
+        io::copy(&mut File::open(path)?, &mut writer)?;
+    }
+    writer.flush()?;
+    println!("export archive: {}, files: {}, bytes: {}", archive_path, files.len(), offset);
+    Ok(())
+}
+
+pub fn read_manifest(archive_path: &str) -> io::Result<ArchiveManifest> {
+    let mut file = File::open(archive_path)?;
+    read_manifest_from(&mut file)
+}
+
+fn read_manifest_from(reader: &mut Read) -> io::Result<ArchiveManifest> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(new_error(ErrorKind::InvalidData, "not a flare archive file"));
+    }
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let mut manifest_json = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+    reader.read_exact(&mut manifest_json)?;
+    let manifest: ArchiveManifest = serde_json::from_slice(&manifest_json)
+        .map_err(|e| new_error(ErrorKind::InvalidData, &format!("parse archive manifest failed: {}", e)))?;
+    if manifest.version > ARCHIVE_VERSION {
+        return Err(new_error(ErrorKind::InvalidData, &format!("unsupported archive version: {}", manifest.version)));
+    }
+    Ok(manifest)
+}
+
+/// Extract archive files into the target dir, return the manifest.
+pub fn unpack_archive(archive_path: &str, target_dir: &str) -> io::Result<ArchiveManifest> {
+    let mut reader = io::BufReader::new(File::open(archive_path)?);
+    let manifest = read_manifest_from(&mut reader)?;
+    for entry in &manifest.entries {
+        //plain file name only, avoid writing out of target dir
+        if entry.name.is_empty() || entry.name.contains('/') || entry.name.contains('\\') || entry.name.contains("..") {
+            return Err(new_error(ErrorKind::InvalidData, &format!("invalid archive entry name: {}", entry.name)));
+        }
+    }
+
+    std::fs::create_dir_all(target_dir)?;
+    //entries are stored in order
+    let mut offset = 0;
+    for entry in &manifest.entries {
+        if entry.offset != offset {
+            return Err(new_error(ErrorKind::InvalidData, &format!("invalid archive entry offset: {}", entry.name)));
+        }
+        let path = Path::new(target_dir).join(&entry.name);
+        let mut file = File::create(&path)?;
+        let copied = io::copy(&mut (&mut reader).take(entry.size), &mut file)?;
+        if copied != entry.size {
+            return Err(new_error(ErrorKind::UnexpectedEof, &format!("archive entry is truncated: {}", entry.name)));
+        }
+        offset += entry.size;
+    }
+    println!("import archive: {} to dir: {}, files: {}", archive_path, target_dir, manifest.entries.len());
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_manifest() -> ArchiveManifest {
+        ArchiveManifest {
+            version: 0,
+            created_time: 0,
+            recording_id: "127.0.0.1_3333-20191001T100000".to_string(),
+            agent_addr: "127.0.0.1:3333".to_string(),
+            start_time: 1000,
+            end_time: 2000,
+            privacy: PrivacyOptions::default(),
+            entries: vec![],
+        }
+    }
+
+    #[test]
+    fn test_pack_and_unpack() {
+        let base_dir = std::env::temp_dir().join(format!("flare-archive-test-{}", std::process::id()));
+        let sample_dir = base_dir.join("samples");
+        let target_dir = base_dir.join("imported");
+        let archive_path = base_dir.join("test.flare");
+        std::fs::create_dir_all(&sample_dir).unwrap();
+        std::fs::write(sample_dir.join("summary_info.json"), b"{}").unwrap();
+        std::fs::write(sample_dir.join("thread_1_stack.fdata"), vec![7u8; 1000]).unwrap();
+        std::fs::write(sample_dir.join("method_info.fidx"), b"").unwrap();
+
+        pack_sample_dir(sample_dir.to_str().unwrap(), archive_path.to_str().unwrap(), new_manifest()).unwrap();
+        let manifest = unpack_archive(archive_path.to_str().unwrap(), target_dir.to_str().unwrap()).unwrap();
+        assert_eq!(manifest.version, ARCHIVE_VERSION);
+        assert_eq!(manifest.recording_id, "127.0.0.1_3333-20191001T100000");
+        assert_eq!(manifest.entries.len(), 3);
+        for name in &["summary_info.json", "thread_1_stack.fdata", "method_info.fidx"] {
+            assert_eq!(std::fs::read(sample_dir.join(name)).unwrap(), std::fs::read(target_dir.join(name)).unwrap());
+        }
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_invalid_archive() {
+        let mut data: &[u8] = b"NOTFLARE\0\0\0\0";
+        assert_eq!(read_manifest_from(&mut data).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_strip_package_prefixes() {
+        let privacy = PrivacyOptions {
+            strip_thread_names: true,
+            strip_package_prefixes: vec!["com.example.".to_string()],
+        };
+        assert_eq!(privacy.get_method_name("com.example.Main.run()V"), "Main.run()V");
+        //prefix in the middle of name is kept
+        assert_eq!(privacy.get_method_name("org.Proxy.call(Lcom.example.Main;)V"), "org.Proxy.call(Lcom.example.Main;)V");
+        assert_eq!(privacy.get_thread_name(12, "worker-1"), "thread-12");
+    }
+}
@@ -13,7 +13,9 @@ use serde_json::{json, Value};
 use log::{debug, info, warn};
 
 use std::collections::hash_map::IterMut;
+use std::cmp::{min, max};
 use tree;
+use sample::ThreadData;
 
 type JavaLong = i64;
 type JavaMethod = i64;
@@ -62,6 +64,88 @@ impl TreeArena {
 }
 
 
+/// Build call stack tree incrementally with max node count.
+/// The tree is coarsened by limiting the depth: the depth is halved and the deeper nodes are pruned
+/// when node count exceeds 3/4 of max nodes, and every half way to max nodes after,
+/// no new nodes are created after reaching max nodes.
+pub struct CallTreeBuilder {
+    call_tree: CallStackTree,
+    //new nodes need method name
+    naming_nodes: Vec<(NodeId, JavaMethod)>,
+    max_nodes: usize,
+    next_coarsen_nodes: usize,
+    max_depth: usize,
+    max_depth_seen: usize,
+    frozen: bool,
+}
+
+impl CallTreeBuilder {
+    pub fn new(max_nodes: usize) -> CallTreeBuilder {
+        CallTreeBuilder {
+            call_tree: CallStackTree::new(0, "CallStack"),
+            naming_nodes: vec![],
+            max_nodes,
+            next_coarsen_nodes: max_nodes / 4 * 3,
+            max_depth: std::usize::MAX,
+            max_depth_seen: 0,
+            frozen: false,
+        }
+    }
+
+    pub fn add_stack_trace(&mut self, thread_data: &ThreadData) {
+        if !self.frozen && self.call_tree.node_count() + thread_data.stacktrace.len() > self.next_coarsen_nodes {
+            self.coarsen();
+        }
+
+        let call_tree = &mut self.call_tree;
+        call_tree.reset_top_call_stack_node();
+        let (delta_duration, delta_cpu_time) = call_tree.start_call_stack(thread_data.sample_time, thread_data.cpu_time);
+
+        //reverse call
+        for method_id in thread_data.stacktrace.iter().rev().take(self.max_depth) {
+            if self.frozen && !call_tree.has_child_call(method_id) {
+                break;
+            }
+            if !call_tree.begin_call(method_id, delta_duration, delta_cpu_time) {
+                self.naming_nodes.push((call_tree.get_top_node().data.node_id, method_id.clone()));
+            }
+        }
+        self.max_depth_seen = max(self.max_depth_seen, min(thread_data.stacktrace.len(), self.max_depth));
+    }
+
+    fn coarsen(&mut self) {
+        let new_max_depth = max(self.max_depth_seen / 2, tree::MIN_COARSEN_TREE_DEPTH as usize);
+        let can_coarsen = new_max_depth < min(self.max_depth_seen, self.max_depth);
+        if can_coarsen {
+            let old_node_count = self.call_tree.node_count();
+            //stats of the pruned nodes are kept in their ancestors at max depth
+            let new_indexes = self.call_tree.prune(new_max_depth as u32);
+            self.naming_nodes = self.naming_nodes.drain(..)
+                .filter_map(|(node_id, method)| new_indexes[node_id.index].map(|index| (NodeId { index }, method)))
+                .collect();
+            self.max_depth = new_max_depth;
+            self.max_depth_seen = new_max_depth;
+            info!("call tree nodes: {} -> {}, coarsen to depth: {}", old_node_count, self.call_tree.node_count(), new_max_depth);
+        }
+        let node_count = self.call_tree.node_count();
+        if node_count >= self.max_nodes || !can_coarsen {
+            self.frozen = true;
+            warn!("call tree exceeds max nodes: {}, stop creating new nodes", self.max_nodes);
+        } else {
+            self.next_coarsen_nodes = node_count + (self.max_nodes - node_count) / 2;
+        }
+    }
+
+    pub fn finish(mut self) -> (CallStackTree, Vec<(NodeId, JavaMethod)>) {
+        if self.frozen || self.max_depth != std::usize::MAX {
+            let max_depth = if self.max_depth == std::usize::MAX { std::i32::MAX } else { self.max_depth as i32 };
+            let label = tree::get_coarsened_label(&self.call_tree.get_root_node().data.name, max_depth, self.frozen);
+            self.call_tree.get_mut_root_node().data.name = label;
+        }
+        (self.call_tree, self.naming_nodes)
+    }
+}
+
 pub struct CallStackTree {
     nodes: Vec<TreeNode>,
     root_node: NodeId,
@@ -253,6 +337,51 @@ impl CallStackTree {
     pub fn get_root_node(&self) -> &TreeNode {
         &self.nodes[self.root_node.index]
     }
+
+    pub fn get_mut_root_node(&mut self) -> &mut TreeNode {
+        &mut self.nodes[self.root_node.index]
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Remove the nodes deeper than max depth, return the new index of each old node, None if removed.
+    pub fn prune(&mut self, max_depth: u32) -> Vec<Option<usize>> {
+        let mut new_indexes = Vec::with_capacity(self.nodes.len());
+        let mut count = 0;
+        for node in &self.nodes {
+            if node.data.depth <= max_depth {
+                new_indexes.push(Some(count));
+                count += 1;
+            } else {
+                new_indexes.push(None);
+            }
+        }
+        //parent is always before children in arena, so the kept nodes keep their order
+        let old_nodes = std::mem::replace(&mut self.nodes, Vec::with_capacity(count));
+        for (old_index, mut node) in old_nodes.into_iter().enumerate() {
+            let index = match new_indexes[old_index] {
+                Some(index) => index,
+                None => continue
+            };
+            node.data.node_id = NodeId { index };
+            node.parent = node.parent.and_then(|parent| new_indexes[parent.index].map(|index| NodeId { index }));
+            node.children = node.children.into_iter()
+                .filter_map(|(key, child)| new_indexes[child.index].map(|index| (key, NodeId { index })))
+                .collect();
+            node.data.children_size = node.children.len() as u32;
+            self.nodes.push(node);
+        }
+        self.root_node = NodeId { index: 0 };
+        self.top_call_stack_node = self.root_node;
+        new_indexes
+    }
+
+    //check call node exists under top node
+    pub fn has_child_call(&self, method_id: &JavaMethod) -> bool {
+        self.get_top_node().find_child(method_id).is_some()
+    }
 }
 
 #[derive(Clone)]
@@ -335,4 +464,56 @@ impl TreeNode {
         self.children.get(&key)
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_sample(sample_time: i64, stacktrace: Vec<i64>) -> ThreadData {
+        let mut thread = ThreadData::new(1, "worker");
+        thread.sample_time = sample_time;
+        thread.stacktrace = stacktrace;
+        thread
+    }
+
+    #[test]
+    fn test_coarsen_prunes_tree() {
+        let max_nodes = 100;
+        let mut builder = CallTreeBuilder::new(max_nodes);
+        for i in 0..50 {
+            //leaf first, the same root method
+            let mut stacktrace: Vec<i64> = (1..40).map(|d| i * 100 + d + 1000).collect();
+            stacktrace.push(1);
+            builder.add_stack_trace(&new_sample(i + 1, stacktrace));
+            assert!(builder.call_tree.node_count() <= max_nodes);
+        }
+        let (tree, naming_nodes) = builder.finish();
+        assert!(naming_nodes.iter().all(|(node_id, _)| node_id.index < tree.node_count()));
+        let root = tree.get_root_node();
+        assert_eq!(root.children.len(), 1);
+        let top = tree.get_node(&root.children[&1]);
+        assert_eq!(top.data.call_count, 50);
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut tree = CallStackTree::new(1, "main");
+        for stack in &[vec![1, 2, 3], vec![1, 2, 4], vec![1, 5]] {
+            tree.reset_top_call_stack_node();
+            for method in stack {
+                tree.begin_call(method, 10, 0);
+            }
+        }
+        assert_eq!(tree.node_count(), 6);
+        let new_indexes = tree.prune(2);
+        assert_eq!(tree.node_count(), 4);
+        assert_eq!(new_indexes.iter().filter(|x| x.is_none()).count(), 2);
+        let node1 = tree.get_node(&tree.get_root_node().children[&1]);
+        assert_eq!(node1.data.call_count, 3);
+        let node2 = tree.get_node(&node1.children[&2]);
+        assert_eq!(node2.data.call_duration, 20);
+        assert!(node2.children.is_empty());
+        assert_eq!(node2.parent.unwrap().index, node1.data.node_id.index);
+    }
+}
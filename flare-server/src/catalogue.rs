@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use chrono::Local;
+use segment::load_summary_info;
+use trigger::TriggerMarker;
+use utils::*;
+
+const CATALOGUE_FILE: &str = "catalogue.json";
+
+/// Metadata of a recording, user fields (name, tags, notes) are kept when rescanning sample dirs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecordingInfo {
+    pub recording_id: String,
+    pub name: String,
+    pub agent_addr: String,
+    #[serde(default)]
+    pub jvm_pid: i64,
+    #[serde(default)]
+    pub main_class: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub size_bytes: u64,
+    pub thread_count: usize,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub notes: String,
+    //fired triggers of conditional recording
+    #[serde(default)]
+    pub markers: Vec<TriggerMarker>,
+    pub sample_dirs: Vec<String>,
+}
+
+/// Prune old recordings by age or total disk size, zero means unlimited.
+#[derive(Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    pub max_age_ms: i64,
+    pub max_total_bytes: u64,
+}
+
+/// Persisted catalogue of recordings under the samples dir.
+pub struct RecordingCatalogue {
+    samples_dir: String,
+    recordings: BTreeMap<String, RecordingInfo>,
+    retention_policy: RetentionPolicy,
+}
+
+impl RecordingCatalogue {
+    pub fn new(samples_dir: &str) -> RecordingCatalogue {
+        let mut catalogue = RecordingCatalogue {
+            samples_dir: samples_dir.to_string(),
+            recordings: BTreeMap::new(),
+            retention_policy: RetentionPolicy::default(),
+        };
+        let path = catalogue.get_catalogue_path();
+        if let Ok(json) = std::fs::read_to_string(&path) {
+            match serde_json::from_str::<Vec<RecordingInfo>>(&json) {
+                Ok(recordings) => {
+                    for recording in recordings {
+                        catalogue.recordings.insert(recording.recording_id.clone(), recording);
+                    }
+                },
+                Err(e) => {
+                    println!("load recording catalogue failed: {}, err: {}", path, e);
+                }
+            }
+        }
+        catalogue
+    }
+
+    fn get_catalogue_path(&self) -> String {
+        format!("{}/{}", self.samples_dir, CATALOGUE_FILE)
+    }
+
+    pub fn set_retention_policy(&mut self, policy: RetentionPolicy) {
+        self.retention_policy = policy;
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let recordings: Vec<&RecordingInfo> = self.recordings.values().collect();
+        let json = serde_json::to_string_pretty(&recordings)?;
+        std::fs::write(self.get_catalogue_path(), json)
+    }
+
+    /// Rescan sample dirs, group rolled dirs by recording id and update metadata.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let scanned = scan_recordings(&self.samples_dir)?;
+        self.update(scanned)
+    }
+
+    /// Update metadata with the scanned recordings, user fields are kept.
+    pub fn update(&mut self, mut scanned: BTreeMap<String, RecordingInfo>) -> io::Result<()> {
+        for (recording_id, recording) in scanned.iter_mut() {
+            let mut new_markers: Vec<&TriggerMarker> = recording.markers.iter().collect();
+            if let Some(old) = self.recordings.get(recording_id) {
+                recording.name = old.name.clone();
+                recording.tags = old.tags.clone();
+                recording.notes = old.notes.clone();
+                new_markers.retain(|x| !old.markers.iter().any(|m| m.time == x.time && m.rule_name == x.rule_name));
+            }
+            //tag the recording when a trigger is fired, the tag can be removed by user
+            for marker in new_markers {
+                let tag = format!("trigger:{}", marker.rule_name);
+                if !recording.tags.contains(&tag) {
+                    recording.tags.push(tag);
+                }
+            }
+        }
+        self.recordings = scanned;
+        self.save()
+    }
+
+    pub fn list_recordings(&self) -> Vec<RecordingInfo> {
+        self.recordings.values().cloned().collect()
+    }
+
+    pub fn get_recording_dirs(&self, recording_id: &str) -> Option<Vec<String>> {
+        self.recordings.get(recording_id).map(|recording| recording.sample_dirs.clone())
+    }
+
+    pub fn get_recording(&self, recording_id: &str) -> io::Result<&RecordingInfo> {
+        self.recordings.get(recording_id).ok_or_else(|| new_error(ErrorKind::NotFound, "recording not found"))
+    }
+
+    pub fn rename(&mut self, recording_id: &str, name: &str) -> io::Result<RecordingInfo> {
+        let recording = self.get_recording_mut(recording_id)?;
+        recording.name = name.to_string();
+        let recording = recording.clone();
+        self.save()?;
+        Ok(recording)
+    }
+
+    pub fn set_tags(&mut self, recording_id: &str, tags: Vec<String>, notes: Option<&str>) -> io::Result<RecordingInfo> {
+        let recording = self.get_recording_mut(recording_id)?;
+        let mut tags: Vec<String> = tags.into_iter().filter(|tag| !tag.is_empty()).collect();
+        tags.sort();
+        tags.dedup();
+        recording.tags = tags;
+        if let Some(notes) = notes {
+            recording.notes = notes.to_string();
+        }
+        let recording = recording.clone();
+        self.save()?;
+        Ok(recording)
+    }
+
+    fn get_recording_mut(&mut self, recording_id: &str) -> io::Result<&mut RecordingInfo> {
+        self.recordings.get_mut(recording_id).ok_or_else(|| new_error(ErrorKind::NotFound, "recording not found"))
+    }
+
+    /// Delete sample dirs of the recording.
+    pub fn delete(&mut self, recording_id: &str) -> io::Result<RecordingInfo> {
+        let recording = self.get_recording(recording_id)?.clone();
+        for dir in &recording.sample_dirs {
+            //only remove dirs under samples dir
+            if !Path::new(dir).starts_with(&self.samples_dir) {
+                return Err(new_invalid_input_error(&format!("sample dir is out of samples dir: {}", dir)));
+            }
+        }
+        for dir in &recording.sample_dirs {
+            println!("delete sample dir: {}", dir);
+            std::fs::remove_dir_all(dir)?;
+        }
+        self.recordings.remove(recording_id);
+        self.save()?;
+        Ok(recording)
+    }
+
+    /// Search by keyword of name, notes, agent address and main class, all tags must be matched.
+    pub fn search(&self, keyword: &str, tags: &[String], start_time: i64, end_time: i64) -> Vec<RecordingInfo> {
+        let keyword = keyword.to_lowercase();
+        self.recordings.values().filter(|recording| {
+            if !keyword.is_empty() {
+                let matched = [&recording.recording_id, &recording.name, &recording.notes, &recording.agent_addr, &recording.main_class]
+                    .iter().any(|x| x.to_lowercase().contains(&keyword));
+                if !matched {
+                    return false;
+                }
+            }
+            if !tags.iter().all(|tag| recording.tags.contains(tag)) {
+                return false;
+            }
+            //time range overlapped
+            (start_time <= 0 || recording.end_time >= start_time) && (end_time <= 0 || recording.start_time <= end_time)
+        }).cloned().collect()
+    }
+
+    pub fn get_retention_policy(&self) -> RetentionPolicy {
+        self.retention_policy
+    }
+
+    /// Prune recordings exceeded the retention policy, the protected recordings (opened sessions) are kept.
+    /// The recordings are scanned by the caller without holding the catalogue.
+    pub fn apply_retention(&mut self, scanned: BTreeMap<String, RecordingInfo>, protected_ids: &HashSet<String>) -> io::Result<Vec<String>> {
+        let policy = self.retention_policy;
+        if policy.max_age_ms <= 0 && policy.max_total_bytes == 0 {
+            return Ok(vec![]);
+        }
+        self.update(scanned)?;
+
+        //oldest first
+        let mut recordings: Vec<RecordingInfo> = self.recordings.values().cloned().collect();
+        recordings.sort_by_key(|r| r.end_time);
+        let mut total_bytes: u64 = recordings.iter().map(|r| r.size_bytes).sum();
+        let now = Local::now().timestamp_millis();
+
+        let mut deleted = vec![];
+        for recording in &recordings {
+            //recording without samples has unknown age
+            if protected_ids.contains(&recording.recording_id) || recording.end_time <= 0 {
+                continue;
+            }
+            let expired = policy.max_age_ms > 0 && now - recording.end_time > policy.max_age_ms;
+            let over_quota = policy.max_total_bytes > 0 && total_bytes > policy.max_total_bytes;
+            if !expired && !over_quota {
+                continue;
+            }
+            println!("prune recording: {}, expired: {}, over quota: {}", recording.recording_id, expired, over_quota);
+            match self.delete(&recording.recording_id) {
+                Ok(_) => {
+                    total_bytes -= recording.size_bytes;
+                    deleted.push(recording.recording_id.clone());
+                },
+                Err(e) => {
+                    println!("prune recording failed: {}, err: {}", recording.recording_id, e);
+                }
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// Scan sample dirs, rolled dirs of one recording are grouped by recording id.
+pub fn scan_recordings(samples_dir: &str) -> io::Result<BTreeMap<String, RecordingInfo>> {
+    let mut scanned: BTreeMap<String, RecordingInfo> = BTreeMap::new();
+    for entry in std::fs::read_dir(samples_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let dir = path.to_str().unwrap_or("").to_string();
+        let summary = match load_summary_info(&dir) {
+            Ok(summary) => summary,
+            Err(_) => continue
+        };
+        let sample_info = &summary.sample_info;
+        //dirs created before recording id was added are standalone recordings
+        let recording_id = if sample_info.recording_id.is_empty() {
+            path.file_name().and_then(|x| x.to_str()).unwrap_or("").to_string()
+        } else {
+            sample_info.recording_id.clone()
+        };
+        let recording = scanned.entry(recording_id.clone()).or_insert_with(|| RecordingInfo {
+            recording_id: recording_id.clone(),
+            name: recording_id.clone(),
+            agent_addr: sample_info.agent_addr.clone(),
+            jvm_pid: sample_info.jvm_pid,
+            main_class: sample_info.main_class.clone(),
+            start_time: sample_info.record_start_time,
+            end_time: sample_info.last_record_time,
+            size_bytes: 0,
+            thread_count: 0,
+            tags: vec![],
+            notes: "".to_string(),
+            markers: vec![],
+            sample_dirs: vec![],
+        });
+        if sample_info.record_start_time > 0 {
+            recording.start_time = if recording.start_time > 0 { recording.start_time.min(sample_info.record_start_time) } else { sample_info.record_start_time };
+        }
+        recording.end_time = recording.end_time.max(sample_info.last_record_time);
+        recording.thread_count = recording.thread_count.max(summary.threads.len());
+        recording.size_bytes += get_dir_size(&path);
+        recording.sample_dirs.push(dir);
+        //markers are copied to the rolled dir
+        for marker in &sample_info.markers {
+            match recording.markers.iter_mut().find(|x| x.time == marker.time && x.rule_name == marker.rule_name) {
+                Some(x) => x.window_end = x.window_end.max(marker.window_end),
+                None => recording.markers.push(marker.clone())
+            }
+        }
+    }
+    for recording in scanned.values_mut() {
+        recording.sample_dirs.sort();
+        recording.markers.sort_by_key(|x| x.time);
+    }
+    Ok(scanned)
+}
+
+fn get_dir_size(path: &Path) -> u64 {
+    let mut size = 0;
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries {
+            if let Ok(meta) = entry.and_then(|e| e.metadata()) {
+                if meta.is_file() {
+                    size += meta.len();
+                }
+            }
+        }
+    }
+    size
+}
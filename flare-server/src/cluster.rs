@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tree::MethodStats;
+
+pub const CLUSTER_SESSION_PREFIX: &str = "cluster:";
+
+/// Sample session of one instance in a cluster, the session is a normal attach or file session.
+#[derive(Clone, Serialize)]
+pub struct ClusterMember {
+    //agent addr or sample dir
+    pub instance: String,
+    pub session_id: String,
+    //the session is opened by the cluster and closed with it
+    #[serde(skip)]
+    pub created: bool,
+}
+
+/// Sessions of several instances of the same service, analyzed together.
+/// Methods are matched by name across instances, as jmethodIDs differ per jvm.
+#[derive(Clone, Serialize)]
+pub struct ClusterSession {
+    pub cluster_id: String,
+    pub name: String,
+    pub members: Vec<ClusterMember>,
+}
+
+pub struct ClusterRegistry {
+    clusters: BTreeMap<String, ClusterSession>,
+    //cluster ids of opening clusters
+    reserved: HashSet<String>,
+}
+
+impl ClusterRegistry {
+    pub fn new() -> ClusterRegistry {
+        ClusterRegistry {
+            clusters: BTreeMap::new(),
+            reserved: HashSet::new(),
+        }
+    }
+
+    /// Reserve the cluster id before opening instances, return false if it is opened or opening.
+    pub fn reserve(&mut self, cluster_id: &str) -> bool {
+        !self.clusters.contains_key(cluster_id) && self.reserved.insert(cluster_id.to_string())
+    }
+
+    pub fn release(&mut self, cluster_id: &str) {
+        self.reserved.remove(cluster_id);
+    }
+
+    pub fn add(&mut self, cluster: ClusterSession) {
+        self.reserved.remove(&cluster.cluster_id);
+        self.clusters.insert(cluster.cluster_id.clone(), cluster);
+    }
+
+    pub fn get(&self, cluster_id: &str) -> Option<&ClusterSession> {
+        self.clusters.get(cluster_id)
+    }
+
+    pub fn remove(&mut self, cluster_id: &str) -> Option<ClusterSession> {
+        self.clusters.remove(cluster_id)
+    }
+
+    pub fn list(&self) -> Vec<ClusterSession> {
+        self.clusters.values().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.clusters.clear();
+    }
+}
+
+pub fn get_cluster_id(name: &str) -> String {
+    format!("{}{}", CLUSTER_SESSION_PREFIX, name)
+}
+
+/// Sum collapsed stacks "a;b;c 10" of instances, optionally put each instance under its own root frame.
+pub fn merge_collapsed_stacks(instance_stacks: &[(String, Vec<String>)], per_instance_root: bool) -> Vec<String> {
+    let mut merged: BTreeMap<String, i64> = BTreeMap::new();
+    for (instance, stacks) in instance_stacks {
+        let root_frame = instance.replace(";", "_");
+        for line in stacks {
+            let (stack, count) = match line.rfind(' ') {
+                Some(pos) => (&line[..pos], line[pos+1..].parse::<i64>().unwrap_or(0)),
+                None => continue
+            };
+            let key = if per_instance_root { format!("{};{}", root_frame, stack) } else { stack.to_string() };
+            *merged.entry(key).or_insert(0) += count;
+        }
+    }
+    merged.into_iter().map(|(stack, count)| format!("{} {}", stack, count)).collect()
+}
+
+/// Stats of a method in one instance.
+#[derive(Clone, Serialize)]
+pub struct InstanceMethodStats {
+    pub instance: String,
+    pub session_id: String,
+    pub self_stats: MethodStats,
+    pub total_stats: MethodStats,
+}
+
+/// Flat profile of the cluster, methods are keyed by full name.
+pub struct ClusterProfileBuilder {
+    pub methods: HashMap<String, Vec<InstanceMethodStats>>,
+    pub total_stats: MethodStats,
+}
+
+impl ClusterProfileBuilder {
+    pub fn new() -> ClusterProfileBuilder {
+        ClusterProfileBuilder {
+            methods: HashMap::new(),
+            total_stats: MethodStats::default(),
+        }
+    }
+
+    pub fn add_instance_total(&mut self, total_stats: &MethodStats) {
+        self.total_stats.merge(total_stats);
+    }
+
+    pub fn add_method(&mut self, method_name: String, stats: InstanceMethodStats) {
+        let instances = self.methods.entry(method_name).or_insert_with(|| vec![]);
+        //different method ids of one instance may have the same name, e.g. reloaded class
+        match instances.iter_mut().find(|x| x.session_id == stats.session_id) {
+            Some(instance) => {
+                instance.self_stats.merge(&stats.self_stats);
+                instance.total_stats.merge(&stats.total_stats);
+            },
+            None => instances.push(stats)
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use utils::*;
+use sample::ReconnectPolicy;
+
+//loaded from current dir if exists and no '--config' flag
+pub const DEFAULT_CONFIG_FILE: &str = "flare-server.toml";
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+const USAGE: &str = "Usage: flare-server [options]
+Options:
+  --config <file>               config file (toml), default: flare-server.toml if exists
+  --ws-bind <addr>              websocket server bind addr, default: 0.0.0.0:3891
+  --http-bind <addr>            http server bind addr, default: 0.0.0.0:3890
+  --data-dir <dir>              sample data dir, relative to install dir, default: flare-samples
+  --exports-dir <dir>           exported archives dir, relative to install dir, default: flare-exports
+  --static-dir <dir>            web ui static dir, default: static/ or res/static/
+  --roll-period-minutes <n>     time period of a sample data dir, default: 15
+  --retention-max-age-hours <n> prune recordings older than n hours, 0 is unlimited
+  --retention-max-size-mb <n>   prune oldest recordings when exceeded, 0 is unlimited
+  --range-cache-mb <n>          max memory of query cache, default: 256
+  --tree-memory-mb <n>          max memory of a built tree, default: 256
+  --reconnect-max-retries <n>   reconnect attempts of lost agent connection, 0 disables, default: 10
+  --reconnect-delay-ms <n>      delay of the first reconnect attempt, doubled after each failure, default: 1000
+  --reconnect-max-delay-ms <n>  max delay between reconnect attempts, default: 60000
+  --log-level <level>           level of log records: off, error, warn, info, debug or trace, default: info
+  -h, --help                    print this help";
+
+/// Settings of flare-server, read from toml config file and overridden by command line flags.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub ws_bind_addr: String,
+    pub http_bind_addr: String,
+    pub data_dir: String,
+    pub exports_dir: String,
+    //empty means lookup static/ or res/static/ in current dir and install dir
+    pub static_dir: String,
+    pub roll_period_minutes: i64,
+    pub retention_max_age_hours: i64,
+    pub retention_max_size_mb: u64,
+    pub range_cache_mb: usize,
+    pub tree_memory_mb: usize,
+    pub reconnect_max_retries: u32,
+    pub reconnect_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    pub log_level: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ws_bind_addr: "0.0.0.0:3891".to_string(),
+            http_bind_addr: "0.0.0.0:3890".to_string(),
+            data_dir: "flare-samples".to_string(),
+            exports_dir: "flare-exports".to_string(),
+            static_dir: String::new(),
+            roll_period_minutes: 15,
+            retention_max_age_hours: 0,
+            retention_max_size_mb: 0,
+            range_cache_mb: 256,
+            tree_memory_mb: 256,
+            reconnect_max_retries: 10,
+            reconnect_delay_ms: 1000,
+            reconnect_max_delay_ms: 60_000,
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+impl ServerConfig {
+
+    /// Parse command line args (without program name), return None if '--help' is given.
+    pub fn from_args(args: &[String]) -> io::Result<Option<ServerConfig>> {
+        let mut flags = vec![];
+        let mut config_file = None;
+        let mut i = 0;
+        while i < args.len() {
+            let name = args[i].as_str();
+            if name == "-h" || name == "--help" {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            if !name.starts_with("--") {
+                return Err(new_invalid_input_error(&format!("unexpected argument: {}\n{}", name, USAGE)));
+            }
+            let value = match args.get(i + 1) {
+                Some(value) => value.clone(),
+                None => return Err(new_invalid_input_error(&format!("missing value of flag: {}", name)))
+            };
+            if name == "--config" {
+                config_file = Some(value);
+            } else {
+                flags.push((name.to_string(), value));
+            }
+            i += 2;
+        }
+
+        let mut config = match config_file {
+            Some(path) => ServerConfig::read_from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => ServerConfig::read_from_file(DEFAULT_CONFIG_FILE)?,
+            None => ServerConfig::default()
+        };
+        for (name, value) in &flags {
+            config.set_flag(name, value)?;
+        }
+        config.data_dir = resolve_install_path(&config.data_dir);
+        config.exports_dir = resolve_install_path(&config.exports_dir);
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    pub fn read_from_file<T: AsRef<Path>>(path: T) -> io::Result<ServerConfig> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| new_invalid_input_error(&format!("read config file failed: {}, error: {}", path.display(), e)))?;
+        toml::from_str(&contents)
+            .map_err(|e| new_invalid_input_error(&format!("parse config file failed: {}, error: {}", path.display(), e)))
+    }
+
+    fn set_flag(&mut self, name: &str, value: &str) -> io::Result<()> {
+        match name {
+            "--ws-bind" => self.ws_bind_addr = value.to_string(),
+            "--http-bind" => self.http_bind_addr = value.to_string(),
+            "--data-dir" => self.data_dir = value.to_string(),
+            "--exports-dir" => self.exports_dir = value.to_string(),
+            "--static-dir" => self.static_dir = value.to_string(),
+            "--roll-period-minutes" => self.roll_period_minutes = parse_flag_value(name, value)?,
+            "--retention-max-age-hours" => self.retention_max_age_hours = parse_flag_value(name, value)?,
+            "--retention-max-size-mb" => self.retention_max_size_mb = parse_flag_value(name, value)?,
+            "--range-cache-mb" => self.range_cache_mb = parse_flag_value(name, value)?,
+            "--tree-memory-mb" => self.tree_memory_mb = parse_flag_value(name, value)?,
+            "--reconnect-max-retries" => self.reconnect_max_retries = parse_flag_value(name, value)?,
+            "--reconnect-delay-ms" => self.reconnect_delay_ms = parse_flag_value(name, value)?,
+            "--reconnect-max-delay-ms" => self.reconnect_max_delay_ms = parse_flag_value(name, value)?,
+            "--log-level" => self.log_level = value.to_string(),
+            _ => return Err(new_invalid_input_error(&format!("unknown flag: {}\n{}", name, USAGE)))
+        }
+        Ok(())
+    }
+
+    /// Check the settings, the data dirs are created if not exist.
+    pub fn validate(&self) -> io::Result<()> {
+        let ws_addr = parse_bind_addr("ws_bind_addr", &self.ws_bind_addr)?;
+        let http_addr = parse_bind_addr("http_bind_addr", &self.http_bind_addr)?;
+        if ws_addr.port() == http_addr.port() && (ws_addr.ip() == http_addr.ip() || ws_addr.ip().is_unspecified() || http_addr.ip().is_unspecified()) {
+            return Err(new_invalid_input_error(&format!("ws_bind_addr and http_bind_addr use the same port: {}", ws_addr.port())));
+        }
+        if self.roll_period_minutes <= 0 {
+            return Err(new_invalid_input_error(&format!("roll_period_minutes must be positive: {}", self.roll_period_minutes)));
+        }
+        if self.retention_max_age_hours < 0 {
+            return Err(new_invalid_input_error(&format!("retention_max_age_hours must not be negative: {}", self.retention_max_age_hours)));
+        }
+        if self.range_cache_mb == 0 {
+            return Err(new_invalid_input_error("range_cache_mb must be positive"));
+        }
+        if self.tree_memory_mb == 0 {
+            return Err(new_invalid_input_error("tree_memory_mb must be positive"));
+        }
+        if self.reconnect_delay_ms == 0 || self.reconnect_max_delay_ms < self.reconnect_delay_ms {
+            return Err(new_invalid_input_error(&format!("invalid reconnect delay: {} - {} ms, expected 0 < reconnect_delay_ms <= reconnect_max_delay_ms",
+                                                        self.reconnect_delay_ms, self.reconnect_max_delay_ms)));
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return Err(new_invalid_input_error(&format!("invalid log_level: {}, expected one of: {}", self.log_level, LOG_LEVELS.join(", "))));
+        }
+        check_dir("data_dir", &self.data_dir)?;
+        check_dir("exports_dir", &self.exports_dir)?;
+        if !self.static_dir.is_empty() && !Path::new(&self.static_dir).is_dir() {
+            return Err(new_invalid_input_error(&format!("static_dir is not a dir: {}", self.static_dir)));
+        }
+        Ok(())
+    }
+
+    pub fn get_ws_bind_addr(&self) -> SocketAddr {
+        self.ws_bind_addr.parse().expect("invalid ws_bind_addr")
+    }
+
+    pub fn get_http_bind_addr(&self) -> SocketAddr {
+        self.http_bind_addr.parse().expect("invalid http_bind_addr")
+    }
+
+    pub fn get_roll_period_ms(&self) -> i64 {
+        self.roll_period_minutes * 60_000
+    }
+
+    pub fn get_retention_max_age_ms(&self) -> i64 {
+        self.retention_max_age_hours * 3600_000
+    }
+
+    pub fn get_retention_max_total_bytes(&self) -> u64 {
+        self.retention_max_size_mb * 1024 * 1024
+    }
+
+    pub fn get_range_cache_bytes(&self) -> usize {
+        self.range_cache_mb * 1024 * 1024
+    }
+
+    pub fn get_tree_memory_bytes(&self) -> usize {
+        self.tree_memory_mb * 1024 * 1024
+    }
+
+    pub fn get_reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_retries: self.reconnect_max_retries,
+            initial_delay_ms: self.reconnect_delay_ms,
+            max_delay_ms: self.reconnect_max_delay_ms,
+        }
+    }
+
+    /// Configured static dir, or the first of static/ and res/static/ found in current dir or install dir.
+    pub fn find_static_dir(&self) -> String {
+        if !self.static_dir.is_empty() {
+            return self.static_dir.clone();
+        }
+        let mut base_dirs = vec![PathBuf::from(".")];
+        base_dirs.extend(get_install_dir());
+        if let Some(dir) = std::env::current_exe().ok().and_then(|x| x.parent().map(|x| x.to_path_buf())) {
+            base_dirs.push(dir);
+        }
+        for base_dir in &base_dirs {
+            for name in &["static", "res/static"] {
+                let path = base_dir.join(name);
+                if path.is_dir() {
+                    return format!("{}/", path.display());
+                }
+            }
+        }
+        "static/".to_string()
+    }
+}
+
+//install dir is the parent of bin dir, None if not started from bin dir
+fn get_install_dir() -> Option<PathBuf> {
+    let exe_path = std::env::current_exe().ok()?;
+    let dir = exe_path.parent()?;
+    if dir.ends_with("bin") {
+        dir.parent().map(|x| x.to_path_buf())
+    } else {
+        None
+    }
+}
+
+//relative path is resolved against install dir, or current dir if not installed
+fn resolve_install_path(path: &str) -> String {
+    if path.is_empty() || Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+    match get_install_dir() {
+        Some(dir) => dir.join(path).display().to_string(),
+        None => path.to_string()
+    }
+}
+
+fn parse_bind_addr(name: &str, addr: &str) -> io::Result<SocketAddr> {
+    addr.parse().map_err(|e| new_invalid_input_error(&format!("invalid {}: {}, expected ip:port, error: {}", name, addr, e)))
+}
+
+fn check_dir(name: &str, dir: &str) -> io::Result<()> {
+    if dir.is_empty() {
+        return Err(new_invalid_input_error(&format!("{} must not be empty", name)));
+    }
+    std::fs::create_dir_all(dir)
+        .map_err(|e| new_invalid_input_error(&format!("create {} failed: {}, error: {}", name, dir, e)))
+}
@@ -3,18 +3,44 @@
 // Run `cargo doc && cargo run --example doc_server`, then
 // point your browser to http://localhost:3000/
 
-use futures::{future, Async::*, Future, Poll};
+use futures::{future, Async::*, Future, Poll, Stream};
+use futures::sync::oneshot;
 use http::response::Builder as ResponseBuilder;
 use http::{header, Request, Response, StatusCode};
 use hyper::Body;
 use hyper_staticfile::{Static, StaticFuture};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::io;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use serde_json::{json, Value};
+use utils::*;
+use Profiler;
+
+//http api: /api/<cmd>?<options>, e.g. /api/call_tree?session_id=localhost:3333&thread_ids=1,2
+const API_PATH_PREFIX: &str = "/api/";
+
+//read only cmds of http api, the cmds changing sessions or reading paths require the ws connection
+const API_READ_CMDS: &[&str] = &["list_sessions", "history_samples", "search_recordings", "dashboard", "cpu_time",
+    "call_tree", "sequenced_call_tree", "call_tree_children", "bottom_up_tree", "flame_graph", "diff_flame_graph",
+    "method_butterfly", "hot_methods", "list_methods_by_filter", "search_slow_method_calls", "trigger_markers",
+    "cluster_flame_graph", "cluster_hot_methods"];
+
+//query params converted from string, other params are kept as string
+const INT_PARAMS: &[&str] = &["start_time", "end_time", "thread_id", "method_id", "top_n", "offset", "unit_time_ms",
+    "min_duration", "max_duration", "image_width", "graph_width", "package_depth", "max_size", "interval_ms"];
+const INT_ARRAY_PARAMS: &[&str] = &["thread_ids", "method_ids", "node_path"];
+const STR_ARRAY_PARAMS: &[&str] = &["strip_package_prefixes", "tags"];
+const BOOL_PARAMS: &[&str] = &["inverted", "normalize", "all_threads", "per_thread_root", "per_instance_root"];
+const JSON_PARAMS: &[&str] = &["transforms"];
 
 /// Future returned from `MainService`.
 enum MainFuture {
     Root,
     Static(StaticFuture<Body>),
+    Api(Box<Future<Item = Response<Body>, Error = Error> + Send>),
 }
 
 impl Future for MainFuture {
@@ -32,6 +58,7 @@ impl Future for MainFuture {
                 Ok(Ready(res))
             }
             MainFuture::Static(ref mut future) => future.poll(),
+            MainFuture::Api(ref mut future) => future.poll(),
         }
     }
 }
@@ -39,12 +66,14 @@ impl Future for MainFuture {
 /// Hyper `Service` implementation that serves all requests.
 struct MainService {
     static_: Static,
+    profiler: Arc<Profiler>,
 }
 
 impl MainService {
-    fn new(static_dir: &str) -> MainService {
+    fn new(static_dir: &str, profiler: Arc<Profiler>) -> MainService {
         MainService {
             static_: Static::new(Path::new(static_dir)),
+            profiler,
         }
     }
 }
@@ -61,28 +90,171 @@ impl hyper::service::Service for MainService {
 //        } else {
 //            MainFuture::Static(self.static_.serve(req))
 //        }
+        if req.uri().path().starts_with(API_PATH_PREFIX) {
+            return MainFuture::Api(handle_api_request(self.profiler.clone(), req));
+        }
         MainFuture::Static(self.static_.serve(req))
     }
 }
 
+/// Run the cmd by Profiler::handle_buffered_request(), the same handlers as ws requests.
+/// Options are query params or json object of POST body. Flame graphs are returned as svg
+/// unless 'format=json', other cmds return the response json (array if more than one message).
+fn handle_api_request(profiler: Arc<Profiler>, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+    let cmd = get_api_cmd(&req.uri().path()[API_PATH_PREFIX.len()..]).to_string();
+    if !API_READ_CMDS.contains(&cmd.as_str()) {
+        let message = format!("cmd is not allowed in http api: {}", cmd);
+        return Box::new(future::ok(create_api_error_response(StatusCode::FORBIDDEN, &cmd, &message)));
+    }
+    let query = req.uri().query().unwrap_or("").to_string();
+    let future = req.into_body().concat2()
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
+        .and_then(move |body| {
+            let (tx, rx) = oneshot::channel();
+            //handlers are blocking, run them out of the http event loop
+            thread::spawn(move || {
+                let response = match parse_api_options(&query, &body) {
+                    Ok(options) => {
+                        let format = get_option_as_str(&options, "format", "").to_string();
+                        let request = json!({"cmd": cmd, "options": options});
+                        let messages = profiler.handle_buffered_request(request.to_string());
+                        create_api_response(&cmd, &format, messages)
+                    },
+                    Err(e) => create_api_error_response(StatusCode::BAD_REQUEST, &cmd, &e.to_string())
+                };
+                let _ = tx.send(response);
+            });
+            rx.map_err(|_| Error::new(ErrorKind::Other, "api request is aborted"))
+        });
+    Box::new(future)
+}
+
+//short names of cmds
+fn get_api_cmd(name: &str) -> &str {
+    match name {
+        "sessions" => "list_sessions",
+        "search" => "search_slow_method_calls",
+        _ => name
+    }
+}
+
+fn parse_api_options(query: &str, body: &[u8]) -> io::Result<serde_json::Map<String, Value>> {
+    let mut options = serde_json::Map::new();
+    if !body.is_empty() {
+        match serde_json::from_slice::<Value>(body)? {
+            Value::Object(map) => options = map,
+            _ => return Err(new_invalid_input_error("request body is not a json object"))
+        }
+    }
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(pos) => (&pair[..pos], &pair[pos+1..]),
+            None => (pair, "")
+        };
+        let key = percent_decode(key);
+        let value = parse_param_value(&key, &percent_decode(value));
+        options.insert(key, value);
+    }
+    Ok(options)
+}
+
+//convert value of the known params, e.g. "thread_ids=1,2" or "thread_ids=[1,2]", invalid values are kept as string
+fn parse_param_value(key: &str, value: &str) -> Value {
+    let parsed = if INT_PARAMS.contains(&key) {
+        value.trim().parse::<i64>().ok().map(Value::from)
+    } else if INT_ARRAY_PARAMS.contains(&key) {
+        if value.trim_start().starts_with('[') {
+            serde_json::from_str::<Value>(value).ok().filter(|x| x.is_array())
+        } else {
+            value.split(',').map(|x| x.trim().parse::<i64>().map(Value::from)).collect::<Result<Vec<Value>, _>>().ok().map(Value::Array)
+        }
+    } else if STR_ARRAY_PARAMS.contains(&key) {
+        Some(Value::Array(value.split(',').map(|x| Value::from(x.trim())).collect()))
+    } else if BOOL_PARAMS.contains(&key) {
+        match value {
+            "true" | "1" => Some(Value::Bool(true)),
+            "false" | "0" => Some(Value::Bool(false)),
+            _ => None
+        }
+    } else if JSON_PARAMS.contains(&key) {
+        serde_json::from_str::<Value>(value).ok()
+    } else {
+        None
+    };
+    parsed.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => result.push(b' '),
+            b'%' if i + 2 < bytes.len() && parse_hex_byte(&bytes[i+1..i+3]).is_some() => {
+                result.push(parse_hex_byte(&bytes[i+1..i+3]).unwrap());
+                i += 2;
+            },
+            x => result.push(x)
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
+
+fn parse_hex_byte(hex: &[u8]) -> Option<u8> {
+    std::str::from_utf8(hex).ok().and_then(|x| u8::from_str_radix(x, 16).ok())
+}
+
+fn create_api_response(cmd: &str, format: &str, messages: Vec<String>) -> Response<Body> {
+    let mut values = vec![];
+    for message in &messages {
+        match serde_json::from_str::<Value>(message) {
+            Ok(value) => values.push(value),
+            Err(e) => return create_api_error_response(StatusCode::INTERNAL_SERVER_ERROR, cmd, &e.to_string())
+        }
+    }
+    if values.is_empty() {
+        return create_api_error_response(StatusCode::NOT_FOUND, cmd, &format!("unknown cmd: {}", cmd));
+    }
+    if let Some(value) = values.iter().find(|x| x["result"] == "failure") {
+        return create_response(StatusCode::BAD_REQUEST, "application/json", value.to_string());
+    }
+    if format != "json" {
+        if let Some(svg) = values[0]["data"]["flame_graph_data"].as_str() {
+            return create_response(StatusCode::OK, "image/svg+xml", svg.to_string());
+        }
+    }
+    let body = if messages.len() == 1 { messages.into_iter().next().unwrap() } else { Value::Array(values).to_string() };
+    create_response(StatusCode::OK, "application/json", body)
+}
+
+fn create_api_error_response(status: StatusCode, cmd: &str, message: &str) -> Response<Body> {
+    let body = json!({"result": "failure", "cmd": cmd, "data": {"message": message}});
+    create_response(status, "application/json", body.to_string())
+}
+
+fn create_response(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    ResponseBuilder::new()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .expect("unable to build response")
+}
+
 pub struct SimpleHttpServer {
 
 }
 
 impl SimpleHttpServer {
-    pub fn start_server(){
+    pub fn start_server(profiler: Arc<Profiler>, addr: SocketAddr, static_dir: String){
 
-        let mut static_dir = "static/";
-        if let Ok(r) = std::fs::read_dir("res/static/") {
-            static_dir = "res/static/";
-        }
         println!("http static dir: {}", static_dir);
 
-        let addr = ([0, 0, 0, 0], 3890).into();
         match hyper::Server::try_bind(&addr) {
             Ok(builder) => {
                 let server = builder
-                    .serve(move || future::ok::<_, Error>(MainService::new(static_dir)))
+                    .serve(move || future::ok::<_, Error>(MainService::new(&static_dir, profiler.clone())))
                     .map_err(|e| eprintln!("server error: {}", e));
                 println!("Http server running on http://127.0.0.1:{}/", addr.port());
                 //println!("Simpleui: http://127.0.0.1:{}/simpleui/", addr.port());
@@ -90,6 +262,7 @@ impl SimpleHttpServer {
             },
             Err(e) => {
                 println!("Start flare web server failed, bind addr: {}, error: {}", addr, e);
+                profiler.shutdown();
             }
         }
 
@@ -106,4 +279,41 @@ impl SimpleHttpServer {
 //        .map_err(|e| eprintln!("server error: {}", e));
 //    eprintln!("Doc server running on http://{}/", addr);
 //    hyper::rt::run(server);
-//}
\ No newline at end of file
+//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("127.0.0.1%3A3333"), "127.0.0.1:3333");
+        assert_eq!(percent_decode("%E4%B8%AD"), "\u{4e2d}");
+        //invalid escapes are kept
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz1"), "%zz1");
+    }
+
+    #[test]
+    fn test_parse_api_options() {
+        let options = parse_api_options("session_id=12345&thread_ids=1,2&start_time=100&inverted=true&name=a%2Cb", b"").unwrap();
+        assert_eq!(options["session_id"], json!("12345"));
+        assert_eq!(options["thread_ids"], json!([1, 2]));
+        assert_eq!(options["start_time"], json!(100));
+        assert_eq!(options["inverted"], json!(true));
+        assert_eq!(options["name"], json!("a,b"));
+
+        let options = parse_api_options("thread_ids=%5B3%2C4%5D&end_time=abc", b"").unwrap();
+        assert_eq!(options["thread_ids"], json!([3, 4]));
+        assert_eq!(options["end_time"], json!("abc"));
+    }
+
+    #[test]
+    fn test_parse_api_options_with_body() {
+        let options = parse_api_options("top_n=5", br#"{"session_id": "s1", "top_n": 10}"#).unwrap();
+        assert_eq!(options["session_id"], json!("s1"));
+        //query params override body
+        assert_eq!(options["top_n"], json!(5));
+        assert!(parse_api_options("", b"[1]").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-//extern crate libc;
+extern crate libc;
 #[macro_use]
 extern crate lazy_static;
 extern crate time;
@@ -24,6 +24,7 @@ extern crate futures;
 extern crate http;
 extern crate hyper;
 extern crate hyper_staticfile;
+extern crate regex;
 
 
 //re-export
@@ -37,5 +38,17 @@ mod http_server;
 pub mod utils;
 pub mod sample_encoder;
 mod method_analysis;
+mod range_cache;
+mod transform;
+mod aggregation;
+mod subscription;
+mod request;
+pub mod segment;
+pub mod config;
+pub mod record;
+mod catalogue;
+mod archive;
+mod cluster;
+mod trigger;
 
 
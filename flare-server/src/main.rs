@@ -1,13 +1,20 @@
 extern crate flare_server;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
 
 use flare_server::sample::*;
 use flare_server::*;
+use flare_server::config::ServerConfig;
+use flare_server::record;
 use std::sync::{Mutex, Arc};
-use std::path::Path;
 
 fn main() {
 
-    init();
+    let config = match init() {
+        Some(config) => config,
+        None => return
+    };
 
 //    match SampleCollector::new("localhost:3333") {
 //        Ok(mut collector) => {
@@ -18,11 +25,11 @@ fn main() {
 //        }
 //    }
 
-    let mut profiler = Profiler::new();
+    let mut profiler = Profiler::with_config(config);
 //    profiler.lock().unwrap().connect_agent("localhost:3333");
 
     //start websocket server
-    profiler.lock().unwrap().startup();
+    profiler.startup();
 
 
 //    let timer = timer::Timer::new();
@@ -36,7 +43,7 @@ fn main() {
 
     //wait for closing
     loop {
-        if !profiler.lock().unwrap().is_running() {
+        if !profiler.is_running() {
             break;
         }
         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -45,18 +52,23 @@ fn main() {
 //    drop(guard);
 }
 
-fn init() {
-    if let Ok(exe_path) = std::env::current_exe() {
-        let dir = exe_path.parent().unwrap();
-        let path = dir.to_str().unwrap_or("");
-        if path.ends_with("bin") {
-            let new_path = &path[0..path.len()-4];
-            std::env::set_current_dir(Path::new(new_path));
-            println!("set_current_dir: {}", new_path);
-        }
+//parse config from config file and command line flags, exit if invalid
+fn init() -> Option<ServerConfig> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    //headless recording: flare_server record [options]
+    if args.first().map(|x| x.as_str()) == Some("record") {
+        let _ = env_logger::try_init();
+        std::process::exit(record::run_record_command(&args[1..]));
     }
-
-    //TEST
-    //std::env::set_current_dir(Path::new("D:\\projects\\arch\\flare-profiler\\target\\flare-profiler"));
-
+    let config = match ServerConfig::from_args(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => return None,
+        Err(e) => {
+            eprintln!("invalid flare-server config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    env_logger::Builder::new().parse_filters(&config.log_level).init();
+    info!("flare-server config: {:?}", config);
+    Some(config)
 }
\ No newline at end of file
@@ -1,11 +1,11 @@
 
 use super::sample::*;
 use std::{io, thread};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use websocket::sync::Server;
 use websocket::OwnedMessage;
 use websocket::sync::sender::Sender;
-use websocket::sender::Writer;
 use websocket::server::upgrade::WsUpgrade;
 use websocket::server::upgrade::sync::Buffer;
 use serde::Serialize;
@@ -19,50 +19,147 @@ use flare_utils::stopwatch::Stopwatch;
 use tree::TreeNode;
 use inferno::flamegraph::*;
 use inferno::flamegraph;
+use inferno::differential;
 use std::str::FromStr;
 use inferno::flamegraph::color::BackgroundColor;
 use ::{tree, utils};
 use inferno::flamegraph::merge::{TimedFrame, Frame};
 use super::http_server::*;
 use method_analysis::*;
+use range_cache::*;
+use catalogue::*;
+use archive::*;
+use segment::load_summary_info;
+use transform::TransformPipeline;
+use aggregation::AggregationLevel;
+use subscription::*;
+use request::*;
+use cluster::*;
+use trigger::*;
+use std::collections::HashSet;
+use config::ServerConfig;
+use segment;
 
 type JsonValue = serde_json::Value;
 
 pub const FLARE_SAMPLES_DIR : &str = "flare-samples";
+pub const FLARE_EXPORTS_DIR : &str = "flare-exports";
+
+//session, threads and time range of one side of a differential flame graph
+pub struct FlameGraphSelection {
+    pub session_id: String,
+    pub thread_ids: Vec<i64>,
+    pub start_time: i64,
+    pub end_time: i64,
+}
+
+//reverse frames of collapsed stack line "a;b;c 10" to "c;b;a 10"
+fn reverse_collapsed_stack(line: &str) -> String {
+    match line.rfind(' ') {
+        Some(pos) => {
+            let mut frames: Vec<&str> = line[..pos].split(';').collect();
+            frames.reverse();
+            format!("{}{}", frames.join(";"), &line[pos..])
+        },
+        None => line.to_string()
+    }
+}
+
+//top-down flame graph svg of collapsed stack lines
+fn render_collapsed_stacks_svg<'a, I>(lines: I, count_name: &str, image_width: usize, title: Option<&str>) -> io::Result<String>
+    where I: IntoIterator<Item=&'a str> {
+    let mut options = flamegraph::Options {
+        direction: Direction::Inverted,
+        no_sort: false,
+        image_width: Some(image_width),
+        count_name: count_name.to_string(),
+        ..Default::default()
+    };
+    if let Some(title) = title {
+        options.title = title.to_string();
+    }
+    let mut writer = vec![];
+    if let Err(e) = flamegraph::from_lines(&mut options, lines, &mut writer) {
+        return Err(new_error(ErrorKind::Other, &format!("create flame graph failed: {}", e)));
+    }
+    match String::from_utf8(writer) {
+        Ok(svg) => Ok(svg),
+        Err(e) => Err(new_error(ErrorKind::Other, &format!("flame graph to string failed: {}", e)))
+    }
+}
 
 #[derive(Clone, Serialize)]
 pub struct FlareResponse<T: ?Sized> {
     pub result: String,
     pub cmd: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<JsonValue>,
     pub data: Box<T>
 }
 
+/// Requests of ws clients are handled concurrently, each state has its own lock which is held briefly.
+/// Analysis queries run on readonly snapshots of sessions, see get_sample_reader().
 pub struct Profiler {
-    self_ref: Option<Arc<Mutex<Profiler>>>,
-    bind_addr: String,
-    running: bool,
-    sample_session_map: HashMap<String, Arc<Mutex<SampleCollector>>>
+    self_ref: Mutex<Option<Arc<Profiler>>>,
+    config: ServerConfig,
+    running: AtomicBool,
+    sample_session_map: RwLock<HashMap<String, Arc<Mutex<SampleCollector>>>>,
+    //agents being connected, reserved before connecting outside of the session map lock
+    connecting_agents: Mutex<HashSet<String>>,
+    //cpu time ts, collapsed stacks and built trees of sessions
+    range_cache: Mutex<RangeCache>,
+    catalogue: Mutex<RecordingCatalogue>,
+    //live push subscriptions of ws clients
+    subscriptions: Mutex<SubscriptionManager>,
+    //in-flight requests with request id
+    requests: Mutex<RequestRegistry>,
+    clusters: Mutex<ClusterRegistry>,
 }
 
 impl Profiler {
-    pub fn new() -> Arc<Mutex<Profiler>> {
-        let mut inst = Arc::new(Mutex::new(Profiler {
-            self_ref: None,
-            bind_addr: "0.0.0.0:3891".to_string(),
-            running: true,
-            sample_session_map: HashMap::new(),
-        }));
-        inst.lock().unwrap().self_ref = Some(inst.clone());
-        inst.lock().unwrap().init();
+    pub fn new() -> Arc<Profiler> {
+        Profiler::with_config(ServerConfig::default())
+    }
+
+    /// Create profiler with validated config, the global settings (roll period, tree memory, samples dir) are applied.
+    pub fn with_config(config: ServerConfig) -> Arc<Profiler> {
+        segment::set_roll_period(config.get_roll_period_ms());
+        set_max_tree_memory(config.get_tree_memory_bytes());
+        set_samples_dir(&config.data_dir);
+        set_reconnect_policy(config.get_reconnect_policy());
+        let mut catalogue = RecordingCatalogue::new(&config.data_dir);
+        catalogue.set_retention_policy(RetentionPolicy {
+            max_age_ms: config.get_retention_max_age_ms(),
+            max_total_bytes: config.get_retention_max_total_bytes(),
+        });
+        let mut inst = Arc::new(Profiler {
+            self_ref: Mutex::new(None),
+            running: AtomicBool::new(true),
+            sample_session_map: RwLock::new(HashMap::new()),
+            connecting_agents: Mutex::new(HashSet::new()),
+            range_cache: Mutex::new(RangeCache::new(config.get_range_cache_bytes())),
+            catalogue: Mutex::new(catalogue),
+            config,
+            subscriptions: Mutex::new(SubscriptionManager::new()),
+            requests: Mutex::new(RequestRegistry::new()),
+            clusters: Mutex::new(ClusterRegistry::new()),
+        });
+        *inst.self_ref.lock().unwrap() = Some(inst.clone());
+        inst.init();
         inst
     }
 
-    pub fn init(&mut self) {
-        match std::fs::read_dir(FLARE_SAMPLES_DIR) {
+    fn get_self_ref(&self) -> Arc<Profiler> {
+        self.self_ref.lock().unwrap().as_ref().unwrap().clone()
+    }
+
+    pub fn init(&self) {
+        let samples_dir = &self.config.data_dir;
+        match std::fs::read_dir(samples_dir) {
             Err(e) => {
-                match std::fs::create_dir(FLARE_SAMPLES_DIR) {
+                match std::fs::create_dir_all(samples_dir) {
                     Err(e) => {
-                        println!("create dir failed: {}, error: {:?}", FLARE_SAMPLES_DIR, e);
+                        error!("create dir failed: {}, error: {:?}", samples_dir, e);
                     }
                     _ => {}
                 }
@@ -71,53 +168,237 @@ impl Profiler {
         }
     }
 
-    pub fn connect_agent(&mut self, agent_addr: &str) -> io::Result<String> {
+    /// Connect to agent and record samples, `replay` asks the agent to send the samples of its flight recorder first.
+    pub fn connect_agent(&self, agent_addr: &str, replay: bool) -> io::Result<String> {
+        self.connect_agent_session(agent_addr, replay).map(|(instance_id, _)| instance_id)
+    }
+
+    //return the session id and whether the session is created by this call
+    fn connect_agent_session(&self, agent_addr: &str, replay: bool) -> io::Result<(String, bool)> {
         println!("connecting to agent: {}", agent_addr);
         let instance_id = agent_addr.to_string();
-        let value = self.sample_session_map.get(&instance_id);
-        if value.is_some() {
+        if self.sample_session_map.read().unwrap().contains_key(&instance_id) {
             println!("already connected to agent: {}", agent_addr);
-            return Ok(instance_id);
+            return Ok((instance_id, false));
+        }
+        //reserve the agent to avoid connecting it twice, the session map is not locked while connecting
+        if !self.connecting_agents.lock().unwrap().insert(instance_id.clone()) {
+            return Err(new_error(ErrorKind::AlreadyExists, &format!("agent is connecting: {}", agent_addr)));
+        }
+        //connected by another request after the first check
+        if self.sample_session_map.read().unwrap().contains_key(&instance_id) {
+            self.connecting_agents.lock().unwrap().remove(&instance_id);
+            return Ok((instance_id, false));
         }
+        let result = SampleCollector::new(agent_addr).and_then(|collector| {
+            collector.lock().unwrap().subscribe_events(replay)?;
+            Ok(collector)
+        });
+        //the session is inserted before releasing the reservation
+        if let Ok(collector) = &result {
+            println!("connect agent: {} successful", agent_addr);
+            self.sample_session_map.write().unwrap().insert(instance_id.clone(), collector.clone());
+        }
+        self.connecting_agents.lock().unwrap().remove(&instance_id);
+        result.map(|_| (instance_id, true))
+    }
 
-        let mut collector = SampleCollector::new(agent_addr)?;
-        collector.lock().unwrap().subscribe_events()?;
-        println!("connect agent: {} successful", agent_addr);
-        self.sample_session_map.insert(instance_id.clone(), collector);
-        Ok(instance_id)
+    pub fn open_sample(&self, sample_data_dir: &str) -> io::Result<String> {
+        self.open_sample_session(sample_data_dir).map(|(instance_id, _)| instance_id)
     }
 
-    pub fn open_sample(&mut self, sample_data_dir: &str) -> io::Result<String> {
+    //return the session id and whether the session is created by this call
+    fn open_sample_session(&self, sample_data_dir: &str) -> io::Result<(String, bool)> {
         println!("open sample {} ..", sample_data_dir);
+        if sample_data_dir.ends_with(ARCHIVE_EXTENSION) {
+            let sample_data_dir = self.import_archive(sample_data_dir)?;
+            return self.open_sample_session(&sample_data_dir);
+        }
         let instance_id = sample_data_dir.to_string();
         if let Ok(value) = self.get_sample_collector(&instance_id) {
-            return Ok(instance_id);
+            return Ok((instance_id, false));
         }
 
-        let mut collector = SampleCollector::open(sample_data_dir)?;
-        self.sample_session_map.insert(instance_id.clone(), collector);
-        Ok(instance_id)
+        let recording_dirs = self.get_recording_dirs(sample_data_dir)?;
+        let mut collector = SampleCollector::open(sample_data_dir, &recording_dirs)?;
+        let mut session_map = self.sample_session_map.write().unwrap();
+        //opened by another request meanwhile
+        if session_map.contains_key(&instance_id) {
+            return Ok((instance_id, false));
+        }
+        session_map.insert(instance_id.clone(), collector);
+        Ok((instance_id, true))
     }
 
-    pub fn close_session(&mut self, session_id: &str) -> io::Result<()> {
-        if let Some(collector) = self.sample_session_map.remove(session_id) {
+    //rolled dirs of the recording in catalogue, dirs out of data dir are found by scanning their parent dir
+    fn get_recording_dirs(&self, sample_data_dir: &str) -> io::Result<Vec<String>> {
+        let recording_id = match load_summary_info(sample_data_dir) {
+            Ok(summary) => summary.sample_info.recording_id,
+            //reported by loading the sample dir
+            Err(_) => return Ok(vec![])
+        };
+        if recording_id.is_empty() {
+            return Ok(vec![]);
+        }
+        if let Some(dirs) = self.catalogue.lock().unwrap().get_recording_dirs(&recording_id) {
+            return Ok(dirs);
+        }
+        let parent_dir = match std::path::Path::new(sample_data_dir).parent().and_then(|x| x.to_str()) {
+            Some("") => ".",
+            Some(dir) => dir,
+            None => return Ok(vec![])
+        };
+        let scanned = scan_recordings(parent_dir)?;
+        Ok(scanned.get(&recording_id).map(|recording| recording.sample_dirs.clone()).unwrap_or_default())
+    }
+
+    /// Export samples of session in time range to a .flare archive file, return the archive path.
+    pub fn export_archive(&self, session_id: &str, start_time: i64, end_time: i64, privacy: PrivacyOptions) -> io::Result<String> {
+        let mut reader = self.get_sample_reader(session_id)?;
+        let sample_info = reader.get_sample_info();
+        let exports_dir = &self.config.exports_dir;
+        std::fs::create_dir_all(exports_dir)?;
+        let now_time = Local::now().format("%Y%m%dT%H%M%S").to_string();
+        let name = format!("{}-{}", sample_info.agent_addr.replace(":", "_"), now_time);
+        let export_dir = format!("{}/{}", exports_dir, name);
+        let archive_path = format!("{}/{}{}", exports_dir, name, ARCHIVE_EXTENSION);
+
+        let result = reader.export_sample_dir(&export_dir, start_time, end_time, &privacy)
+            .and_then(|summary| {
+                let manifest = ArchiveManifest {
+                    version: ARCHIVE_VERSION,
+                    created_time: 0,
+                    recording_id: summary.sample_info.recording_id.clone(),
+                    agent_addr: summary.sample_info.agent_addr.clone(),
+                    start_time: summary.sample_info.record_start_time,
+                    end_time: summary.sample_info.last_record_time,
+                    privacy,
+                    entries: vec![],
+                };
+                pack_sample_dir(&export_dir, &archive_path, manifest)
+            });
+        if let Err(e) = std::fs::remove_dir_all(&export_dir) {
+            println!("remove export temp dir failed: {}, err: {}", export_dir, e);
+        }
+        result?;
+        Ok(archive_path)
+    }
+
+    /// Extract .flare archive to a new sample dir, return the sample dir.
+    pub fn import_archive(&self, archive_path: &str) -> io::Result<String> {
+        self.check_archive_path(archive_path)?;
+        let manifest = read_manifest(archive_path)?;
+        let mut name = manifest.recording_id.clone();
+        if name.is_empty() || name.contains("..") || name.contains('/') || name.contains('\\') {
+            name = format!("imported-{}", Local::now().format("%Y%m%dT%H%M%S"));
+        }
+        let mut sample_data_dir = format!("{}/{}", self.config.data_dir, name);
+        let mut n = 1;
+        while std::path::Path::new(&sample_data_dir).exists() {
+            sample_data_dir = format!("{}/{}-{}", self.config.data_dir, name, n);
+            n += 1;
+        }
+        if let Err(e) = unpack_archive(archive_path, &sample_data_dir) {
+            if let Err(e) = std::fs::remove_dir_all(&sample_data_dir) {
+                println!("remove unpacked dir failed: {}, err: {}", sample_data_dir, e);
+            }
+            return Err(e);
+        }
+
+        //imported dir is a standalone recording
+        let mut summary = load_summary_info(&sample_data_dir)?;
+        summary.sample_info.sample_data_dir = sample_data_dir.clone();
+        summary.sample_info.recording_id = std::path::Path::new(&sample_data_dir).file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+        std::fs::write(format!("{}/summary_info.json", sample_data_dir), serde_json::to_string_pretty(&summary)?)?;
+        Ok(sample_data_dir)
+    }
+
+    //only archives in the exports dir or data dir can be imported by clients
+    fn check_archive_path(&self, archive_path: &str) -> io::Result<()> {
+        if !archive_path.ends_with(ARCHIVE_EXTENSION) {
+            return Err(new_invalid_input_error(&format!("not a {} archive file: {}", ARCHIVE_EXTENSION, archive_path)));
+        }
+        let path = std::fs::canonicalize(archive_path)?;
+        for dir in &[&self.config.exports_dir, &self.config.data_dir] {
+            if let Ok(dir) = std::fs::canonicalize(dir) {
+                if path.starts_with(&dir) {
+                    return Ok(());
+                }
+            }
+        }
+        Err(new_error(ErrorKind::PermissionDenied, &format!("archive is not in exports dir or data dir: {}", archive_path)))
+    }
+
+    pub fn close_session(&self, session_id: &str) -> io::Result<()> {
+        let collector = self.sample_session_map.write().unwrap().remove(session_id);
+        if let Some(collector) = collector {
             println!("close session: {}", session_id);
             collector.lock().unwrap().close();
         }
+        self.range_cache.lock().unwrap().remove_session(session_id);
 
         Ok(())
     }
 
-    pub fn close_all_session(&mut self) -> io::Result<()> {
-        let session_ids = self.sample_session_map.keys().map(|x|{ x.to_string() }).collect::<Vec<String>>();
+    pub fn close_all_session(&self) -> io::Result<()> {
+        self.clusters.lock().unwrap().clear();
+        let session_ids = self.sample_session_map.read().unwrap().keys().map(|x|{ x.to_string() }).collect::<Vec<String>>();
         for session_id in &session_ids {
             self.close_session(session_id);
         }
         Ok(())
     }
 
-    fn get_sample_collector(&mut self, session_id: &str) -> io::Result<Arc<Mutex<SampleCollector>>> {
-        let collector = if let Some(_collector) = self.sample_session_map.get(session_id) {
+    pub fn set_retention_policy(&self, max_age_ms: i64, max_total_bytes: u64) {
+        self.catalogue.lock().unwrap().set_retention_policy(RetentionPolicy { max_age_ms, max_total_bytes });
+    }
+
+    //recording ids of opened sessions
+    fn get_open_recording_ids(&self, session_type: Option<&str>) -> HashSet<String> {
+        let mut recording_ids = HashSet::new();
+        for collector in self.sample_session_map.read().unwrap().values() {
+            let collector = collector.lock().unwrap();
+            if let Some(session_type) = session_type {
+                if collector.get_sample_type() != session_type {
+                    continue;
+                }
+            }
+            let sample_info = collector.get_sample_info();
+            if !sample_info.recording_id.is_empty() {
+                recording_ids.insert(sample_info.recording_id);
+            } else if let Some(name) = std::path::Path::new(&sample_info.sample_data_dir).file_name().and_then(|x| x.to_str()) {
+                recording_ids.insert(name.to_string());
+            }
+        }
+        recording_ids
+    }
+
+    pub fn delete_recording(&self, recording_id: &str) -> io::Result<RecordingInfo> {
+        if self.get_open_recording_ids(Some("attach")).contains(recording_id) {
+            return Err(new_invalid_input_error("can not delete recording of a live session"));
+        }
+        //close opened sessions of the recording
+        let sample_dirs = self.catalogue.lock().unwrap().get_recording(recording_id)?.sample_dirs.clone();
+        for dir in &sample_dirs {
+            self.close_session(dir)?;
+        }
+        self.catalogue.lock().unwrap().delete(recording_id)
+    }
+
+    //prune recordings by retention policy
+    pub fn prune_recordings(&self) -> io::Result<Vec<String>> {
+        let policy = self.catalogue.lock().unwrap().get_retention_policy();
+        if policy.max_age_ms <= 0 && policy.max_total_bytes == 0 {
+            return Ok(vec![]);
+        }
+        let protected_ids = self.get_open_recording_ids(None);
+        //scanning sample dirs is slow, do not block other catalogue requests
+        let scanned = scan_recordings(&self.config.data_dir)?;
+        self.catalogue.lock().unwrap().apply_retention(scanned, &protected_ids)
+    }
+
+    fn get_sample_collector(&self, session_id: &str) -> io::Result<Arc<Mutex<SampleCollector>>> {
+        let collector = if let Some(_collector) = self.sample_session_map.read().unwrap().get(session_id) {
             Some(_collector.clone())
         }else {
             None
@@ -126,7 +407,7 @@ impl Profiler {
         if let Some(_collector) = collector {
             if _collector.lock().unwrap().is_disconnected() {
                 println!("sample session is disconnected: {}, removing it", session_id);
-                self.sample_session_map.remove(session_id);
+                self.sample_session_map.write().unwrap().remove(session_id);
                 Err(io::Error::new(ErrorKind::NotFound, "sample session is disconnected"))
             }else {
                 Ok(_collector)
@@ -136,13 +417,200 @@ impl Profiler {
         }
     }
 
-    pub fn get_dashboard(&mut self, session_id: &str) -> io::Result<DashboardInfo> {
+    //readonly snapshot of the session, the collector lock is released before running the query
+    fn get_sample_reader(&self, session_id: &str) -> io::Result<SampleCollector> {
+        let collector = self.get_sample_collector(session_id)?;
+        let reader = collector.lock().unwrap().create_reader();
+        Ok(reader)
+    }
+
+    /// Connect agents or open recordings of the instances as one cluster session, return the cluster id.
+    /// Instances failed to open are skipped, at least one instance is required.
+    pub fn open_cluster(&self, name: &str, agent_addrs: &[String], sample_dirs: &[String]) -> io::Result<String> {
+        if name.is_empty() || agent_addrs.is_empty() && sample_dirs.is_empty() {
+            return Err(new_invalid_input_error("cluster name and instances are required"));
+        }
+        let cluster_id = get_cluster_id(name);
+        //reserve the id, instances are opened without holding the lock
+        if !self.clusters.lock().unwrap().reserve(&cluster_id) {
+            return Err(new_invalid_input_error(&format!("cluster is already opened: {}", name)));
+        }
+        let mut members = vec![];
+        for agent_addr in agent_addrs {
+            match self.connect_agent_session(agent_addr, false) {
+                Ok((session_id, created)) => members.push(ClusterMember { instance: agent_addr.clone(), session_id, created }),
+                Err(e) => println!("connect cluster instance failed: {}, err: {}", agent_addr, e)
+            }
+        }
+        for sample_dir in sample_dirs {
+            match self.open_sample_session(sample_dir) {
+                Ok((session_id, created)) => members.push(ClusterMember { instance: sample_dir.clone(), session_id, created }),
+                Err(e) => println!("open cluster instance failed: {}, err: {}", sample_dir, e)
+            }
+        }
+        if members.is_empty() {
+            self.clusters.lock().unwrap().release(&cluster_id);
+            return Err(new_error(ErrorKind::NotConnected, "open all instances of cluster failed"));
+        }
+        println!("open cluster: {}, instances: {}/{}", cluster_id, members.len(), agent_addrs.len() + sample_dirs.len());
+        self.clusters.lock().unwrap().add(ClusterSession {
+            cluster_id: cluster_id.clone(),
+            name: name.to_string(),
+            members,
+        });
+        Ok(cluster_id)
+    }
+
+    pub fn close_cluster(&self, cluster_id: &str) -> io::Result<()> {
+        let cluster = self.clusters.lock().unwrap().remove(cluster_id);
+        match cluster {
+            Some(cluster) => {
+                //sessions opened before the cluster are kept
+                for member in cluster.members.iter().filter(|x| x.created) {
+                    self.close_session(&member.session_id)?;
+                }
+                println!("close cluster: {}", cluster_id);
+                Ok(())
+            },
+            None => Err(new_error(ErrorKind::NotFound, "cluster session not found"))
+        }
+    }
+
+    pub fn get_cluster(&self, cluster_id: &str) -> io::Result<ClusterSession> {
+        match self.clusters.lock().unwrap().get(cluster_id) {
+            Some(cluster) => Ok(cluster.clone()),
+            None => Err(new_error(ErrorKind::NotFound, "cluster session not found"))
+        }
+    }
+
+    //threads of each member selected by thread set options, default is all threads
+    fn select_cluster_thread_ids(&self, cluster: &ClusterSession, options: &serde_json::Map<String, serde_json::Value>) -> Vec<(ClusterMember, Vec<i64>)> {
+        let mut result = vec![];
+        for member in &cluster.members {
+            let thread_ids = match self.select_thread_ids(&member.session_id, options) {
+                Ok(Some(thread_ids)) => Ok(thread_ids),
+                Ok(None) => self.get_all_thread_ids(&member.session_id),
+                Err(e) => Err(e)
+            };
+            match thread_ids {
+                Ok(thread_ids) => result.push((member.clone(), thread_ids)),
+                Err(e) => println!("select threads of cluster instance failed: {}, err: {}", member.instance, e)
+            }
+        }
+        result
+    }
+
+    /// Flame graph of all instances, stacks are merged by method names, optionally put each instance under its own root frame.
+    pub fn create_cluster_flame_graph_svg(&self, cluster_id: &str, options: &serde_json::Map<String, serde_json::Value>, start_time: i64, end_time: i64, stats_type_str: &str,
+                                          image_width: usize, per_instance_root: bool, inverted: bool, aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<String> {
+        let count_name = match StatsType::from_str(stats_type_str) {
+            Ok(stats_type) => stats_type.count_name(),
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let cluster = self.get_cluster(cluster_id)?;
+        let mut instance_stacks = vec![];
+        for (member, thread_ids) in self.select_cluster_thread_ids(&cluster, options) {
+            check_cancelled()?;
+            let stacks = self.get_threads_collapsed_call_stacks(&member.session_id, &thread_ids, start_time, end_time, stats_type_str, false, aggregation, transforms)?;
+            instance_stacks.push((member.instance, stacks));
+        }
+        let lines = merge_collapsed_stacks(&instance_stacks, per_instance_root);
+        if lines.is_empty() {
+            return Err(new_error(ErrorKind::NotFound, "no samples of the cluster in time range"));
+        }
+        self.create_collapsed_stacks_svg(lines, count_name, image_width, inverted)
+    }
+
+    /// Hot methods of all instances merged by method name, each method has stats of instances for drilling down.
+    pub fn get_cluster_hot_methods(&self, cluster_id: &str, options: &serde_json::Map<String, serde_json::Value>, start_time: i64, end_time: i64, stats_type_str: &str,
+                                   sort_by: &str, offset: usize, top_n: usize) -> io::Result<JsonValue> {
+        let stats_type = match StatsType::from_str(stats_type_str) {
+            Ok(x) => x,
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let sort_by_self = match sort_by {
+            "self" => true,
+            "total" => false,
+            _ => return Err(new_invalid_input_error(&format!("invalid sort_by: {}", sort_by)))
+        };
+        let cluster = self.get_cluster(cluster_id)?;
+        let mut builder = ClusterProfileBuilder::new();
+        for (member, thread_ids) in self.select_cluster_thread_ids(&cluster, options) {
+            check_cancelled()?;
+            let mut reader = self.get_sample_reader(&member.session_id)?;
+            let profile = reader.get_flat_profile(&thread_ids, start_time, end_time)?;
+            builder.add_instance_total(&profile.total_stats);
+            for (method_id, method) in &profile.methods {
+                builder.add_method(reader.get_method_name(*method_id), InstanceMethodStats {
+                    instance: member.instance.clone(),
+                    session_id: member.session_id.clone(),
+                    self_stats: method.self_stats.clone(),
+                    total_stats: method.total_stats.clone(),
+                });
+            }
+        }
+
+        let mut methods = vec![];
+        for (name, instances) in builder.methods {
+            let mut self_stats = tree::MethodStats::default();
+            let mut total_stats = tree::MethodStats::default();
+            for instance in &instances {
+                self_stats.merge(&instance.self_stats);
+                total_stats.merge(&instance.total_stats);
+            }
+            methods.push((name, self_stats, total_stats, instances));
+        }
+        methods.sort_by(|a, b| {
+            let (x, y) = if sort_by_self { (&a.1, &b.1) } else { (&a.2, &b.2) };
+            y.get_stats_value(&stats_type).cmp(&x.get_stats_value(&stats_type)).then_with(|| a.0.cmp(&b.0))
+        });
+        let total = &builder.total_stats;
+        let percent = |value: i64, total: i64| if total > 0 { value as f64 * 100.0 / total as f64 } else { 0.0 };
+        let mut hot_methods = vec![];
+        for (name, self_stats, total_stats, instances) in methods.iter().skip(offset).take(top_n) {
+            hot_methods.push(json!({
+                "name": name,
+                "self_duration": self_stats.duration,
+                "total_duration": total_stats.duration,
+                "self_cpu": self_stats.cpu,
+                "total_cpu": total_stats.cpu,
+                "self_samples": self_stats.samples,
+                "total_samples": total_stats.samples,
+                "self_percent": percent(self_stats.get_stats_value(&stats_type), total.get_stats_value(&stats_type)),
+                "total_percent": percent(total_stats.get_stats_value(&stats_type), total.get_stats_value(&stats_type)),
+                "instances": instances,
+            }));
+        }
+        Ok(json!({
+            "cluster_id": cluster_id,
+            "members": cluster.members,
+            "start_time": start_time,
+            "end_time": end_time,
+            "stats_type": stats_type_str,
+            "sort_by": sort_by,
+            "total": total,
+            "methods": hot_methods,
+            "offset": offset,
+            "total_methods": methods.len(),
+            "more": offset + hot_methods.len() < methods.len()
+        }))
+    }
+
+    //disconnected or closed session
+    pub fn is_session_disconnected(&self, session_id: &str) -> bool {
+        match self.sample_session_map.read().unwrap().get(session_id) {
+            Some(collector) => collector.lock().unwrap().is_disconnected(),
+            None => true
+        }
+    }
+
+    pub fn get_dashboard(&self, session_id: &str) -> io::Result<DashboardInfo> {
         let collector = self.get_sample_collector(session_id)?;
         let data = collector.lock().unwrap().get_dashboard();
         Ok(data)
     }
 
-    pub fn get_all_thread_ids(&mut self, session_id: &str) -> io::Result<Vec<i64>> {
+    pub fn get_all_thread_ids(&self, session_id: &str) -> io::Result<Vec<i64>> {
         let collector = self.get_sample_collector(session_id)?;
         let dashboard = collector.lock().unwrap().get_dashboard();
         let mut thread_ids = vec![];
@@ -152,9 +620,15 @@ impl Profiler {
         Ok(thread_ids)
     }
 
-    pub fn get_thread_cpu_times(&mut self, session_id: &str, thread_ids: &[i64], mut start_time: i64, mut end_time: i64, mut unit_time_ms: i64, graph_width: i64) -> io::Result<Vec<Value>> {
-        if let Some(collector) = self.sample_session_map.get(session_id) {
-            let sample_info = collector.lock().unwrap().get_sample_info();
+    pub fn get_thread_cpu_times(&self, session_id: &str, thread_ids: &[i64], mut start_time: i64, mut end_time: i64, mut unit_time_ms: i64, graph_width: i64) -> io::Result<Vec<Value>> {
+        let collector = self.sample_session_map.read().unwrap().get(session_id).cloned();
+        if let Some(collector) = collector {
+            let (sample_info, data_version) = {
+                let collector = collector.lock().unwrap();
+                (collector.get_sample_info(), collector.get_data_version())
+            };
+            //the snapshot is created on cache miss
+            let mut reader = None;
             //限制时间范围
             if start_time < 0 {
                 start_time = sample_info.record_start_time;
@@ -184,8 +658,21 @@ impl Profiler {
 
 
             let mut thread_cpu_times = vec![];
+            let stats_type = format!("cpu_time_{}", unit_time_ms);
             for thread_id in thread_ids {
-                let ts_result = collector.lock().unwrap().get_thread_cpu_time(thread_id, start_time, end_time, unit_time_ms);
+                let key = RangeCacheKey::new(session_id, "thread_cpu_time", &[*thread_id], start_time, end_time, &stats_type);
+                let cached_value = self.range_cache.lock().unwrap().get(&key, sample_info.record_start_time, sample_info.last_record_time, data_version);
+                let ts_result = match cached_value {
+                    Some(RangeCacheValue::ThreadCpuTime(ts_result)) => Some(ts_result),
+                    _ => {
+                        let ts_result = reader.get_or_insert_with(|| collector.lock().unwrap().create_reader())
+                            .get_thread_cpu_time(thread_id, start_time, end_time, unit_time_ms);
+                        if let Some(ts_result) = &ts_result {
+                            self.range_cache.lock().unwrap().put(key, RangeCacheValue::ThreadCpuTime(ts_result.clone()), sample_info.record_start_time, sample_info.last_record_time, data_version);
+                        }
+                        ts_result
+                    }
+                };
                 if let Some(ts_result) = ts_result {
                     let ts_data = ts_result.data.as_int64();
                     thread_cpu_times.push(json!({
@@ -205,28 +692,24 @@ impl Profiler {
         }
     }
 
-    pub fn get_call_tree(&mut self, session_id: &str, thread_ids: &[i64], start_time: i64, end_time: i64) -> io::Result<TreeNode> {
-        //xxx
-        let collector = self.get_sample_collector(session_id)?;
-        let call_tree = collector.lock().unwrap().get_call_tree(thread_ids, start_time, end_time)?;
+    pub fn get_call_tree(&self, session_id: &str, thread_ids: &[i64], start_time: i64, end_time: i64, aggregation: AggregationLevel) -> io::Result<TreeNode> {
+        let call_tree = self.get_sample_reader(session_id)?.get_call_tree(thread_ids, start_time, end_time)?;
 
         //convert to json
-        Ok(call_tree.to_tree())
+        let mut tree = call_tree.to_tree();
+        aggregation.aggregate_tree(&mut tree, false);
+        Ok(tree)
     }
 
-    pub fn create_flame_graph_svg(&mut self, session_id: &str, thread_id: i64, start_time: &mut i64, end_time: &mut i64, stats_type_str: &str, image_width: usize) -> io::Result<String> {
+    pub fn create_flame_graph_svg(&self, session_id: &str, thread_id: i64, start_time: &mut i64, end_time: &mut i64, stats_type_str: &str, image_width: usize, aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<String> {
         let mut stats_type = StatsType::DURATION;
         if let Ok(x) = StatsType::from_str(stats_type_str) {
             stats_type = x;
         }else {
             return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)));
         }
-        let count_name = match stats_type {
-            StatsType::DURATION => "ms",
-            StatsType::CPU_TIME => "micros",
-            StatsType::SAMPLES => "samples",
-        };
-        let collector = self.get_sample_collector(session_id)?;
+        let count_name = stats_type.count_name();
+        let mut reader = self.get_sample_reader(session_id)?;
         //create frame graph
         let mut options = flamegraph::Options {
             //colors: Palette::from_str("java").unwrap(),
@@ -247,7 +730,9 @@ impl Profiler {
 //            return Err(new_error(ErrorKind::Other, &format!("create flame graph failed: {}", e)));
 //        }
 
-        let stack_tree = collector.lock().unwrap().get_sequenced_call_tree(thread_id, start_time, end_time, true)?;
+        let mut stack_tree = reader.get_sequenced_call_tree(thread_id, start_time, end_time, true)?;
+        aggregation.aggregate_tree(&mut stack_tree, true);
+        transforms.apply_tree(&mut stack_tree, true);
         let mut frames = vec![];
         let mut time = stack_tree.duration as usize;
         let mut delta_max = 0;
@@ -263,6 +748,111 @@ impl Profiler {
         }
     }
 
+    /// Merge collapsed stacks of threads into one flame graph, optionally put each thread under its own root frame.
+    /// Inverted (bottom-up) flame graph puts leaf methods at the root, expanding to callers.
+    pub fn create_merged_flame_graph_svg(&self, session_id: &str, thread_ids: &[i64], start_time: i64, end_time: i64, stats_type_str: &str, image_width: usize, per_thread_root: bool, inverted: bool,
+                                         aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<String> {
+        let count_name = match StatsType::from_str(stats_type_str) {
+            Ok(stats_type) => stats_type.count_name(),
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let lines = self.get_threads_collapsed_call_stacks(session_id, thread_ids, start_time, end_time, stats_type_str, per_thread_root, aggregation, transforms)?;
+        if lines.is_empty() {
+            return Err(new_error(ErrorKind::NotFound, "no samples of the threads in time range"));
+        }
+        self.create_collapsed_stacks_svg(lines, count_name, image_width, inverted)
+    }
+
+    fn create_collapsed_stacks_svg(&self, mut lines: Vec<String>, count_name: &str, image_width: usize, inverted: bool) -> io::Result<String> {
+        if inverted {
+            lines = lines.iter().map(|line| reverse_collapsed_stack(line)).collect();
+        }
+
+        render_collapsed_stacks_svg(lines.iter().map(|x| x.as_str()), count_name, image_width, None)
+    }
+
+    /// Compare collapsed stacks of two selections, red frames grow and blue frames shrink in the after selection.
+    pub fn create_diff_flame_graph_svg(&self, before: &FlameGraphSelection, after: &FlameGraphSelection, stats_type_str: &str, image_width: usize, normalize: bool,
+                                       aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<String> {
+        let count_name = match StatsType::from_str(stats_type_str) {
+            Ok(stats_type) => stats_type.count_name(),
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let before_lines = self.get_threads_collapsed_call_stacks(&before.session_id, &before.thread_ids, before.start_time, before.end_time, stats_type_str, false, aggregation, transforms)?;
+        let after_lines = self.get_threads_collapsed_call_stacks(&after.session_id, &after.thread_ids, after.start_time, after.end_time, stats_type_str, false, aggregation, transforms)?;
+        if before_lines.is_empty() || after_lines.is_empty() {
+            return Err(new_error(ErrorKind::NotFound, "no samples of the threads in time range"));
+        }
+
+        let mut folded = vec![];
+        let diff_options = differential::Options {
+            normalize,
+            strip_hex: false,
+        };
+        differential::from_readers(diff_options, before_lines.join("\n").as_bytes(), after_lines.join("\n").as_bytes(), &mut folded)?;
+        let folded = String::from_utf8_lossy(&folded).to_string();
+        render_collapsed_stacks_svg(folded.lines(), count_name, image_width, Some("Differential Flame Graph"))
+    }
+
+    //collapsed stacks of threads, optionally put each thread under its own root frame
+    fn get_threads_collapsed_call_stacks(&self, session_id: &str, thread_ids: &[i64], start_time: i64, end_time: i64, stats_type_str: &str, per_thread_root: bool,
+                                         aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<Vec<String>> {
+        let collector = self.get_sample_collector(session_id)?;
+        let thread_names: HashMap<i64, String> = collector.lock().unwrap().get_threads()?
+            .into_iter().map(|t| (t.id, t.name)).collect();
+
+        let mut lines = vec![];
+        for (i, thread_id) in thread_ids.iter().enumerate() {
+            check_cancelled()?;
+            report_progress(i, thread_ids.len(), "collapsing call stacks");
+            let stacks = match self.get_collapsed_call_stacks(session_id, *thread_id, start_time, end_time, stats_type_str, aggregation) {
+                Ok(stacks) => stacks,
+                Err(e) => {
+                    if e.kind() == ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                    println!("get collapsed call stacks failed, thread: {}, err: {}", thread_id, e);
+                    continue;
+                }
+            };
+            let stacks = transforms.apply_collapsed_stacks(&stacks);
+            if per_thread_root {
+                let thread_name = thread_names.get(thread_id).map(|x| x.as_str()).unwrap_or("");
+                let root_frame = format!("{} [{}]", thread_name, thread_id).replace(";", "_");
+                for stack in stacks.iter() {
+                    lines.push(format!("{};{}", root_frame, stack));
+                }
+            } else {
+                lines.extend(stacks.iter().cloned());
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Select threads by 'thread_ids', 'thread_name_pattern' or 'all_threads' options, return None if none of them is present.
+    fn select_thread_ids(&self, session_id: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<Option<Vec<i64>>> {
+        let all_threads = options.get("all_threads").and_then(|x| x.as_bool()).unwrap_or(false);
+        let name_pattern = get_option_as_str(options, "thread_name_pattern", "");
+        let mut thread_ids = if options.contains_key("thread_ids") { get_option_as_int_array(options, "thread_ids")? } else { vec![] };
+        if !all_threads && name_pattern.is_empty() && thread_ids.is_empty() {
+            return Ok(None);
+        }
+
+        let collector = self.get_sample_collector(session_id)?;
+        let threads = collector.lock().unwrap().get_threads()?;
+        for thread in &threads {
+            if thread.sample_count <= 0 {
+                continue;
+            }
+            if all_threads || (!name_pattern.is_empty() && match_wildcard(name_pattern, &thread.name)) {
+                thread_ids.push(thread.id);
+            }
+        }
+        thread_ids.sort();
+        thread_ids.dedup();
+        Ok(Some(thread_ids))
+    }
+
     fn prepare_flame_graph_frames<'a>(&self, node: &'a Box<TreeNode>, frames: &mut Vec<TimedFrame<'a>>, delta_max: &mut usize) {
         let frame = TimedFrame::new(
             &node.label,
@@ -286,14 +876,205 @@ impl Profiler {
         }
     }
 
-    pub fn get_sequenced_call_tree(&mut self, session_id: &str, thread_id: i64, start_time: &mut i64, end_time: &mut i64, stats_type_str: &str) -> io::Result<Box<tree::TreeNode>> {
+    pub fn get_sequenced_call_tree(&self, session_id: &str, thread_id: i64, start_time: &mut i64, end_time: &mut i64, stats_type_str: &str, aggregation: AggregationLevel) -> io::Result<Box<tree::TreeNode>> {
+        let mut tree = self.get_sample_reader(session_id)?.get_sequenced_call_tree(thread_id, start_time, end_time, true)?;
+        aggregation.aggregate_tree(&mut tree, true);
+        Ok(tree)
+    }
+
+    //get cached tree or build it, drilling down a node does not rebuild the tree
+    fn get_cached_call_tree(&self, session_id: &str, tree_type: &str, thread_ids: &[i64], start_time: i64, end_time: i64,
+                            aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<Arc<TreeNode>> {
+        let (sample_info, data_version) = {
+            let collector = self.get_sample_collector(session_id)?;
+            let collector = collector.lock().unwrap();
+            (collector.get_sample_info(), collector.get_data_version())
+        };
+        let key = RangeCacheKey::new(session_id, tree_type, thread_ids, start_time, end_time, &format!("{}|{}", aggregation, transforms.key));
+        let cached_value = self.range_cache.lock().unwrap().get(&key, sample_info.record_start_time, sample_info.last_record_time, data_version);
+        if let Some(RangeCacheValue::CallTree(tree)) = cached_value {
+            return Ok(tree);
+        }
+
+        let mut tree = match tree_type {
+            "call_tree" => self.get_call_tree(session_id, thread_ids, start_time, end_time, aggregation)?,
+            "sequenced_call_tree" => {
+                if thread_ids.len() != 1 {
+                    return Err(new_invalid_input_error("sequenced_call_tree requires exactly one thread"));
+                }
+                let mut new_start_time = start_time;
+                let mut new_end_time = end_time;
+                *self.get_sequenced_call_tree(session_id, thread_ids[0], &mut new_start_time, &mut new_end_time, "duration", aggregation)?
+            },
+            "bottom_up_tree" => {
+                let mut tree = self.get_sample_reader(session_id)?.get_bottom_up_tree(thread_ids, start_time, end_time)?;
+                aggregation.aggregate_tree(&mut tree, false);
+                *tree
+            },
+            _ => return Err(new_invalid_input_error(&format!("invalid tree_type: {}", tree_type)))
+        };
+        transforms.apply_tree(&mut tree, tree_type == "sequenced_call_tree");
+
+        let tree = Arc::new(tree);
+        self.range_cache.lock().unwrap().put(key, RangeCacheValue::CallTree(tree.clone()), sample_info.record_start_time, sample_info.last_record_time, data_version);
+        Ok(tree)
+    }
+
+    pub fn get_collapsed_call_stacks(&self, session_id: &str, thread_id: i64, start_time: i64, end_time: i64, stats_type_str: &str, aggregation: AggregationLevel) -> io::Result<Arc<Vec<String>>> {
+        let stats_type = match StatsType::from_str(stats_type_str) {
+            Ok(x) => x,
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
         let collector = self.get_sample_collector(session_id)?;
-        let result = collector.lock().unwrap().get_sequenced_call_tree(thread_id, start_time, end_time, true);
-        result
+        let (sample_info, data_version) = {
+            let collector = collector.lock().unwrap();
+            (collector.get_sample_info(), collector.get_data_version())
+        };
+        let key = RangeCacheKey::new(session_id, "collapsed_stacks", &[thread_id], start_time, end_time, &format!("{}|{}", stats_type_str, aggregation));
+        let cached_value = self.range_cache.lock().unwrap().get(&key, sample_info.record_start_time, sample_info.last_record_time, data_version);
+        if let Some(RangeCacheValue::CollapsedStacks(stacks)) = cached_value {
+            return Ok(stacks);
+        }
+
+        let mut reader = collector.lock().unwrap().create_reader();
+        let stacks = Arc::new(reader.get_collapsed_call_stacks(thread_id, start_time, end_time, stats_type, aggregation)?);
+        self.range_cache.lock().unwrap().put(key, RangeCacheValue::CollapsedStacks(stacks.clone()), sample_info.record_start_time, sample_info.last_record_time, data_version);
+        Ok(stacks)
+    }
+
+    //top n children of the node addressed by node_path, call tree children are sorted by stats value
+    pub fn get_call_tree_children(&self, session_id: &str, tree_type: &str, thread_ids: &[i64], start_time: i64, end_time: i64,
+                                  node_path: &[i64], stats_type_str: &str, offset: usize, top_n: usize,
+                                  aggregation: AggregationLevel, transforms: &TransformPipeline) -> io::Result<JsonValue> {
+        let stats_type = match StatsType::from_str(stats_type_str) {
+            Ok(x) => x,
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let tree = self.get_cached_call_tree(session_id, tree_type, thread_ids, start_time, end_time, aggregation, transforms)?;
+        let node = match tree.get_node_by_path(node_path) {
+            Some(node) => node,
+            None => return Err(new_invalid_input_error(&format!("tree node not found: {:?}", node_path)))
+        };
+
+        //sequenced tree keeps the calling order
+        let mut child_indexes: Vec<usize> = (0..node.children.len()).collect();
+        if tree_type != "sequenced_call_tree" {
+            child_indexes.sort_by_key(|i| -node.children[*i].get_stats_value(&stats_type));
+        }
+
+        let mut children = vec![];
+        for index in child_indexes.iter().skip(offset).take(top_n) {
+            let mut child_path = node_path.to_vec();
+            child_path.push(*index as i64);
+            children.push(node.children[*index].to_summary_json(&child_path));
+        }
+        let total_children = node.children.len();
+        Ok(json!({
+            "session_id": session_id,
+            "tree_type": tree_type,
+            "stats_type": stats_type_str,
+            "aggregation": aggregation.to_string(),
+            "node_path": node_path,
+            "node": node.to_summary_json(node_path),
+            "children": children,
+            "offset": offset,
+            "total_children": total_children,
+            "more": offset + children.len() < total_children
+        }))
+    }
+
+    /// Callers and callees of the method, sorted by stats value.
+    pub fn get_method_butterfly(&self, session_id: &str, thread_ids: &[i64], start_time: i64, end_time: i64, method_id: i64, stats_type_str: &str, top_n: usize) -> io::Result<JsonValue> {
+        let stats_type = match StatsType::from_str(stats_type_str) {
+            Ok(x) => x,
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let mut collector = self.get_sample_reader(session_id)?;
+        let butterfly = collector.get_method_butterfly(thread_ids, start_time, end_time, method_id)?;
+
+        let mut to_json_list = |methods: &HashMap<i64, tree::MethodStats>| {
+            let mut methods: Vec<(&i64, &tree::MethodStats)> = methods.iter().collect();
+            methods.sort_by_key(|(_, stats)| -stats.get_stats_value(&stats_type));
+            methods.iter().take(top_n).map(|(method_id, stats)| json!({
+                "method_id": method_id,
+                "name": collector.get_method_name(**method_id),
+                "duration": stats.duration,
+                "cpu": stats.cpu,
+                "samples": stats.samples,
+            })).collect::<Vec<JsonValue>>()
+        };
+        let callers = to_json_list(&butterfly.callers);
+        let callees = to_json_list(&butterfly.callees);
+        Ok(json!({
+            "session_id": session_id,
+            "thread_ids": thread_ids,
+            "stats_type": stats_type_str,
+            "method_id": method_id,
+            "name": collector.get_method_name(method_id),
+            "self": butterfly.self_stats,
+            "total": butterfly.total_stats,
+            "callers": callers,
+            "total_callers": butterfly.callers.len(),
+            "callees": callees,
+            "total_callees": butterfly.callees.len(),
+        }))
+    }
+
+    /// Flat profile of methods sorted by self or total stats value, with paging.
+    pub fn get_hot_methods(&self, session_id: &str, thread_ids: &[i64], start_time: i64, end_time: i64, stats_type_str: &str, sort_by: &str, offset: usize, top_n: usize) -> io::Result<JsonValue> {
+        let stats_type = match StatsType::from_str(stats_type_str) {
+            Ok(x) => x,
+            Err(_) => return Err(new_invalid_input_error(&format!("invalid stats_type: {}", stats_type_str)))
+        };
+        let sort_by_self = match sort_by {
+            "self" => true,
+            "total" => false,
+            _ => return Err(new_invalid_input_error(&format!("invalid sort_by: {}", sort_by)))
+        };
+        let mut collector = self.get_sample_reader(session_id)?;
+        let profile = collector.get_flat_profile(thread_ids, start_time, end_time)?;
+
+        let mut methods: Vec<(&i64, &tree::MethodProfile)> = profile.methods.iter().collect();
+        methods.sort_by_key(|(method_id, method)| {
+            let stats = if sort_by_self { &method.self_stats } else { &method.total_stats };
+            (-stats.get_stats_value(&stats_type), **method_id)
+        });
+        let total = &profile.total_stats;
+        let percent = |value: i64, total: i64| if total > 0 { value as f64 * 100.0 / total as f64 } else { 0.0 };
+        let mut hot_methods = vec![];
+        for (method_id, method) in methods.iter().skip(offset).take(top_n) {
+            let self_stats = &method.self_stats;
+            let total_stats = &method.total_stats;
+            hot_methods.push(json!({
+                "method_id": method_id,
+                "name": collector.get_method_name(**method_id),
+                "self_duration": self_stats.duration,
+                "total_duration": total_stats.duration,
+                "self_cpu": self_stats.cpu,
+                "total_cpu": total_stats.cpu,
+                "self_samples": self_stats.samples,
+                "total_samples": total_stats.samples,
+                "self_percent": percent(self_stats.get_stats_value(&stats_type), total.get_stats_value(&stats_type)),
+                "total_percent": percent(total_stats.get_stats_value(&stats_type), total.get_stats_value(&stats_type)),
+            }));
+        }
+        Ok(json!({
+            "session_id": session_id,
+            "thread_ids": thread_ids,
+            "start_time": start_time,
+            "end_time": end_time,
+            "stats_type": stats_type_str,
+            "sort_by": sort_by,
+            "total": total,
+            "methods": hot_methods,
+            "offset": offset,
+            "total_methods": methods.len(),
+            "more": offset + hot_methods.len() < methods.len()
+        }))
     }
 
-    pub fn get_sample_info(&mut self, session_id: &str) -> io::Result<SampleInfo> {
-        if let Some(collector) = self.sample_session_map.get(session_id) {
+    pub fn get_sample_info(&self, session_id: &str) -> io::Result<SampleInfo> {
+        if let Some(collector) = self.sample_session_map.read().unwrap().get(session_id) {
             Ok(collector.lock().unwrap().get_sample_info())
         }else {
             Err(io::Error::new(ErrorKind::NotFound, "sample session not found"))
@@ -301,36 +1082,31 @@ impl Profiler {
     }
 
     fn list_methods_by_filter(&self, session_id: &str, method_name_filter: &str) -> io::Result<Vec<MethodInfo>> {
-        if let Some(collector) = self.sample_session_map.get(session_id) {
-            Ok(collector.lock().unwrap().list_methods_by_filter(method_name_filter)?)
-        }else {
-            Err(io::Error::new(ErrorKind::NotFound, "sample session not found"))
-        }
+        self.get_sample_reader(session_id)?.list_methods_by_filter(method_name_filter)
     }
 
     fn search_slow_method_calls(&self, session_id: &str, thread_id: i64, method_ids: &[i64], min_duration: i64, max_duration: i64) -> io::Result<Vec<Box<MethodCall>>> {
-        if let Some(collector) = self.sample_session_map.get(session_id) {
-            Ok(collector.lock().unwrap().search_slow_method_calls(thread_id, method_ids, min_duration, max_duration)?)
-        }else {
-            Err(io::Error::new(ErrorKind::NotFound, "sample session not found"))
-        }
+        self.get_sample_reader(session_id)?.search_slow_method_calls(thread_id, method_ids, min_duration, max_duration)
     }
 
-    fn start_http_server(&mut self) {
-        thread::spawn(|| {
-            SimpleHttpServer::start_server();
+    fn start_http_server(&self) {
+        let self_ref = self.get_self_ref();
+        let bind_addr = self.config.get_http_bind_addr();
+        let static_dir = self.config.find_static_dir();
+        thread::spawn(move || {
+            SimpleHttpServer::start_server(self_ref, bind_addr, static_dir);
         });
     }
 
-    fn start_ws_server(&mut self) {
-        let self_ref = self.self_ref.as_ref().unwrap().clone();
-        let bind_addr = self.bind_addr.clone();
+    fn start_ws_server(&self) {
+        let self_ref = self.get_self_ref();
+        let bind_addr = self.config.ws_bind_addr.clone();
         thread::spawn(move || {
             match Server::bind(bind_addr.clone()) {
                 Ok(server) => {
                     println!("Flare profiler started on port: {}", bind_addr);
                     for request in server.filter_map(Result::ok) {
-                        if !self_ref.lock().unwrap().is_running() {
+                        if !self_ref.is_running() {
                             println!("Shutting down flare analysis server ...");
                             return;
                         }
@@ -339,13 +1115,13 @@ impl Profiler {
                 }
                 Err(e) => {
                     println!("Start flare analysis server failed, bind addr: {}, error: {}", bind_addr, e);
-                    self_ref.lock().unwrap().shutdown();
+                    self_ref.shutdown();
                 }
             }
         });
     }
 
-    fn handle_connection(self_ref: Arc<Mutex<Profiler>>, request: WsUpgrade<std::net::TcpStream, Option<Buffer>>) {
+    fn handle_connection(self_ref: Arc<Profiler>, request: WsUpgrade<std::net::TcpStream, Option<Buffer>>) {
         // Spawn a new thread for each connection.
         thread::spawn(move || {
             let ws_protocol = "flare-profiler";
@@ -366,39 +1142,106 @@ impl Profiler {
             //recv first message
 //            client.recv_message();
 
-            //recv and dispatch message
-            let (mut receiver, mut sender) = client.split().unwrap();
+            //recv and dispatch message, the writer is shared with push subscriptions
+            let (mut receiver, sender) = client.split().unwrap();
+            let ws_client = WsClient::new(sender);
             for message in receiver.incoming_messages() {
-                let message = message.unwrap();
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        println!("Client {} receive message failed: {}", ip, e);
+                        break;
+                    }
+                };
                 match message {
                     OwnedMessage::Close(_) => {
                         let message = OwnedMessage::Close(None);
-                        sender.send_message(&message).unwrap();
+                        ws_client.writer.lock().unwrap().send_message(&message);
                         println!("Client {} disconnected", ip);
-                        return;
+                        break;
                     }
                     OwnedMessage::Ping(ping) => {
                         let message = OwnedMessage::Pong(ping);
-                        sender.send_message(&message).unwrap();
+                        ws_client.writer.lock().unwrap().send_message(&message);
                     }
                     OwnedMessage::Text(json) => {
-                        let mut cmd = String::new();
-                        if let Err(e) = self_ref.lock().unwrap().handle_request(&mut sender,json.clone(), &mut cmd) {
-                            let err = e.to_string();
-                            println!("handle request failed: {}, cmd: {}, json: {}", err, cmd, json);
-                            //send error
-                            sender.send_message(&wrap_error_response(&cmd, &err));
+                        let (cmd, request_id) = Profiler::parse_request_header(&json);
+                        let sender = RequestContext::new(ws_client.writer.clone(), &cmd, request_id);
+                        //register before running, so a following cancel request always finds it
+                        if !self_ref.requests.lock().unwrap().register(ws_client.id, &sender) {
+                            sender.send_error(&sender.cmd, "duplicate request_id of in-flight request");
+                            continue;
+                        }
+                        if sender.request_id.is_some() {
+                            //request with id runs in background, so it can be cancelled by a later request
+                            let self_ref = self_ref.clone();
+                            let ws_client = ws_client.clone();
+                            thread::spawn(move || {
+                                self_ref.dispatch_request(&sender, &ws_client, json);
+                            });
+                        } else {
+                            self_ref.dispatch_request(&sender, &ws_client, json);
                         }
                     }
                     _ => {
-                        sender.send_message(&message).unwrap()
+                        ws_client.writer.lock().unwrap().send_message(&message);
                     },
                 }
             }
+            let count = self_ref.subscriptions.lock().unwrap().remove_client(ws_client.id);
+            if count > 0 {
+                println!("Client {} closed, remove subscriptions: {}", ip, count);
+            }
+            let count = self_ref.requests.lock().unwrap().remove_client(ws_client.id);
+            if count > 0 {
+                println!("Client {} closed, cancel requests: {}", ip, count);
+            }
         });
     }
 
-    fn handle_request(&mut self, sender: &mut Writer<std::net::TcpStream>, json_str: String, _out_cmd: &mut String) -> io::Result<()> {
+    //cmd and optional request_id of request json
+    fn parse_request_header(json_str: &str) -> (String, Option<JsonValue>) {
+        match serde_json::from_str::<JsonValue>(json_str) {
+            Ok(request) => {
+                let cmd = request["cmd"].as_str().unwrap_or("").to_string();
+                let request_id = request.get("request_id").filter(|x| !x.is_null()).cloned();
+                (cmd, request_id)
+            },
+            Err(_) => (String::new(), None)
+        }
+    }
+
+    //handle registered request and send error response, the request with id can be cancelled while running
+    fn dispatch_request(&self, sender: &RequestContext, client: &WsClient, json: String) {
+        let mut cmd = String::new();
+        if let Err(e) = sender.enter(|| self.handle_request(sender, Some(client), json.clone(), &mut cmd)) {
+            let err = e.to_string();
+            println!("handle request failed: {}, cmd: {}, json: {}", err, cmd, json);
+            //send error
+            sender.send_error(&cmd, &err);
+        }
+        self.requests.lock().unwrap().unregister(client.id, sender);
+    }
+
+    /// Handle a request without ws connection (e.g. http api), return the response messages.
+    pub fn handle_buffered_request(&self, json_str: String) -> Vec<String> {
+        let (cmd, request_id) = Profiler::parse_request_header(&json_str);
+        let sender = RequestContext::new_buffered(&cmd, request_id);
+        let mut cmd = String::new();
+        if let Err(e) = sender.enter(|| self.handle_request(&sender, None, json_str.clone(), &mut cmd)) {
+            let err = e.to_string();
+            println!("handle request failed: {}, cmd: {}, json: {}", err, cmd, json_str);
+            sender.send_error(&cmd, &err);
+        }
+        sender.take_messages()
+    }
+
+    //subscriptions and cancel are bound to the ws connection
+    fn require_ws_client(client: Option<&WsClient>) -> io::Result<&WsClient> {
+        client.ok_or_else(|| new_invalid_input_error("cmd requires a websocket connection"))
+    }
+
+    fn handle_request(&self, sender: &RequestContext, client: Option<&WsClient>, json_str: String, _out_cmd: &mut String) -> io::Result<()> {
         println!("recv: {}", json_str);
         //TODO parse request to json
         let request: JsonValue = serde_json::from_str(&json_str)?;
@@ -439,6 +1282,27 @@ impl Profiler {
             "close_all_session" => {
                 self.handle_close_all_session_request(sender, cmd, options)?;
             }
+            "open_cluster" => {
+                self.handle_open_cluster_request(sender, cmd, options)?;
+            }
+            "close_cluster" => {
+                self.handle_close_cluster_request(sender, cmd, options)?;
+            }
+            "cluster_flame_graph" => {
+                self.handle_cluster_flame_graph_request(sender, cmd, options)?;
+            }
+            "cluster_hot_methods" => {
+                self.handle_cluster_hot_methods_request(sender, cmd, options)?;
+            }
+            "set_trigger_rules" => {
+                self.handle_set_trigger_rules_request(sender, cmd, options)?;
+            }
+            "clear_trigger_rules" => {
+                self.handle_clear_trigger_rules_request(sender, cmd, options)?;
+            }
+            "trigger_markers" => {
+                self.handle_trigger_markers_request(sender, cmd, options)?;
+            }
             "dashboard" => {
                 self.handle_dashboard_request(sender, cmd, options)?;
             }
@@ -454,12 +1318,57 @@ impl Profiler {
             "flame_graph" => {
                 self.handle_flame_graph_request(sender, cmd, options)?;
             }
+            "diff_flame_graph" => {
+                self.handle_diff_flame_graph_request(sender, cmd, options)?;
+            }
+            "bottom_up_tree" => {
+                self.handle_bottom_up_tree_request(sender, cmd, options)?;
+            }
+            "method_butterfly" => {
+                self.handle_method_butterfly_request(sender, cmd, options)?;
+            }
+            "hot_methods" => {
+                self.handle_hot_methods_request(sender, cmd, options)?;
+            }
+            "call_tree_children" => {
+                self.handle_call_tree_children_request(sender, cmd, options)?;
+            }
             "list_methods_by_filter" => {
                 self.handle_list_methods_by_filter_request(sender, cmd, options)?;
             }
             "search_slow_method_calls" => {
                 self.handle_search_slow_method_calls_request(sender, cmd, options)?;
             }
+            "export_archive" => {
+                self.handle_export_archive_request(sender, cmd, options)?;
+            }
+            "import_archive" => {
+                self.handle_import_archive_request(sender, cmd, options)?;
+            }
+            "rename_recording" => {
+                self.handle_rename_recording_request(sender, cmd, options)?;
+            }
+            "tag_recording" => {
+                self.handle_tag_recording_request(sender, cmd, options)?;
+            }
+            "delete_recording" => {
+                self.handle_delete_recording_request(sender, cmd, options)?;
+            }
+            "search_recordings" => {
+                self.handle_search_recordings_request(sender, cmd, options)?;
+            }
+            "subscribe_dashboard" => {
+                self.handle_subscribe_request(sender, cmd, options, Profiler::require_ws_client(client)?, SubscriptionType::DASHBOARD)?;
+            }
+            "subscribe_cpu_time" => {
+                self.handle_subscribe_request(sender, cmd, options, Profiler::require_ws_client(client)?, SubscriptionType::CPU_TIME)?;
+            }
+            "unsubscribe" => {
+                self.handle_unsubscribe_request(sender, cmd, options, Profiler::require_ws_client(client)?)?;
+            }
+            "cancel" => {
+                self.handle_cancel_request(sender, cmd, options, Profiler::require_ws_client(client)?)?;
+            }
             _ => {
                 println!("unknown cmd: {}, request: {}", cmd, json_str);
             }
@@ -468,21 +1377,22 @@ impl Profiler {
     }
 
     //list open sessions
-    fn handle_list_sessions(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_list_sessions(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let mut sample_sessions = vec![];
-        for (instance_id, collector) in self.sample_session_map.iter() {
+        for (instance_id, collector) in self.sample_session_map.read().unwrap().iter() {
             let sample_type = collector.lock().unwrap().get_sample_type();
             sample_sessions.push(json!({"session_id": instance_id, "type": sample_type.to_string()}))
         }
-        let data = json!({"sample_sessions": sample_sessions});
-        sender.send_message(&wrap_response(cmd, &data));
+        let cluster_sessions = self.clusters.lock().unwrap().list();
+        let data = json!({"sample_sessions": sample_sessions, "cluster_sessions": cluster_sessions});
+        sender.send_response(cmd, &data);
         Ok(())
     }
 
     //list history samples
-    fn handle_history_samples(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_history_samples(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let mut samples = vec![];
-        let paths = std::fs::read_dir(FLARE_SAMPLES_DIR)?;
+        let paths = std::fs::read_dir(&self.config.data_dir)?;
         for dir in paths {
             let path_buf = dir.unwrap().path();
             if !std::fs::metadata(&path_buf).unwrap().is_dir() {
@@ -490,22 +1400,87 @@ impl Profiler {
             }
             samples.push(json!({"path": path_buf.to_str(), "type": "file"}));
         }
-        let data = json!({"history_samples": samples});
-        sender.send_message(&wrap_response(cmd, &data));
+        let scanned = scan_recordings(&self.config.data_dir)?;
+        let recordings = {
+            let mut catalogue = self.catalogue.lock().unwrap();
+            catalogue.update(scanned)?;
+            catalogue.list_recordings()
+        };
+        let data = json!({"history_samples": samples, "recordings": recordings});
+        sender.send_response(cmd, &data);
+        Ok(())
+    }
+
+    fn handle_export_archive_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let privacy = PrivacyOptions {
+            strip_thread_names: options.get("strip_thread_names").and_then(|x| x.as_bool()).unwrap_or(false),
+            strip_package_prefixes: if options.contains_key("strip_package_prefixes") { get_option_as_str_array(options, "strip_package_prefixes")? } else { vec![] },
+        };
+        let archive_path = self.export_archive(session_id, start_time, end_time, privacy)?;
+        sender.send_response(&cmd, &json!({ "session_id": session_id, "archive_path": archive_path }));
+        Ok(())
+    }
+
+    fn handle_import_archive_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let archive_path = get_option_as_str_required(options, "archive_path")?;
+        let instance_id = self.open_sample(archive_path)?;
+        sender.send_response(&cmd, &json!({ "session_id": instance_id, "type": "file" }));
         Ok(())
     }
 
-    fn handle_open_sample(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_rename_recording_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let recording_id = get_option_as_str_required(options, "recording_id")?;
+        let name = get_option_as_str_required(options, "name")?;
+        let recording = self.catalogue.lock().unwrap().rename(recording_id, name)?;
+        sender.send_response(&cmd, &recording);
+        Ok(())
+    }
+
+    fn handle_tag_recording_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let recording_id = get_option_as_str_required(options, "recording_id")?;
+        let tags = get_option_as_str_array(options, "tags")?;
+        let notes = options.get("notes").and_then(|x| x.as_str());
+        let recording = self.catalogue.lock().unwrap().set_tags(recording_id, tags, notes)?;
+        sender.send_response(&cmd, &recording);
+        Ok(())
+    }
+
+    fn handle_delete_recording_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let recording_id = get_option_as_str_required(options, "recording_id")?;
+        let recording = self.delete_recording(recording_id)?;
+        sender.send_response(&cmd, &recording);
+        Ok(())
+    }
+
+    fn handle_search_recordings_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let keyword = get_option_as_str(options, "keyword", "");
+        let tags = if options.contains_key("tags") { get_option_as_str_array(options, "tags")? } else { vec![] };
+        let start_time = get_option_as_int(options, "start_time", 0);
+        let end_time = get_option_as_int(options, "end_time", 0);
+        let scanned = scan_recordings(&self.config.data_dir)?;
+        let recordings = {
+            let mut catalogue = self.catalogue.lock().unwrap();
+            catalogue.update(scanned)?;
+            catalogue.search(keyword, &tags, start_time, end_time)
+        };
+        sender.send_response(&cmd, &json!({"recordings": recordings}));
+        Ok(())
+    }
+
+    fn handle_open_sample(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let sample_data_dir = options["sample_data_dir"].as_str().unwrap_or("");
         if sample_data_dir == "" {
             return Err(new_invalid_input_error("missing option 'sample_data_dir'"));
         }
         let instance_id = self.open_sample(sample_data_dir)?;
-        sender.send_message(&wrap_response(&cmd, &json!({ "session_id": instance_id, "type": "file" })));
+        sender.send_response(&cmd, &json!({ "session_id": instance_id, "type": "file" }));
         Ok(())
     }
 
-    fn handle_attach_jvm(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_attach_jvm(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let target_pid = options["target_pid"].as_u64();
         if target_pid.is_none() {
             return Err(new_invalid_input_error("missing option 'target_pid'"));
@@ -518,38 +1493,199 @@ impl Profiler {
         Ok(())
     }
 
-    fn handle_connect_agent(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_connect_agent(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let agent_addr = options.get("agent_addr").map_or(None, |x| x.as_str());
         if agent_addr.is_none() {
             return Err(new_invalid_input_error("missing option 'agent_addr'"));
         }
-        let instance_id = self.connect_agent(agent_addr.unwrap())?;
-        sender.send_message(&wrap_response(&cmd, &json!({ "session_id": instance_id, "type": "attach" })));
+        let replay = options.get("replay").and_then(|x| x.as_bool()).unwrap_or(false);
+        let instance_id = self.connect_agent(agent_addr.unwrap(), replay)?;
+        sender.send_response(&cmd, &json!({ "session_id": instance_id, "type": "attach" }));
 
         Ok(())
     }
 
-    fn handle_close_session_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_close_session_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let session_id = get_option_as_str_required(options, "session_id")?;
         self.close_session(session_id)?;
-        sender.send_message(&wrap_response(&cmd, &json!({ "session_id": session_id})));
+        sender.send_response(&cmd, &json!({ "session_id": session_id}));
+        Ok(())
+    }
+
+    fn handle_open_cluster_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let name = get_option_as_str_required(options, "name")?;
+        let agent_addrs = if options.contains_key("agent_addrs") { get_option_as_str_array(options, "agent_addrs")? } else { vec![] };
+        let sample_dirs = if options.contains_key("sample_dirs") { get_option_as_str_array(options, "sample_dirs")? } else { vec![] };
+        let cluster_id = self.open_cluster(name, &agent_addrs, &sample_dirs)?;
+        let cluster = self.get_cluster(&cluster_id)?;
+        sender.send_response(&cmd, &cluster);
+        Ok(())
+    }
+
+    fn handle_close_cluster_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let cluster_id = get_option_as_str_required(options, "cluster_id")?;
+        self.close_cluster(cluster_id)?;
+        sender.send_response(&cmd, &json!({ "cluster_id": cluster_id }));
+        Ok(())
+    }
+
+    fn handle_cluster_flame_graph_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let cluster_id = get_option_as_str_required(options, "cluster_id")?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let mut image_width = get_option_as_int(options, "image_width", 900);
+        if image_width <= 0 {
+            image_width = 900;
+        }
+        let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let per_instance_root = options.get("per_instance_root").and_then(|x| x.as_bool()).unwrap_or(false);
+        let inverted = options.get("inverted").and_then(|x| x.as_bool()).unwrap_or(false);
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
+        let mut sw = Stopwatch::start_new();
+
+        let svg = self.create_cluster_flame_graph_svg(cluster_id, options, start_time, end_time, stats_type, image_width as usize, per_instance_root, inverted, aggregation, &transforms)?;
+        let result = json!({
+            "cluster_id": cluster_id,
+            "start_time": start_time,
+            "end_time": end_time,
+            "stats_type": stats_type,
+            "image_width": image_width,
+            "per_instance_root": per_instance_root,
+            "inverted": inverted,
+            "aggregation": aggregation.to_string(),
+            "transforms": transforms.get_options(),
+            "flame_graph_data": svg
+        });
+        sender.send_response(&cmd, &result);
+        println!("handle_cluster_flame_graph_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    fn handle_cluster_hot_methods_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let cluster_id = get_option_as_str_required(options, "cluster_id")?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let sort_by = get_option_as_str(options, "sort_by", "self");
+        let offset = max(get_option_as_int(options, "offset", 0), 0) as usize;
+        let top_n = max(get_option_as_int(options, "top_n", 100), 1) as usize;
+        let mut sw = Stopwatch::start_new();
+
+        let result = self.get_cluster_hot_methods(cluster_id, options, start_time, end_time, stats_type, sort_by, offset, top_n)?;
+        sender.send_response(&cmd, &result);
+        println!("handle_cluster_hot_methods_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    fn handle_set_trigger_rules_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let rules: Vec<TriggerRule> = match options.get("rules") {
+            Some(rules) => serde_json::from_value(rules.clone())
+                .map_err(|e| new_invalid_input_error(&format!("invalid trigger rules: {}", e)))?,
+            None => return Err(new_invalid_input_error("missing option 'rules'"))
+        };
+        let policy = TriggerPolicy {
+            rules,
+            pre_window_ms: get_option_as_int(options, "pre_window_ms", DEFAULT_PRE_WINDOW_MS),
+            post_window_ms: get_option_as_int(options, "post_window_ms", DEFAULT_POST_WINDOW_MS),
+            downsample_ratio: max(get_option_as_int(options, "downsample_ratio", DEFAULT_DOWNSAMPLE_RATIO as i64), 0) as u32,
+            max_pending_bytes: max(get_option_as_int(options, "max_pending_mb", DEFAULT_MAX_PENDING_MB), 0) as usize * 1024 * 1024,
+        };
+        let collector = self.get_sample_collector(session_id)?;
+        let mut collector = collector.lock().unwrap();
+        if collector.get_sample_type() != "attach" {
+            return Err(new_invalid_input_error("trigger rules require an attach session"));
+        }
+        collector.set_trigger_policy(Some(policy.clone()))?;
+        sender.send_response(&cmd, &json!({
+            "session_id": session_id,
+            "policy": policy
+        }));
         Ok(())
     }
 
-    fn handle_close_all_session_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_clear_trigger_rules_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let collector = self.get_sample_collector(session_id)?;
+        collector.lock().unwrap().set_trigger_policy(None)?;
+        sender.send_response(&cmd, &json!({ "session_id": session_id }));
+        Ok(())
+    }
+
+    fn handle_trigger_markers_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let collector = self.get_sample_collector(session_id)?;
+        let collector = collector.lock().unwrap();
+        sender.send_response(&cmd, &json!({
+            "session_id": session_id,
+            "policy": collector.get_trigger_policy(),
+            "markers": collector.get_trigger_markers()
+        }));
+        Ok(())
+    }
+
+    fn handle_close_all_session_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         self.close_all_session()?;
-        sender.send_message(&wrap_response(&cmd, &json!({})));
+        sender.send_response(&cmd, &json!({}));
         Ok(())
     }
 
-    fn handle_dashboard_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_dashboard_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let session_id = get_option_as_str_required(options, "session_id")?;
         let dashboard_info = self.get_dashboard(session_id)?;
-        sender.send_message(&wrap_response(&cmd, &dashboard_info));
+        sender.send_response(&cmd, &dashboard_info);
+        Ok(())
+    }
+
+    //push incremental updates of a live session to the client
+    fn handle_subscribe_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>,
+                                client: &WsClient, sub_type: SubscriptionType) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let interval_ms = min(max(get_option_as_int(options, "interval_ms", DEFAULT_PUSH_INTERVAL_MS), MIN_PUSH_INTERVAL_MS), MAX_PUSH_INTERVAL_MS);
+        let thread_ids = if options.contains_key("thread_ids") { get_option_as_int_array(options, "thread_ids")? } else { vec![] };
+        let collector = self.get_sample_collector(session_id)?;
+        let receiver = {
+            let mut collector = collector.lock().unwrap();
+            if collector.get_sample_type() != "attach" {
+                return Err(new_invalid_input_error("only attach session can be subscribed"));
+            }
+            collector.add_event_listener()
+        };
+        let subscription_id = self.subscriptions.lock().unwrap().subscribe(client, session_id, sub_type, receiver, interval_ms, thread_ids.clone());
+        sender.send_response(&cmd, &json!({
+            "subscription_id": subscription_id,
+            "session_id": session_id,
+            "interval_ms": interval_ms,
+            "thread_ids": thread_ids
+        }));
+        Ok(())
+    }
+
+    fn handle_unsubscribe_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>, client: &WsClient) -> io::Result<()> {
+        let subscription_id = get_option_as_int(options, "subscription_id", -1);
+        if subscription_id <= 0 || !self.subscriptions.lock().unwrap().unsubscribe(client.id, subscription_id as usize) {
+            return Err(new_invalid_input_error("subscription not found"));
+        }
+        sender.send_response(&cmd, &json!({"subscription_id": subscription_id}));
+        Ok(())
+    }
+
+    //abort an in-flight request of the client by its request_id
+    fn handle_cancel_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>, client: &WsClient) -> io::Result<()> {
+        let request_id = match options.get("request_id") {
+            Some(x) if !x.is_null() => x,
+            _ => return Err(new_invalid_input_error("missing option 'request_id'"))
+        };
+        let cancelled_cmd = match self.requests.lock().unwrap().cancel(client.id, request_id) {
+            Some(x) => x,
+            None => return Err(new_error(ErrorKind::NotFound, "request not found or finished"))
+        };
+        sender.send_response(&cmd, &json!({"request_id": request_id, "cmd": cancelled_cmd}));
         Ok(())
     }
 
-    fn handle_cpu_time_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_cpu_time_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let session_id = get_option_as_str_required(options, "session_id")?;
         let mut thread_ids = get_option_as_int_array(options, "thread_ids")?;
         let start_time = get_option_as_int(options, "start_time", -1);
@@ -566,6 +1702,7 @@ impl Profiler {
         println!("[{}] handle_cpu_time_request, fetching thread count: {}", utils::nowTime(), thread_ids.len());
         let mut start = 0;
         while start < thread_ids.len() {
+            check_cancelled()?;
             let t1 = Local::now().timestamp_millis();
             let end = min(start+50, thread_ids.len());
             let thread_cpu_times = self.get_thread_cpu_times(session_id, &thread_ids[start..end], start_time, end_time, unit_time_ms, graph_width)?;;
@@ -574,7 +1711,7 @@ impl Profiler {
                 "thread_cpu_times": thread_cpu_times
             });
             let t2 = Local::now().timestamp_millis();
-            sender.send_message(&wrap_response(&cmd, &result));
+            sender.send_response(&cmd, &result);
             println!("[{}] fetch thread cpu time data cost: {}ms, threads: {}-{}", utils::nowTime(), t2-t1, start, end);
             start = end;
         }
@@ -584,21 +1721,26 @@ impl Profiler {
         Ok(())
     }
 
-    fn handle_call_tree_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_call_tree_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let session_id = get_option_as_str_required(options, "session_id")?;
         let thread_ids = get_option_as_int_array(options, "thread_ids")?;
         let start_time = get_option_as_int(options, "start_time", -1);
         let end_time = get_option_as_int(options, "end_time", -1);
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
         let mut sw = Stopwatch::start_new();
 
-        let call_tree = self.get_call_tree(session_id, thread_ids.as_slice(), start_time, end_time)?;
+        let mut call_tree = self.get_call_tree(session_id, thread_ids.as_slice(), start_time, end_time, aggregation)?;
+        transforms.apply_tree(&mut call_tree, false);
         println!("build call tree data cost: {}ms, threads: {:?}", sw.lap(), &thread_ids);
 
         let result = json!({
                 "session_id": session_id,
+                "aggregation": aggregation.to_string(),
+                "transforms": transforms.get_options(),
                 "call_tree_data": [call_tree]
             });
-        let message = wrap_response(&cmd, &result);
+        let message = sender.wrap_response(&cmd, &result);
         println!("wrap message cost: {}ms", sw.lap());
 
         sender.send_message(&message);
@@ -606,7 +1748,107 @@ impl Profiler {
         Ok(())
     }
 
-    fn handle_flame_graph_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_bottom_up_tree_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let thread_ids = self.get_required_thread_ids(session_id, options)?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
+        let mut sw = Stopwatch::start_new();
+
+        let tree = self.get_cached_call_tree(session_id, "bottom_up_tree", &thread_ids, start_time, end_time, aggregation, &transforms)?;
+        let result = json!({
+                "session_id": session_id,
+                "thread_ids": thread_ids,
+                "aggregation": aggregation.to_string(),
+                "transforms": transforms.get_options(),
+                "call_tree_data": [&*tree]
+            });
+        sender.send_response(&cmd, &result);
+        println!("handle_bottom_up_tree_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    fn handle_method_butterfly_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let thread_ids = self.get_required_thread_ids(session_id, options)?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let method_id = get_option_as_int(options, "method_id", 0);
+        if method_id == 0 {
+            return Err(new_invalid_input_error("missing or invalid option 'method_id'"));
+        }
+        let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let top_n = max(get_option_as_int(options, "top_n", 100), 1) as usize;
+        let mut sw = Stopwatch::start_new();
+
+        let result = self.get_method_butterfly(session_id, &thread_ids, start_time, end_time, method_id, stats_type, top_n)?;
+        sender.send_response(&cmd, &result);
+        println!("handle_method_butterfly_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    fn handle_hot_methods_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let thread_ids = self.get_required_thread_ids(session_id, options)?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let sort_by = get_option_as_str(options, "sort_by", "self");
+        let offset = max(get_option_as_int(options, "offset", 0), 0) as usize;
+        let top_n = max(get_option_as_int(options, "top_n", 100), 1) as usize;
+        let mut sw = Stopwatch::start_new();
+
+        let result = self.get_hot_methods(session_id, &thread_ids, start_time, end_time, stats_type, sort_by, offset, top_n)?;
+        sender.send_response(&cmd, &result);
+        println!("handle_hot_methods_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    //thread set options or single 'thread_id'
+    fn get_required_thread_ids(&self, session_id: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<Vec<i64>> {
+        let thread_ids = match self.select_thread_ids(session_id, options)? {
+            Some(thread_ids) => thread_ids,
+            None => {
+                let thread_id = get_option_as_int(options, "thread_id", -1);
+                if thread_id <= 0 {
+                    return Err(new_invalid_input_error("missing option 'thread_ids' or 'thread_id'"));
+                }
+                vec![thread_id]
+            }
+        };
+        if thread_ids.is_empty() {
+            return Err(new_invalid_input_error("no thread matched"));
+        }
+        Ok(thread_ids)
+    }
+
+    fn handle_call_tree_children_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let session_id = get_option_as_str_required(options, "session_id")?;
+        let tree_type = get_option_as_str(options, "tree_type", "call_tree");
+        let thread_ids = get_option_as_int_array(options, "thread_ids")?;
+        let start_time = get_option_as_int(options, "start_time", -1);
+        let end_time = get_option_as_int(options, "end_time", -1);
+        let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let node_path = if options.contains_key("node_path") {
+            get_option_as_int_array(options, "node_path")?
+        } else {
+            vec![]
+        };
+        let offset = max(get_option_as_int(options, "offset", 0), 0) as usize;
+        let top_n = max(get_option_as_int(options, "top_n", 100), 1) as usize;
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
+        let mut sw = Stopwatch::start_new();
+
+        let result = self.get_call_tree_children(session_id, tree_type, &thread_ids, start_time, end_time, &node_path, stats_type, offset, top_n, aggregation, &transforms)?;
+        sender.send_response(&cmd, &result);
+        println!("handle_call_tree_children_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    fn handle_flame_graph_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let session_id = get_option_as_str_required(options, "session_id")?;
         let thread_id = get_option_as_int(options, "thread_id", -1);
         let start_time = get_option_as_int(options, "start_time", -1);
@@ -616,14 +1858,46 @@ impl Profiler {
             image_width = 900;
         }
         let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
         let mut sw = Stopwatch::start_new();
 
+        //merged flame graph of thread set, or inverted flame graph
+        let inverted = options.get("inverted").and_then(|x| x.as_bool()).unwrap_or(false);
+        let mut selected_thread_ids = self.select_thread_ids(session_id, options)?;
+        if inverted && selected_thread_ids.is_none() && thread_id > 0 {
+            selected_thread_ids = Some(vec![thread_id]);
+        }
+        if let Some(thread_ids) = selected_thread_ids {
+            if thread_ids.is_empty() {
+                return Err(new_invalid_input_error("no thread matched"));
+            }
+            let per_thread_root = options.get("per_thread_root").and_then(|x| x.as_bool()).unwrap_or(false);
+            let svg = self.create_merged_flame_graph_svg(session_id, &thread_ids, start_time, end_time, stats_type, image_width as usize, per_thread_root, inverted, aggregation, &transforms)?;
+            let result = json!({
+                "session_id": session_id,
+                "thread_ids": thread_ids,
+                "start_time": start_time,
+                "end_time": end_time,
+                "stats_type": stats_type,
+                "image_width": image_width,
+                "per_thread_root": per_thread_root,
+                "inverted": inverted,
+                "aggregation": aggregation.to_string(),
+                "transforms": transforms.get_options(),
+                "flame_graph_data": svg
+            });
+            sender.send_response(&cmd, &result);
+            println!("handle_flame_graph_request total cost: {}ms", sw.elapsed_ms());
+            return Ok(());
+        }
+
         if thread_id <= 0 {
             return Err(new_invalid_input_error("missing or invalid option 'thread_id'"));
         }
         let mut new_start_time = start_time;
         let mut new_end_time = end_time;
-        let svg = self.create_flame_graph_svg(session_id, thread_id, &mut new_start_time, &mut new_end_time, stats_type, image_width as usize)?;
+        let svg = self.create_flame_graph_svg(session_id, thread_id, &mut new_start_time, &mut new_end_time, stats_type, image_width as usize, aggregation, &transforms)?;
         let result = json!({
                 "session_id": session_id,
                 "thread_id": thread_id,
@@ -631,21 +1905,76 @@ impl Profiler {
                 "end_time": new_end_time,
                 "stats_type": stats_type,
                 "image_width": image_width,
+                "aggregation": aggregation.to_string(),
+                "transforms": transforms.get_options(),
                 "flame_graph_data": svg
             });
-        let message = wrap_response(&cmd, &result);
+        let message = sender.wrap_response(&cmd, &result);
         sender.send_message(&message);
         println!("handle_flame_graph_request total cost: {}ms", sw.elapsed_ms());
 
         Ok(())
     }
 
-    fn handle_sequenced_call_tree_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_diff_flame_graph_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+        let before = self.parse_flame_graph_selection(options, "before")?;
+        let after = self.parse_flame_graph_selection(options, "after")?;
+        let mut image_width = get_option_as_int(options, "image_width", 900);
+        if image_width <= 0 {
+            image_width = 900;
+        }
+        let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let normalize = options.get("normalize").and_then(|x| x.as_bool()).unwrap_or(true);
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
+        let mut sw = Stopwatch::start_new();
+
+        let svg = self.create_diff_flame_graph_svg(&before, &after, stats_type, image_width as usize, normalize, aggregation, &transforms)?;
+        let selection_json = |s: &FlameGraphSelection| json!({
+            "session_id": s.session_id,
+            "thread_ids": s.thread_ids,
+            "start_time": s.start_time,
+            "end_time": s.end_time,
+        });
+        let result = json!({
+            "before": selection_json(&before),
+            "after": selection_json(&after),
+            "stats_type": stats_type,
+            "image_width": image_width,
+            "normalize": normalize,
+            "aggregation": aggregation.to_string(),
+            "transforms": transforms.get_options(),
+            "flame_graph_data": svg
+        });
+        sender.send_response(&cmd, &result);
+        println!("handle_diff_flame_graph_request total cost: {}ms", sw.elapsed_ms());
+        Ok(())
+    }
+
+    //selection options: session_id, thread_id or thread set options, start_time, end_time
+    fn parse_flame_graph_selection(&self, options: &serde_json::Map<String, serde_json::Value>, key: &str) -> io::Result<FlameGraphSelection> {
+        let selection = match options.get(key).and_then(|x| x.as_object()) {
+            Some(x) => x,
+            None => return Err(new_invalid_input_error(&format!("missing option '{}'", key)))
+        };
+        let session_id = get_option_as_str_required(selection, "session_id")?;
+        let thread_ids = self.get_required_thread_ids(session_id, selection)?;
+        Ok(FlameGraphSelection {
+            session_id: session_id.to_string(),
+            thread_ids,
+            start_time: get_option_as_int(selection, "start_time", -1),
+            end_time: get_option_as_int(selection, "end_time", -1),
+        })
+    }
+
+    fn handle_sequenced_call_tree_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let session_id = get_option_as_str_required(options, "session_id")?;
         let thread_id = get_option_as_int(options, "thread_id", -1);
         let start_time = get_option_as_int(options, "start_time", -1);
         let end_time = get_option_as_int(options, "end_time", -1);
         let stats_type = get_option_as_str(options, "stats_type", "duration");
+        let aggregation = AggregationLevel::parse(options)?;
+        let transforms = TransformPipeline::parse(options)?;
         let mut sw = Stopwatch::start_new();
 
         if thread_id <= 0 {
@@ -653,23 +1982,26 @@ impl Profiler {
         }
         let mut new_start_time = start_time;
         let mut new_end_time = end_time;
-        let stacks = self.get_sequenced_call_tree(session_id, thread_id, &mut new_start_time, &mut new_end_time, stats_type)?;
+        let mut stacks = self.get_sequenced_call_tree(session_id, thread_id, &mut new_start_time, &mut new_end_time, stats_type, aggregation)?;
+        transforms.apply_tree(&mut stacks, true);
         let result = json!({
                 "session_id": session_id,
                 "thread_id": thread_id,
                 "start_time": new_start_time,
                 "end_time": new_end_time,
                 "stats_type": stats_type,
+                "aggregation": aggregation.to_string(),
+                "transforms": transforms.get_options(),
                 "sequenced_call_tree_data": stacks
             });
-        let message = wrap_response(&cmd, &result);
+        let message = sender.wrap_response(&cmd, &result);
         sender.send_message(&message);
         println!("handle_sequenced_call_tree_request total cost: {}ms", sw.elapsed_ms());
 
         Ok(())
     }
 
-    fn handle_list_methods_by_filter_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_list_methods_by_filter_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let mut sw = Stopwatch::start_new();
         let session_id = get_option_as_str_required(options, "session_id")?;
         let method_name_filter = get_option_as_str(options, "method_name_filter", "");
@@ -687,13 +2019,13 @@ impl Profiler {
                 "total_method_size": filter_method_size,
                 "method_infos": method_infos
             });
-        let message = wrap_response(&cmd, &result);
+        let message = sender.wrap_response(&cmd, &result);
         sender.send_message(&message);
         println!("handle_list_methods_by_filter_request total cost: {}ms", sw.elapsed_ms());
         Ok(())
     }
 
-    fn handle_search_slow_method_calls_request(&mut self, sender: &mut Writer<std::net::TcpStream>, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
+    fn handle_search_slow_method_calls_request(&self, sender: &RequestContext, cmd: &str, options: &serde_json::Map<String, serde_json::Value>) -> io::Result<()> {
         let mut sw = Stopwatch::start_new();
         let session_id = get_option_as_str_required(options, "session_id")?;
         let mut method_ids = get_option_as_int_array(options, "method_ids")?;
@@ -708,9 +2040,9 @@ impl Profiler {
         //let method_calls : Vec<String> = vec![];
         let mut search_error = false;
         let mut search_error_msg = "".to_string();
-        if let Some(collector) = self.sample_session_map.get(session_id) {
+        if let Ok(mut collector) = self.get_sample_reader(session_id) {
             let mut total = 0;
-            let threads = collector.lock().unwrap().get_threads()?;
+            let threads = collector.get_threads()?;
             let thread_size = threads.len();
             let mut search_progress = 0;
             let mut method_analysis = MethodAnalysis::new();
@@ -722,8 +2054,9 @@ impl Profiler {
                 if !thread_name_filter.is_empty() && !thread.name.contains(thread_name_filter) {
                     continue;
                 }
+                check_cancelled()?;
 
-                match collector.lock().unwrap().search_slow_method_calls(thread.id, &method_ids, min_duration, max_duration) {
+                match collector.search_slow_method_calls(thread.id, &method_ids, min_duration, max_duration) {
                     Ok(method_calls ) => {
                         let search_cost = sw.lap();
                         //send progress
@@ -737,7 +2070,7 @@ impl Profiler {
                                 "search_finished": false,
                                 "search_message": format!("searching {}", thread.name)
                             });
-                            sender.send_message(&wrap_response(&cmd, &result));
+                            sender.send_response(&cmd, &result);
                             println!("search progress: {}%", search_progress);
                         }
 
@@ -768,7 +2101,7 @@ impl Profiler {
             //fill call method name
             for method_group in &mut method_analysis.call_groups {
                 for call in &mut method_group.call_stack {
-                    match collector.lock().unwrap().get_method_info(call.method_id) {
+                    match collector.get_method_info(call.method_id) {
                         Some(val) => {
                             call.full_name = val.full_name.clone();
                         },
@@ -788,7 +2121,7 @@ impl Profiler {
                 "search_error": search_error,
                 "search_message": search_error_msg
             });
-            sender.send_message(&wrap_response(&cmd, &result));
+            sender.send_response(&cmd, &result);
             println!("handle_search_slow_method_calls_request total cost: {}ms", sw.elapsed_ms());
         }else {
             return Err(io::Error::new(ErrorKind::NotFound, "sample session not found"));
@@ -796,16 +2129,33 @@ impl Profiler {
         Ok(())
     }
 
-    pub fn startup(&mut self) {
+    pub fn startup(&self) {
         self.start_ws_server();
         self.start_http_server();
+        self.start_retention_timer();
+    }
+
+    //check retention policy periodically
+    fn start_retention_timer(&self) {
+        let self_ref = self.get_self_ref();
+        thread::spawn(move || {
+            loop {
+                if !self_ref.is_running() {
+                    break;
+                }
+                if let Err(e) = self_ref.prune_recordings() {
+                    println!("prune recordings failed: {}", e);
+                }
+                thread::sleep(std::time::Duration::from_secs(60));
+            }
+        });
     }
 
-    pub fn shutdown(&mut self) {
-        self.running = false;
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
     }
 
     pub fn is_running(&self) -> bool {
-        self.running
+        self.running.load(Ordering::SeqCst)
     }
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::mem::size_of;
+use flare_utils::timeseries::{TSResult, TSRangeValue};
+use tree::TreeNode;
+
+//default memory limit of range cache
+pub const DEFAULT_RANGE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+pub struct RangeCacheKey {
+    pub session_id: String,
+    //data kind: thread_cpu_time, collapsed_stacks, call_tree, sequenced_call_tree
+    pub kind: String,
+    pub thread_ids: Vec<i64>,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub stats_type: String,
+}
+
+impl RangeCacheKey {
+    pub fn new(session_id: &str, kind: &str, thread_ids: &[i64], start_time: i64, end_time: i64, stats_type: &str) -> RangeCacheKey {
+        let mut thread_ids = thread_ids.to_vec();
+        thread_ids.sort();
+        RangeCacheKey {
+            session_id: session_id.to_string(),
+            kind: kind.to_string(),
+            thread_ids,
+            start_time,
+            end_time,
+            stats_type: stats_type.to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum RangeCacheValue {
+    ThreadCpuTime(Arc<TSResult>),
+    CollapsedStacks(Arc<Vec<String>>),
+    CallTree(Arc<TreeNode>),
+}
+
+impl RangeCacheValue {
+    //approximate heap size
+    fn estimate_size(&self) -> usize {
+        match self {
+            RangeCacheValue::ThreadCpuTime(ts) => {
+                size_of::<TSResult>() + match &ts.data {
+                    TSRangeValue::vec_int16(x) => x.len() * 2,
+                    TSRangeValue::vec_int32(x) => x.len() * 4,
+                    TSRangeValue::vec_int64(x) => x.len() * 8,
+                    TSRangeValue::vec_f32(x) => x.len() * 4,
+                }
+            },
+            RangeCacheValue::CollapsedStacks(stacks) => {
+                stacks.iter().map(|s| s.len() + size_of::<String>()).sum()
+            },
+            RangeCacheValue::CallTree(tree) => estimate_tree_size(tree),
+        }
+    }
+}
+
+fn estimate_tree_size(node: &TreeNode) -> usize {
+    let mut size = size_of::<TreeNode>() + size_of::<Box<TreeNode>>() + node.label.len();
+    for child in &node.children {
+        size += estimate_tree_size(child);
+    }
+    size
+}
+
+struct RangeCacheEntry {
+    value: RangeCacheValue,
+    size: usize,
+    last_access: u64,
+    //session record time range when building the value, used to check live session data change
+    record_start_time: i64,
+    last_record_time: i64,
+    //bumped when past data of the session is written, e.g. trigger backfill
+    data_version: u64,
+}
+
+/// Memory bounded LRU cache of range query results, shared by all sessions.
+pub struct RangeCache {
+    capacity_bytes: usize,
+    used_bytes: usize,
+    access_counter: u64,
+    entries: HashMap<RangeCacheKey, RangeCacheEntry>,
+}
+
+impl RangeCache {
+    pub fn new(capacity_bytes: usize) -> RangeCache {
+        RangeCache {
+            capacity_bytes,
+            used_bytes: 0,
+            access_counter: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Get cached value. For a live session new samples arrive after the value was built,
+    /// the entry is stale if its range is not closed before the data it was built from,
+    /// or past data of the session is changed since then.
+    pub fn get(&mut self, key: &RangeCacheKey, record_start_time: i64, last_record_time: i64, data_version: u64) -> Option<RangeCacheValue> {
+        let mut stale = false;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let range_closed = key.end_time >= 0 && key.end_time <= entry.last_record_time;
+            if entry.record_start_time != record_start_time || entry.data_version != data_version || (entry.last_record_time != last_record_time && !range_closed) {
+                stale = true;
+            } else {
+                self.access_counter += 1;
+                entry.last_access = self.access_counter;
+                return Some(entry.value.clone());
+            }
+        }
+        if stale {
+            self.remove(key);
+        }
+        None
+    }
+
+    pub fn put(&mut self, key: RangeCacheKey, value: RangeCacheValue, record_start_time: i64, last_record_time: i64, data_version: u64) {
+        let size = value.estimate_size();
+        self.remove(&key);
+        //too large to cache
+        if size > self.capacity_bytes {
+            return;
+        }
+        while self.used_bytes + size > self.capacity_bytes {
+            if !self.evict_lru() {
+                break;
+            }
+        }
+        self.access_counter += 1;
+        self.used_bytes += size;
+        self.entries.insert(key, RangeCacheEntry {
+            value,
+            size,
+            last_access: self.access_counter,
+            record_start_time,
+            last_record_time,
+            data_version,
+        });
+    }
+
+    pub fn remove(&mut self, key: &RangeCacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used_bytes -= entry.size;
+        }
+    }
+
+    pub fn remove_session(&mut self, session_id: &str) {
+        let keys: Vec<RangeCacheKey> = self.entries.keys().filter(|k| k.session_id == session_id).cloned().collect();
+        for key in &keys {
+            self.remove(key);
+        }
+    }
+
+    fn evict_lru(&mut self) -> bool {
+        let lru_key = self.entries.iter()
+            .min_by_key(|(_, entry)| entry.last_access)
+            .map(|(key, _)| key.clone());
+        match lru_key {
+            Some(key) => {
+                self.remove(&key);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_key(session_id: &str, start_time: i64, end_time: i64) -> RangeCacheKey {
+        RangeCacheKey::new(session_id, "collapsed_stacks", &[2, 1], start_time, end_time, "duration")
+    }
+
+    //size is 10 + size_of::<String>()
+    fn new_value() -> RangeCacheValue {
+        RangeCacheValue::CollapsedStacks(Arc::new(vec!["a;b;c 1000".to_string()]))
+    }
+
+    #[test]
+    fn test_key_thread_order() {
+        assert_eq!(new_key("s1", 0, 100), RangeCacheKey::new("s1", "collapsed_stacks", &[1, 2], 0, 100, "duration"));
+    }
+
+    #[test]
+    fn test_evict_lru() {
+        let value_size = new_value().estimate_size();
+        let mut cache = RangeCache::new(value_size * 2);
+        cache.put(new_key("s1", 0, 100), new_value(), 0, 1000, 0);
+        cache.put(new_key("s1", 100, 200), new_value(), 0, 1000, 0);
+        assert!(cache.get(&new_key("s1", 0, 100), 0, 1000, 0).is_some());
+        //the second entry is least recently used
+        cache.put(new_key("s1", 200, 300), new_value(), 0, 1000, 0);
+        assert!(cache.get(&new_key("s1", 100, 200), 0, 1000, 0).is_none());
+        assert!(cache.get(&new_key("s1", 0, 100), 0, 1000, 0).is_some());
+        assert!(cache.get(&new_key("s1", 200, 300), 0, 1000, 0).is_some());
+        assert_eq!(cache.used_bytes, value_size * 2);
+
+        //too large to cache
+        let mut cache = RangeCache::new(value_size - 1);
+        cache.put(new_key("s1", 0, 100), new_value(), 0, 1000, 0);
+        assert!(cache.get(&new_key("s1", 0, 100), 0, 1000, 0).is_none());
+        assert_eq!(cache.used_bytes, 0);
+    }
+
+    #[test]
+    fn test_live_session_change() {
+        let mut cache = RangeCache::new(DEFAULT_RANGE_CACHE_BYTES);
+        //range is closed before the last record time, new samples do not change it
+        cache.put(new_key("s1", 0, 500), new_value(), 100, 1000, 0);
+        assert!(cache.get(&new_key("s1", 0, 500), 100, 2000, 0).is_some());
+        //open range is stale after new samples
+        cache.put(new_key("s1", 0, -1), new_value(), 100, 1000, 0);
+        assert!(cache.get(&new_key("s1", 0, -1), 100, 1000, 0).is_some());
+        assert!(cache.get(&new_key("s1", 0, -1), 100, 2000, 0).is_none());
+        //data dir is rolled
+        assert!(cache.get(&new_key("s1", 0, 500), 1500, 2000, 0).is_none());
+        assert_eq!(cache.used_bytes, 0);
+    }
+
+    #[test]
+    fn test_past_data_change() {
+        let mut cache = RangeCache::new(DEFAULT_RANGE_CACHE_BYTES);
+        cache.put(new_key("s1", 0, 500), new_value(), 100, 1000, 0);
+        assert!(cache.get(&new_key("s1", 0, 500), 100, 2000, 0).is_some());
+        //closed range is stale after backfill is written into it
+        assert!(cache.get(&new_key("s1", 0, 500), 100, 2000, 1).is_none());
+        assert_eq!(cache.used_bytes, 0);
+    }
+
+    #[test]
+    fn test_remove_session() {
+        let mut cache = RangeCache::new(DEFAULT_RANGE_CACHE_BYTES);
+        cache.put(new_key("s1", 0, 100), new_value(), 0, 1000, 0);
+        cache.put(new_key("s2", 0, 100), new_value(), 0, 1000, 0);
+        cache.remove_session("s1");
+        assert!(cache.get(&new_key("s1", 0, 100), 0, 1000, 0).is_none());
+        assert!(cache.get(&new_key("s2", 0, 100), 0, 1000, 0).is_some());
+        assert_eq!(cache.used_bytes, new_value().estimate_size());
+    }
+}
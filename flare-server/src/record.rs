@@ -0,0 +1,307 @@
+use std::io;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Local;
+use serde_json::Value;
+use aggregation::AggregationLevel;
+use config::ServerConfig;
+use transform::TransformPipeline;
+use utils::*;
+use Profiler;
+
+const DEFAULT_AGENT_PORT: u16 = 3333;
+//max wait time of agent listening after attaching
+const ATTACH_CONNECT_TIMEOUT_SECS: u64 = 15;
+
+const USAGE: &str = "Usage: flare-server record [options]
+Record samples of a jvm without the web ui.
+Options:
+  --agent <addr>           connect to a running flare agent, e.g. localhost:3333
+  --pid <pid>              attach flare agent to the jvm process, and connect to it
+  --port <port>            agent port when attaching, default: 3333
+  --interval <ms>          sample interval when attaching, default: 5
+  --agent-home <dir>       dir of attacher jar and agent lib, default: <install dir>/agent/lib
+  --duration <secs>        record duration, 0 means until Ctrl+C, default: 0
+  --data-dir <dir>         sample data dir, default: flare-samples
+  --flame-graph <file>     write flame graph svg of all threads when finished
+  --hot-methods <file>     write hot methods report when finished
+  --stats-type <type>      stats type of reports: duration, cpu_time or samples, default: duration
+  --top <n>                count of hot methods in report, default: 50
+  -h, --help               print this help";
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Options of headless recording, parsed from 'record' command line args.
+pub struct RecordOptions {
+    pub agent_addr: String,
+    pub target_pid: u32,
+    pub agent_port: u16,
+    pub sample_interval_ms: u32,
+    pub agent_home: String,
+    pub duration_secs: u64,
+    pub data_dir: String,
+    pub flame_graph_file: String,
+    pub hot_methods_file: String,
+    pub stats_type: String,
+    pub top_n: usize,
+}
+
+impl RecordOptions {
+
+    /// Parse args after 'record', return None if '--help' is given.
+    pub fn from_args(args: &[String]) -> io::Result<Option<RecordOptions>> {
+        let mut options = RecordOptions {
+            agent_addr: String::new(),
+            target_pid: 0,
+            agent_port: DEFAULT_AGENT_PORT,
+            sample_interval_ms: 5,
+            agent_home: String::new(),
+            duration_secs: 0,
+            data_dir: ServerConfig::default().data_dir,
+            flame_graph_file: String::new(),
+            hot_methods_file: String::new(),
+            stats_type: "duration".to_string(),
+            top_n: 50,
+        };
+        let mut i = 0;
+        while i < args.len() {
+            let name = args[i].as_str();
+            if name == "-h" || name == "--help" {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            let value = match args.get(i + 1) {
+                Some(value) => value.as_str(),
+                None => return Err(new_invalid_input_error(&format!("missing value of flag: {}\n{}", name, USAGE)))
+            };
+            match name {
+                "--agent" => options.agent_addr = value.to_string(),
+                "--pid" => options.target_pid = parse_flag_value(name, value)?,
+                "--port" => options.agent_port = parse_flag_value(name, value)?,
+                "--interval" => options.sample_interval_ms = parse_flag_value(name, value)?,
+                "--agent-home" => options.agent_home = value.to_string(),
+                "--duration" => options.duration_secs = parse_flag_value(name, value)?,
+                "--data-dir" => options.data_dir = value.to_string(),
+                "--flame-graph" => options.flame_graph_file = value.to_string(),
+                "--hot-methods" => options.hot_methods_file = value.to_string(),
+                "--stats-type" => options.stats_type = value.to_string(),
+                "--top" => options.top_n = parse_flag_value(name, value)?,
+                _ => return Err(new_invalid_input_error(&format!("unknown flag: {}\n{}", name, USAGE)))
+            }
+            i += 2;
+        }
+        if options.agent_addr.is_empty() == (options.target_pid == 0) {
+            return Err(new_invalid_input_error(&format!("one of '--agent' and '--pid' is required\n{}", USAGE)));
+        }
+        if !["duration", "cpu_time", "samples"].contains(&options.stats_type.as_str()) {
+            return Err(new_invalid_input_error(&format!("invalid stats type: {}", options.stats_type)));
+        }
+        Ok(Some(options))
+    }
+}
+
+/// Run 'record' command, return the process exit code.
+pub fn run_record_command(args: &[String]) -> i32 {
+    let options = match RecordOptions::from_args(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => return 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    match record(&options) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("record failed: {}", e);
+            1
+        }
+    }
+}
+
+/// Record samples until the duration is elapsed, Ctrl+C is pressed or the agent is disconnected, then print summary and write reports.
+pub fn record(options: &RecordOptions) -> io::Result<()> {
+    let mut config = ServerConfig::default();
+    config.data_dir = options.data_dir.clone();
+    config.validate()?;
+
+    let agent_addr = if options.target_pid > 0 {
+        attach_agent(options)?
+    } else {
+        options.agent_addr.clone()
+    };
+
+    let profiler = Profiler::with_config(config);
+    let session_id = profiler.connect_agent(&agent_addr, false)?;
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    if options.duration_secs > 0 {
+        println!("recording {} for {} seconds, press Ctrl+C to stop ...", agent_addr, options.duration_secs);
+    } else {
+        println!("recording {}, press Ctrl+C to stop ...", agent_addr);
+    }
+
+    let start = Instant::now();
+    loop {
+        std::thread::sleep(Duration::from_millis(200));
+        if profiler.is_session_disconnected(&session_id) {
+            println!("agent is disconnected: {}", agent_addr);
+            break;
+        }
+        if STOP_REQUESTED.load(Ordering::SeqCst) {
+            println!("recording is interrupted");
+            break;
+        }
+        if options.duration_secs > 0 && start.elapsed() >= Duration::from_secs(options.duration_secs) {
+            break;
+        }
+    }
+    let sample_data_dir = profiler.get_sample_info(&session_id)?.sample_data_dir;
+    profiler.close_session(&session_id)?;
+    if sample_data_dir.is_empty() {
+        return Err(new_error(ErrorKind::NotFound, "no samples are recorded"));
+    }
+
+    //analyze the saved recording, including all rolled dirs
+    let session_id = profiler.open_sample(&sample_data_dir)?;
+    let result = print_summary(&profiler, &session_id)
+        .and_then(|_| write_reports(&profiler, &session_id, options));
+    profiler.close_session(&session_id)?;
+    result
+}
+
+fn print_summary(profiler: &Arc<Profiler>, session_id: &str) -> io::Result<()> {
+    let dashboard = profiler.get_dashboard(session_id)?;
+    let info = &dashboard.sample_info;
+    let total_samples: i64 = dashboard.threads.iter().map(|x| x.sample_count).sum();
+    println!("=======================================================");
+    println!("recording: {}", info.recording_id);
+    println!("sample dir: {}", info.sample_data_dir);
+    println!("jvm pid: {}, main class: {}", info.jvm_pid, info.main_class);
+    println!("duration: {} ms, sample interval: {} ms", info.last_record_time - info.record_start_time, info.sample_interval);
+    println!("threads: {}, samples: {}", dashboard.threads.len(), total_samples);
+
+    let mut threads: Vec<_> = dashboard.threads.iter().collect();
+    threads.sort_by_key(|x| -x.cpu_time);
+    println!("top cpu threads:");
+    for thread in threads.iter().take(10) {
+        println!("  [{}] {}, cpu: {} ms, samples: {}", thread.id, thread.name, thread.cpu_time / 1000_000, thread.sample_count);
+    }
+    println!("=======================================================");
+    Ok(())
+}
+
+fn write_reports(profiler: &Arc<Profiler>, session_id: &str, options: &RecordOptions) -> io::Result<()> {
+    if options.flame_graph_file.is_empty() && options.hot_methods_file.is_empty() {
+        return Ok(());
+    }
+    let thread_ids = profiler.get_all_thread_ids(session_id)?;
+    if !options.flame_graph_file.is_empty() {
+        let transforms = TransformPipeline::parse(&serde_json::Map::new())?;
+        let svg = profiler.create_merged_flame_graph_svg(session_id, &thread_ids, -1, -1, &options.stats_type, 1200, true, false, AggregationLevel::METHOD, &transforms)?;
+        std::fs::write(&options.flame_graph_file, svg)?;
+        println!("write flame graph to: {}", options.flame_graph_file);
+    }
+    if !options.hot_methods_file.is_empty() {
+        let hot_methods = profiler.get_hot_methods(session_id, &thread_ids, -1, -1, &options.stats_type, "self", 0, options.top_n)?;
+        std::fs::write(&options.hot_methods_file, format_hot_methods_report(&hot_methods))?;
+        println!("write hot methods to: {}", options.hot_methods_file);
+    }
+    Ok(())
+}
+
+fn format_hot_methods_report(hot_methods: &Value) -> String {
+    let mut report = format!("Hot methods of {}, stats type: {}, generated at: {}\n",
+                             hot_methods["session_id"].as_str().unwrap_or(""),
+                             hot_methods["stats_type"].as_str().unwrap_or(""),
+                             Local::now().format("%Y-%m-%d %H:%M:%S"));
+    report.push_str(&format!("{:>8} {:>8} {:>10} {:>10}  {}\n", "self%", "total%", "self", "samples", "method"));
+    if let Some(methods) = hot_methods["methods"].as_array() {
+        for method in methods {
+            report.push_str(&format!("{:>8.2} {:>8.2} {:>10} {:>10}  {}\n",
+                                     method["self_percent"].as_f64().unwrap_or(0.0),
+                                     method["total_percent"].as_f64().unwrap_or(0.0),
+                                     get_stats_value(method, hot_methods["stats_type"].as_str().unwrap_or("")),
+                                     method["self_samples"].as_i64().unwrap_or(0),
+                                     method["name"].as_str().unwrap_or("")));
+        }
+    }
+    report
+}
+
+fn get_stats_value(method: &Value, stats_type: &str) -> i64 {
+    let key = match stats_type {
+        "cpu_time" => "self_cpu",
+        "samples" => "self_samples",
+        _ => "self_duration"
+    };
+    method[key].as_i64().unwrap_or(0)
+}
+
+/// Load flare agent into the jvm by the attacher jar (same as start-agent.sh), return the agent addr.
+fn attach_agent(options: &RecordOptions) -> io::Result<String> {
+    let java_home = std::env::var("JAVA_HOME")
+        .map_err(|_| new_invalid_input_error("attaching requires system env: JAVA_HOME"))?;
+    let lib_dir = if options.agent_home.is_empty() { find_agent_lib_dir() } else { PathBuf::from(&options.agent_home) };
+    let attacher_path = lib_dir.join("flare-attacher-jar-with-dependencies.jar");
+    let agent_path = lib_dir.join(get_agent_lib_name());
+    for path in &[&attacher_path, &agent_path] {
+        if !path.is_file() {
+            return Err(new_error(ErrorKind::NotFound, &format!("file not found: {}, see '--agent-home'", path.display())));
+        }
+    }
+
+    let agent_opts = format!("trace=on,interval={},address={}", options.sample_interval_ms, options.agent_port);
+    println!("attaching agent to jvm: {}, agent: {}, options: {}", options.target_pid, agent_path.display(), agent_opts);
+    let java_path = Path::new(&java_home).join("bin").join("java");
+    let status = Command::new(&java_path)
+        .arg(format!("-Xbootclasspath/a:{}", Path::new(&java_home).join("lib").join("tools.jar").display()))
+        .arg("-jar").arg(&attacher_path)
+        .arg(&agent_path).arg(&agent_opts).arg(options.target_pid.to_string())
+        .status()
+        .map_err(|e| new_error(ErrorKind::Other, &format!("run attacher failed: {}, error: {}", java_path.display(), e)))?;
+    if !status.success() {
+        return Err(new_error(ErrorKind::Other, &format!("attach agent to jvm failed: {}, {}", options.target_pid, status)));
+    }
+
+    //wait for agent listening
+    let agent_addr = format!("127.0.0.1:{}", options.agent_port);
+    let start = Instant::now();
+    while TcpStream::connect(&agent_addr).is_err() {
+        if start.elapsed() > Duration::from_secs(ATTACH_CONNECT_TIMEOUT_SECS) {
+            return Err(new_error(ErrorKind::TimedOut, &format!("agent is not listening after attaching: {}", agent_addr)));
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    Ok(agent_addr)
+}
+
+//agent/lib of install dir, the exe is in bin dir
+fn find_agent_lib_dir() -> PathBuf {
+    if let Ok(exe_path) = std::env::current_exe() {
+        if let Some(install_dir) = exe_path.parent().and_then(|x| x.parent()) {
+            return install_dir.join("agent").join("lib");
+        }
+    }
+    PathBuf::from("agent/lib")
+}
+
+fn get_agent_lib_name() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "flareagent.dll"
+    } else if cfg!(target_os = "macos") {
+        "libflareagent.dylib"
+    } else {
+        "libflareagent.so"
+    }
+}
@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use websocket::OwnedMessage;
+use websocket::result::WebSocketResult;
+use websocket::sender::Writer;
+use serde::Serialize;
+use serde_json::json;
+use utils::*;
+
+type JsonValue = serde_json::Value;
+
+//min percent change between two progress messages
+const PROGRESS_STEP_PERCENT: usize = 5;
+
+thread_local! {
+    //request handled by current thread, checked by long running tree builds
+    static CURRENT_REQUEST: RefCell<Option<RequestContext>> = RefCell::new(None);
+}
+
+//ws connection or message buffer of request without connection
+#[derive(Clone)]
+enum ResponseWriter {
+    Ws(Arc<Mutex<Writer<TcpStream>>>),
+    Buffer(Arc<Mutex<Vec<String>>>),
+}
+
+/// Response sender of one request. The optional 'request_id' of the request is echoed in
+/// responses and progress messages, and the request can be cancelled by it.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub cmd: String,
+    pub request_id: Option<JsonValue>,
+    writer: ResponseWriter,
+    cancelled: Arc<AtomicBool>,
+    last_progress: Arc<AtomicUsize>,
+}
+
+impl RequestContext {
+    pub fn new(writer: Arc<Mutex<Writer<TcpStream>>>, cmd: &str, request_id: Option<JsonValue>) -> RequestContext {
+        RequestContext::new_with_writer(ResponseWriter::Ws(writer), cmd, request_id)
+    }
+
+    /// Keep the responses in memory, see take_messages().
+    pub fn new_buffered(cmd: &str, request_id: Option<JsonValue>) -> RequestContext {
+        RequestContext::new_with_writer(ResponseWriter::Buffer(Arc::new(Mutex::new(vec![]))), cmd, request_id)
+    }
+
+    fn new_with_writer(writer: ResponseWriter, cmd: &str, request_id: Option<JsonValue>) -> RequestContext {
+        RequestContext {
+            cmd: cmd.to_string(),
+            request_id,
+            writer,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            last_progress: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Buffered response messages (json text), empty for ws request.
+    pub fn take_messages(&self) -> Vec<String> {
+        match &self.writer {
+            ResponseWriter::Buffer(messages) => std::mem::replace(&mut *messages.lock().unwrap(), vec![]),
+            ResponseWriter::Ws(_) => vec![]
+        }
+    }
+
+    pub fn wrap_response<T: ?Sized>(&self, cmd: &str, value: &T) -> OwnedMessage
+        where T: Serialize {
+        wrap_message("success", cmd, self.request_id.as_ref(), value)
+    }
+
+    pub fn send_message(&self, message: &OwnedMessage) -> WebSocketResult<()> {
+        match &self.writer {
+            ResponseWriter::Ws(writer) => writer.lock().unwrap().send_message(message),
+            ResponseWriter::Buffer(messages) => {
+                if let OwnedMessage::Text(text) = message {
+                    messages.lock().unwrap().push(text.clone());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn send_response<T: ?Sized>(&self, cmd: &str, value: &T) -> WebSocketResult<()>
+        where T: Serialize {
+        self.send_message(&self.wrap_response(cmd, value))
+    }
+
+    pub fn send_error(&self, cmd: &str, message: &str) -> WebSocketResult<()> {
+        self.send_message(&wrap_message("failure", cmd, self.request_id.as_ref(), &json!({ "message": message })))
+    }
+
+    /// Send progress of a long running ws request, only when the client gives a request id.
+    pub fn send_progress(&self, done: usize, total: usize, message: &str) {
+        if self.request_id.is_none() || total == 0 {
+            return;
+        }
+        if let ResponseWriter::Buffer(_) = self.writer {
+            return;
+        }
+        let progress = min(done * 100 / total, 100);
+        if progress < self.last_progress.load(Ordering::SeqCst) + PROGRESS_STEP_PERCENT {
+            return;
+        }
+        self.last_progress.store(progress, Ordering::SeqCst);
+        let data = json!({ "progress": progress, "message": message });
+        if let Err(e) = self.send_message(&wrap_message("progress", &self.cmd, self.request_id.as_ref(), &data)) {
+            println!("send progress failed: {}, cmd: {}, err: {}", progress, self.cmd, e);
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Run f with this request as the current request of the thread.
+    pub fn enter<F, R>(&self, f: F) -> R
+        where F: FnOnce() -> R {
+        CURRENT_REQUEST.with(|x| *x.borrow_mut() = Some(self.clone()));
+        let result = f();
+        CURRENT_REQUEST.with(|x| *x.borrow_mut() = None);
+        result
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CURRENT_REQUEST.with(|x| x.borrow().as_ref().map(|r| r.is_cancelled()).unwrap_or(false))
+}
+
+/// Return an error if the request of current thread is cancelled.
+pub fn check_cancelled() -> io::Result<()> {
+    if is_cancelled() {
+        Err(new_error(ErrorKind::Interrupted, "request is cancelled"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Send progress of the request of current thread.
+pub fn report_progress(done: usize, total: usize, message: &str) {
+    CURRENT_REQUEST.with(|x| {
+        if let Some(request) = x.borrow().as_ref() {
+            request.send_progress(done, total, message);
+        }
+    });
+}
+
+/// In-flight requests with request id, a client can cancel only its own requests.
+pub struct RequestRegistry {
+    requests: HashMap<(usize, String), RequestContext>,
+}
+
+impl RequestRegistry {
+    pub fn new() -> RequestRegistry {
+        RequestRegistry {
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Return false if a request with the same id of the client is in flight.
+    pub fn register(&mut self, client_id: usize, request: &RequestContext) -> bool {
+        let key = match &request.request_id {
+            Some(request_id) => (client_id, request_id.to_string()),
+            None => return true
+        };
+        if self.requests.contains_key(&key) {
+            return false;
+        }
+        self.requests.insert(key, request.clone());
+        true
+    }
+
+    pub fn unregister(&mut self, client_id: usize, request: &RequestContext) {
+        if let Some(request_id) = &request.request_id {
+            let key = (client_id, request_id.to_string());
+            let registered = self.requests.get(&key).map(|x| Arc::ptr_eq(&x.cancelled, &request.cancelled)).unwrap_or(false);
+            if registered {
+                self.requests.remove(&key);
+            }
+        }
+    }
+
+    /// Cancel the in-flight request of the client, return the cmd of it.
+    pub fn cancel(&mut self, client_id: usize, request_id: &JsonValue) -> Option<String> {
+        let request = self.requests.get(&(client_id, request_id.to_string()))?;
+        request.cancel();
+        println!("cancel request: {}, cmd: {}, client: {}", request_id, request.cmd, client_id);
+        Some(request.cmd.clone())
+    }
+
+    /// Cancel all requests of the closed client, return the count.
+    pub fn remove_client(&mut self, client_id: usize) -> usize {
+        let keys: Vec<(usize, String)> = self.requests.keys().filter(|(id, _)| *id == client_id).cloned().collect();
+        for key in &keys {
+            if let Some(request) = self.requests.remove(key) {
+                request.cancel();
+            }
+        }
+        keys.len()
+    }
+}
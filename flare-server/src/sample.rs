@@ -14,11 +14,14 @@ use flare_utils::tuple_indexed::*;
 use flare_utils::tuple_indexed::{TupleIndexedFile, TupleValue};
 use flare_utils::timeseries::{TimeSeries, TSValue, TimeSeriesFileWriter, TimeSeriesFileReader};
 use flare_utils::{ValueType, file_utils};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use utils::*;
 use std::hash::Hash;
-use std::sync::{Mutex, Arc};
-use std::cmp::min;
+use std::sync::{Mutex, Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, TrySendError};
+use std::cmp::{min, max};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use flare_utils::file_utils::open_file;
@@ -27,39 +30,25 @@ use std::ops::{Index, Deref, DerefMut};
 use flare_utils::stopwatch::*;
 use std::str::FromStr;
 use tree;
+use segment::*;
+use archive::PrivacyOptions;
+use aggregation::AggregationLevel;
+use request::{check_cancelled, is_cancelled, report_progress};
+use trigger::{TriggerEngine, TriggerMarker, TriggerPolicy};
+//sample dir format is shared with the flight recorder of agent
+pub use flare_utils::sample_format::{ThreadData, TimeGap, SampleInfo, SummaryInfo};
 
 
 type JavaLong = i64;
 type JavaMethod = i64;
 
 pub const FLARE_SAMPLES_DIR : &str = "flare-samples";
-
-#[derive(Clone, Serialize, Deserialize)]
-pub struct ThreadData {
-    pub id: JavaLong,
-    pub name: String,
-    pub priority: u32,
-    pub daemon: bool,
-    pub state: String,
-    pub cpu_time: i64,
-    pub cpu_time_delta: i64,
-    pub sample_time: i64,
-    #[serde(default = "default_sample_count")]
-    pub sample_count: i64,
-    pub stacktrace: Vec<i64>,
-
-    //dynamic calc attrs
-    #[serde(default)]
-    pub duration: i64,
-    #[serde(default)]
-    pub self_duration: i64,
-    #[serde(default)]
-    pub self_cpu_time: i64
-}
-
-fn default_sample_count() -> i64 {
-    1
-}
+//samples with unresolved methods wait for method info before saving, up to the time or count
+const METHOD_RESOLVE_WAIT_MS: i64 = 3000;
+const MAX_PENDING_EVENTS: usize = 10000;
+//max queued events of a live update subscriber, thread samples are dropped when it lags behind,
+//other events are retained until the subscriber catches up
+const MAX_LISTENER_EVENTS: usize = 10000;
 
 #[derive(Clone, Serialize)]
 pub struct MethodInfo {
@@ -72,6 +61,14 @@ pub struct MethodInfo {
 //    pub line_num: u16
 }
 
+//all method entries of method info file, shared by the collector and its readers
+#[derive(Default)]
+struct MethodEntryCache {
+    entries: Arc<Vec<MethodInfo>>,
+    //method info update time of the entries
+    update_time: i64,
+}
+
 #[derive(Clone, Serialize)]
 pub struct MethodCall {
     pub method_id: i64,
@@ -95,20 +92,155 @@ pub struct DashboardInfo {
     //jvm_info: JvmInfo,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct SampleInfo {
-    pub sample_interval: i64,
-    pub sample_start_time: i64,
-    pub record_start_time: i64,
-    pub last_record_time: i64,
-    pub agent_addr: String,
-    pub sample_data_dir: String,
+/// Incremental update of a live session, sent to subscribers while ingesting samples.
+#[derive(Clone)]
+pub enum SampleEvent {
+    //thread summary of the sample (stacktrace is cleared), and whether it is the first sample of the thread
+    ThreadSample(ThreadData, bool),
+    //agent connection is lost, trying to reconnect
+    Reconnecting,
+    //samples are missing in the time range, closed by the first sample after reconnecting
+    Gap(TimeGap),
+    //a trigger rule is fired
+    Trigger(TriggerMarker),
+    //agent connection is closed
+    Disconnected,
+    //thread samples are dropped as the subscriber lags behind, the session data should be refetched
+    Lagging,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct SummaryInfo {
-    sample_info: SampleInfo,
-    threads: Vec<ThreadData>
+//live update subscriber of the collector
+struct EventListener {
+    sender: SyncSender<SampleEvent>,
+    //a lagging event is queued and not delivered yet
+    lagging: bool,
+    //events waiting for free space of the channel
+    retained: VecDeque<SampleEvent>,
+}
+
+impl EventListener {
+    //never block the ingestion, return false if the receiver is closed
+    fn send(&mut self, event: SampleEvent) -> bool {
+        match event {
+            SampleEvent::ThreadSample(_, false) => {
+                if !self.flush() {
+                    return false;
+                }
+                //thread state is refreshed by the later samples
+                if !self.retained.is_empty() {
+                    self.on_dropped();
+                    return true;
+                }
+                match self.sender.try_send(event) {
+                    Err(TrySendError::Full(_)) => self.on_dropped(),
+                    Err(TrySendError::Disconnected(_)) => return false,
+                    Ok(_) => {}
+                }
+                true
+            },
+            //new threads and state changes are never dropped
+            _ => {
+                self.retained.push_back(event);
+                self.flush()
+            }
+        }
+    }
+
+    fn on_dropped(&mut self) {
+        if !self.lagging {
+            self.lagging = true;
+            self.retained.push_back(SampleEvent::Lagging);
+        }
+    }
+
+    fn flush(&mut self) -> bool {
+        while let Some(event) = self.retained.pop_front() {
+            let is_lagging = match event { SampleEvent::Lagging => true, _ => false };
+            match self.sender.try_send(event) {
+                Ok(_) => if is_lagging { self.lagging = false },
+                Err(TrySendError::Full(event)) => {
+                    self.retained.push_front(event);
+                    break;
+                },
+                Err(TrySendError::Disconnected(_)) => return false
+            }
+        }
+        true
+    }
+}
+
+/// Reconnect lost agent connection of attach session, the delay is doubled after each failed attempt.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    //zero disables reconnecting
+    pub max_retries: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_retries: 10,
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
+        }
+    }
+}
+
+//max memory of a built tree, the tree is coarsened when exceeded
+pub const DEFAULT_MAX_TREE_MEMORY_BYTES: usize = 256 * 1024 * 1024;
+static MAX_TREE_MEMORY_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_TREE_MEMORY_BYTES);
+//approximate memory per node, including method name
+const SEQUENCED_TREE_NODE_SIZE: usize = 160;
+const CALL_TREE_NODE_SIZE: usize = 240;
+
+pub fn set_max_tree_memory(max_bytes: usize) {
+    MAX_TREE_MEMORY_BYTES.store(max_bytes, Ordering::Relaxed);
+}
+
+lazy_static! {
+    //dir of new sample data dirs, default is FLARE_SAMPLES_DIR
+    static ref SAMPLES_DIR: RwLock<String> = RwLock::new(FLARE_SAMPLES_DIR.to_string());
+    static ref RECONNECT_POLICY: RwLock<ReconnectPolicy> = RwLock::new(ReconnectPolicy::default());
+}
+
+pub fn set_reconnect_policy(policy: ReconnectPolicy) {
+    *RECONNECT_POLICY.write().unwrap() = policy;
+}
+
+pub fn get_reconnect_policy() -> ReconnectPolicy {
+    *RECONNECT_POLICY.read().unwrap()
+}
+
+fn connect_with_timeout(addr: &str, timeout: std::time::Duration) -> io::Result<TcpStream> {
+    let mut last_error = new_error(ErrorKind::NotFound, &format!("resolve addr failed: {}", addr));
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e
+        }
+    }
+    Err(last_error)
+}
+
+pub fn set_samples_dir(dir: &str) {
+    *SAMPLES_DIR.write().unwrap() = dir.to_string();
+}
+
+pub fn get_samples_dir() -> String {
+    SAMPLES_DIR.read().unwrap().clone()
+}
+
+fn is_same_dir(dir1: &str, dir2: &str) -> bool {
+    match (std::fs::canonicalize(dir1), std::fs::canonicalize(dir2)) {
+        (Ok(path1), Ok(path2)) => path1 == path2,
+        _ => Path::new(dir1) == Path::new(dir2)
+    }
+}
+
+fn get_max_tree_nodes(node_size: usize) -> usize {
+    MAX_TREE_MEMORY_BYTES.load(Ordering::Relaxed) / node_size
 }
 
 // 统计方式
@@ -124,39 +256,74 @@ pub enum StatsType {
     SAMPLES,
 }
 
+impl StatsType {
+    /// Count unit shown in flame graph.
+    pub fn count_name(&self) -> &'static str {
+        match self {
+            StatsType::DURATION => "ms",
+            StatsType::CPU_TIME => "micros",
+            StatsType::SAMPLES => "samples",
+        }
+    }
+}
+
 pub struct SampleCollector {
     //self ref
     this_ref: Option<Arc<Mutex<SampleCollector>>>,
     connected: bool,
     disconnected: bool,
+    //agent connection is lost, trying to reconnect
+    reconnecting: bool,
+    //last record time before the connection was lost, the gap is closed by the next sample
+    gap_start_time: i64,
+    gaps: Vec<TimeGap>,
+    //conditional recording rules of live session
+    trigger_engine: Option<TriggerEngine>,
+    markers: Vec<TriggerMarker>,
     agent_addr: String,
     agent_stream: Option<TcpStream>,
     readonly: bool,
+    //readonly snapshot of a session for queries
+    snapshot: bool,
     running: bool,
 
     //sample option
     sample_interval: i64,
     sample_start_time: i64,
     sample_type: String,
+    jvm_pid: i64,
+    main_class: String,
 
     //collector
+    recording_id: String,
     record_start_time: i64,
+    segment_start_time: i64,
     last_record_time: i64,
+    //bumped when samples are written into the past, cached range results are rebuilt
+    data_version: u64,
     //last save summary time
     last_save_time: i64,
 
     //sample data processor
     threads : HashMap<JavaLong, ThreadData>,
     sample_data_dir: String,
-    sample_cpu_ts_map: HashMap<JavaLong, Option<Box<TimeSeries+Send>>>,
-    sample_cpu_ts_cache: HashMap<String, Option<Arc<TSResult>>>,
-    sample_stacktrace_map: HashMap<JavaLong, Option<TupleIndexedFile>>,
+    segments: SegmentManager,
     sample_method_idx_file: Option<TupleIndexedFile>,
-    method_cache: HashMap<JavaMethod, Option<MethodInfo>>,
-    method_entries: Vec<MethodInfo>,
-    method_entry_cache_time: i64,
+    //resolved method infos, shared with the snapshot readers
+    method_cache: Arc<RwLock<HashMap<JavaMethod, MethodInfo>>>,
+    //agent jmethodID -> stable method key
+    method_key_map: HashMap<JavaMethod, i64>,
+    //method ids received in stacktrace before method info
+    unresolved_methods: HashSet<JavaMethod>,
+    //method ids saved as raw id after waiting for method info timeout
+    raw_methods: HashSet<JavaMethod>,
+    //agent events in received order, waiting for the method info of the samples
+    pending_events: VecDeque<(i64, Vec<Value>)>,
+    resolve_request_time: i64,
+    method_entry_cache: Arc<RwLock<MethodEntryCache>>,
     method_info_update_time: i64,
-    call_tree_cahce: HashMap<JavaLong, Box<tree::TreeNode>>,
+    //live update subscribers
+    event_listeners: Vec<EventListener>,
 //    tree_arena: TreeArena
 }
 
@@ -169,10 +336,11 @@ impl SampleCollector {
         Ok(collector)
     }
 
-    pub fn open(sample_dir: &str) -> io::Result<Arc<Mutex<SampleCollector>>> {
+    /// Open the sample dir, the other rolled dirs of the recording are loaded as one timeline.
+    pub fn open(sample_dir: &str, recording_dirs: &[String]) -> io::Result<Arc<Mutex<SampleCollector>>> {
         println!("load sample data from dir: {}", sample_dir);
         let mut collector = SampleCollector::new_instance();
-        match collector.lock().unwrap().load_sample(sample_dir) {
+        match collector.lock().unwrap().load_sample(sample_dir, recording_dirs) {
             Ok(_) => {},
             Err(e) => {
                 println!("load sample failed: {:?}", e);
@@ -187,29 +355,42 @@ impl SampleCollector {
         let mut collector = Arc::new(Mutex::new(SampleCollector {
             this_ref: None,
             readonly: false,
+            snapshot: false,
             running: true,
             sample_type: "".to_string(),
             sample_interval: 20,
             sample_start_time: 0,
+            jvm_pid: 0,
+            main_class: "".to_string(),
+            recording_id: "".to_string(),
             record_start_time: 0,
+            segment_start_time: 0,
             last_record_time: 0,
+            data_version: 0,
             last_save_time: 0,
             threads: HashMap::new(),
             sample_data_dir: "".to_string(),
-            sample_cpu_ts_map: HashMap::new(),
-            sample_cpu_ts_cache: Default::default(),
-            sample_stacktrace_map: HashMap::new(),
+            segments: SegmentManager::new(),
             sample_method_idx_file: None,
             connected: false,
             disconnected: false,
+            reconnecting: false,
+            gap_start_time: 0,
+            gaps: vec![],
+            trigger_engine: None,
+            markers: vec![],
             agent_addr: "".to_string(),
             agent_stream: None,
-            method_cache: HashMap::new(),
+            method_cache: Arc::new(RwLock::new(HashMap::new())),
+            method_key_map: HashMap::new(),
+            unresolved_methods: HashSet::new(),
+            raw_methods: HashSet::new(),
+            pending_events: VecDeque::new(),
+            resolve_request_time: 0,
 //            tree_arena: TreeArena::new()
-            method_entries: vec![],
-            method_entry_cache_time: 0,
+            method_entry_cache: Arc::new(RwLock::new(MethodEntryCache::default())),
             method_info_update_time: 0,
-            call_tree_cahce: Default::default()
+            event_listeners: vec![],
         }));
         //self ref for threads
         collector.lock().unwrap().this_ref = Some(collector.clone());
@@ -219,7 +400,10 @@ impl SampleCollector {
     pub fn close(&mut self) {
 //        if self.running {
 //        }
+        self.release_pending_events(true);
         self.running = false;
+        self.reconnecting = false;
+        self.trigger_engine = None;
         //release self ref 必须释放自引用，否则不会释放此对象，打开的文件句柄也不会自动关闭
         self.this_ref = None;
         //close agent connection
@@ -232,14 +416,61 @@ impl SampleCollector {
             stream.shutdown(Shutdown::Both);
         }
         self.agent_stream = None;
+        //stop subscribers
+        self.event_listeners.clear();
     }
 
     pub fn is_disconnected(&self) -> bool {
         self.disconnected
     }
 
+    /// Readonly snapshot of the data flushed so far, analysis queries run on it without holding
+    /// the collector lock, so building trees never blocks the ingestion of a live session.
+    /// Indexes and method maps are shared rather than copied, only the thread list is cloned.
+    pub fn create_reader(&self) -> SampleCollector {
+        SampleCollector {
+            this_ref: None,
+            connected: self.connected,
+            disconnected: self.disconnected,
+            reconnecting: self.reconnecting,
+            gap_start_time: self.gap_start_time,
+            gaps: self.gaps.clone(),
+            trigger_engine: None,
+            markers: self.markers.clone(),
+            agent_addr: self.agent_addr.clone(),
+            agent_stream: None,
+            readonly: true,
+            snapshot: true,
+            running: false,
+            sample_interval: self.sample_interval,
+            sample_start_time: self.sample_start_time,
+            sample_type: self.sample_type.clone(),
+            jvm_pid: self.jvm_pid,
+            main_class: self.main_class.clone(),
+            recording_id: self.recording_id.clone(),
+            record_start_time: self.record_start_time,
+            segment_start_time: self.segment_start_time,
+            last_record_time: self.last_record_time,
+            data_version: self.data_version,
+            last_save_time: self.last_save_time,
+            threads: self.threads.clone(),
+            sample_data_dir: self.sample_data_dir.clone(),
+            segments: self.segments.snapshot(),
+            sample_method_idx_file: self.sample_method_idx_file.as_ref().map(|x| x.new_snapshot_reader()),
+            method_cache: Arc::clone(&self.method_cache),
+            method_key_map: HashMap::new(),
+            unresolved_methods: HashSet::new(),
+            raw_methods: HashSet::new(),
+            pending_events: VecDeque::new(),
+            resolve_request_time: 0,
+            method_entry_cache: self.method_entry_cache.clone(),
+            method_info_update_time: self.method_info_update_time,
+            event_listeners: vec![],
+        }
+    }
+
     //加载取样数据
-    fn load_sample(&mut self, sample_data_dir: &str) -> io::Result<()> {
+    fn load_sample(&mut self, sample_data_dir: &str, recording_dirs: &[String]) -> io::Result<()> {
         self.readonly = true;
         self.sample_type = "file".to_string();
         self.sample_data_dir = sample_data_dir.to_string();
@@ -254,52 +485,76 @@ impl SampleCollector {
         self.sample_data_dir = sample_data_dir.to_string();
 
         //summary info
-        let path = format!("{}/summary_info.json", sample_data_dir);
-        let json = std::fs::read_to_string(path)?;
-
-        let summary = serde_json::from_str::<SummaryInfo>(&json);
-        if summary.is_err() {
-            println!("load summary info json failed: {}", summary.err().unwrap());
-            return Err(new_error(ErrorKind::InvalidData, "load summary info json failed"));
-        }
-        let summary = summary.unwrap();
+        let summary = match load_summary_info(sample_data_dir) {
+            Ok(summary) => summary,
+            Err(e) => {
+                println!("load summary info json failed: {}", e);
+                return Err(new_error(ErrorKind::InvalidData, "load summary info json failed"));
+            }
+        };
 
         let sample_info = &summary.sample_info;
         self.sample_start_time = sample_info.sample_start_time;
         self.sample_interval = sample_info.sample_interval;
         self.agent_addr = sample_info.agent_addr.clone();
+        self.jvm_pid = sample_info.jvm_pid;
+        self.main_class = sample_info.main_class.clone();
+        self.recording_id = sample_info.recording_id.clone();
         self.record_start_time = sample_info.record_start_time;
+        self.segment_start_time = sample_info.segment_start_time;
         self.last_record_time = sample_info.last_record_time;
 
-        //threads
-        for thread in &summary.threads {
-            self.threads.insert(thread.id, thread.clone());
-
-            //load cpu time ts
-            let thread_cpu_ts_file = format!("{}/thread_{}_cpu_time", sample_data_dir, thread.id);
-            match TimeSeriesFileReader::new(&thread_cpu_ts_file) {
-                Ok(ts) => {
-                    self.sample_cpu_ts_map.insert(thread.id, Some(Box::new(ts)));
-                },
-                Err(e) => {
-                    println!("load thread cpu time file failed: {}, err: {}", thread_cpu_ts_file, e);
+        //load all rolled dirs of the recording as one timeline
+        let mut segment_summaries = vec![];
+        if !self.recording_id.is_empty() {
+            for dir in recording_dirs {
+                if is_same_dir(dir, sample_data_dir) {
+                    continue;
+                }
+                match load_summary_info(dir) {
+                    //dirs of other recordings are ignored
+                    Ok(ref summary) if summary.sample_info.recording_id != self.recording_id => {},
+                    Ok(summary) => segment_summaries.push((dir.to_string(), summary)),
+                    Err(e) => println!("load summary info failed: {}, err: {}", dir, e)
                 }
             }
+        }
+        segment_summaries.push((sample_data_dir.to_string(), summary));
+        segment_summaries.sort_by_key(|(_, summary)| summary.sample_info.segment_start_time);
 
-            //load thread stacktrace
-            let thread_stack_file = format!("{}/thread_{}_stack", sample_data_dir, thread.id);
-            match TupleIndexedFile::new_reader(&thread_stack_file) {
-                Ok(file) => {
-                    self.sample_stacktrace_map.insert(thread.id, Some(file));
-                },
-                Err(e) => {
-                    println!("load thread stacktrace file failed: {}, err: {}", thread_stack_file, e);
+        for (dir, summary) in &segment_summaries {
+            let sample_info = &summary.sample_info;
+            if sample_info.record_start_time > 0 {
+                self.record_start_time = min(self.record_start_time, sample_info.record_start_time);
+            }
+            self.last_record_time = max(self.last_record_time, sample_info.last_record_time);
+            //gaps are copied to the rolled dir
+            for gap in &sample_info.gaps {
+                if !self.gaps.iter().any(|x| x.start_time == gap.start_time) {
+                    self.gaps.push(*gap);
                 }
             }
+            for marker in &sample_info.markers {
+                match self.markers.iter_mut().find(|x| x.time == marker.time && x.rule_name == marker.rule_name) {
+                    //window is extended in later dirs
+                    Some(x) => x.window_end = max(x.window_end, marker.window_end),
+                    None => self.markers.push(marker.clone())
+                }
+            }
+
+            //threads, later segment has newer state
+            for thread in &summary.threads {
+                self.threads.insert(thread.id, thread.clone());
+            }
+
+            self.segments.add_segment(SampleSegment::open(dir, summary));
+        }
+        if segment_summaries.len() > 1 {
+            println!("load {} rolled sample dirs of recording: {}", segment_summaries.len(), self.recording_id);
         }
 
-        //method info idx file
-        let method_idx_path = format!("{}/method_info", sample_data_dir);
+        //method info idx file, method info is copied to the next dir when rolling, the last one is complete
+        let method_idx_path = format!("{}/method_info", segment_summaries.last().unwrap().0);
         let mut method_idx_file = TupleIndexedFile::new_writer(&method_idx_path, ValueType::INT64)?;
         self.sample_method_idx_file = Some(method_idx_file);
         let now = Local::now().timestamp_millis();
@@ -320,46 +575,57 @@ impl SampleCollector {
     //按周期滚动更换数据保存目录
     fn check_and_roll_data_dir(&mut self, sample_time: i64) -> io::Result<bool> {
         //采样文件最大时间周期
-        if self.record_start_time==0 || sample_time - self.record_start_time > 900_000 {
-            //create sample data dir
-            let now = Local::now();
-            let now_time = now.format("%Y%m%dT%H%M%S").to_string();
-            let sample_data_dir = format!("{}/{}-{}", FLARE_SAMPLES_DIR, self.agent_addr.replace(":","_"), now_time);
-            std::fs::create_dir_all(sample_data_dir.clone())?;
-            println!("save sample data to dir: {}", sample_data_dir);
-
-            //method info idx file
-            let method_idx_path = format!("{}/method_info", sample_data_dir);
-            if self.sample_method_idx_file.is_some() {
-                //copy old method info file to new dir
-                let old_method_file = format!("{}/method_info.fidx", self.sample_data_dir);
-                let new_method_file = format!("{}/method_info.fidx", sample_data_dir);
-                std::fs::copy(old_method_file, new_method_file)?;
-
-                let old_method_file = format!("{}/method_info.fdata", self.sample_data_dir);
-                let new_method_file = format!("{}/method_info.fdata", sample_data_dir);
-                std::fs::copy(old_method_file, new_method_file)?;
-            }
-            let mut method_idx_file = TupleIndexedFile::new_writer(&method_idx_path, ValueType::INT64)?;
-
-            self.record_start_time = sample_time;
-            self.sample_data_dir = sample_data_dir;
-            self.sample_method_idx_file = Some(method_idx_file);
-            let now = Local::now().timestamp_millis();
-            self.method_info_update_time = now;
-            self.sample_cpu_ts_map.clear();
-            self.sample_stacktrace_map.clear();
-            self.sample_cpu_ts_cache.clear();
-            //reset sample count
-            for thread in self.threads.values_mut() {
-                thread.sample_count = 0;
-            }
+        if self.segment_start_time==0 || sample_time - self.segment_start_time > get_roll_period() {
+            self.roll_data_dir(sample_time)?;
             Ok(true)
         }else {
             Ok(false)
         }
     }
 
+    //seal the active segment and save samples to a new data dir
+    fn roll_data_dir(&mut self, sample_time: i64) -> io::Result<()> {
+        //create sample data dir
+        let now = Local::now();
+        let now_time = now.format("%Y%m%dT%H%M%S").to_string();
+        let sample_data_dir = format!("{}/{}-{}", get_samples_dir(), self.agent_addr.replace(":","_"), now_time);
+        std::fs::create_dir_all(sample_data_dir.clone())?;
+        println!("save sample data to dir: {}", sample_data_dir);
+
+        //method info idx file
+        let method_idx_path = format!("{}/method_info", sample_data_dir);
+        if self.sample_method_idx_file.is_some() {
+            //copy old method info file to new dir
+            let old_method_file = format!("{}/method_info.fidx", self.sample_data_dir);
+            let new_method_file = format!("{}/method_info.fidx", sample_data_dir);
+            std::fs::copy(old_method_file, new_method_file)?;
+
+            let old_method_file = format!("{}/method_info.fdata", self.sample_data_dir);
+            let new_method_file = format!("{}/method_info.fdata", sample_data_dir);
+            std::fs::copy(old_method_file, new_method_file)?;
+        }
+        let mut method_idx_file = TupleIndexedFile::new_writer(&method_idx_path, ValueType::INT64)?;
+
+        //save final summary of old dir, and keep it readable as a part of the recording timeline
+        if self.segment_start_time != 0 {
+            self.last_save_time = 0;
+            self.save_summary_info();
+            self.segments.seal_active_segment();
+        }
+        if self.record_start_time == 0 {
+            self.record_start_time = sample_time;
+            self.recording_id = Path::new(&sample_data_dir).file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+        }
+        self.segment_start_time = sample_time;
+        self.segments.add_segment(SampleSegment::new(&sample_data_dir, sample_time));
+        self.sample_data_dir = sample_data_dir;
+        self.sample_method_idx_file = Some(method_idx_file);
+        let now = Local::now().timestamp_millis();
+        self.method_info_update_time = now;
+        self.last_save_time = 0;
+        Ok(())
+    }
+
     fn save_summary_info(&mut self) -> io::Result<()> {
         if self.readonly {
             return Ok(());
@@ -410,37 +676,205 @@ impl SampleCollector {
         }
     }
 
-    pub fn subscribe_events(&mut self) -> Result<bool, Error> {
+    pub fn subscribe_events(&mut self, replay: bool) -> Result<bool, Error> {
         let mut stream = self.connect_agent()?;
-        let cmdValue = resp::Value::Array(vec![Value::String("subscribe-events".to_string())]);
-        let cmd = cmdValue.encode();
-        let size = stream.write(cmd.as_slice()).unwrap();
-        println!("start subscribe events, awaiting reply: {}", cmdValue.to_encoded_string()?);
+        SampleCollector::send_subscribe_request(&mut stream, replay)?;
 
         if let Some(this_ref) = &self.this_ref {
             self.agent_stream = Some(stream.try_clone()?);
             let this = this_ref.clone();
             std::thread::spawn(move ||{
-                let mut decoder = resp::Decoder::with_buf_bulk(BufReader::new(stream));
-                while match decoder.decode() {
-                    Ok(data) => {
-                        this.lock().unwrap().on_sample_data(data)
-                    },
-                    Err(e) => {
-                        println!("Failed to receive data: {}", e);
-                        false
-                    }
-                }{}
-                println!("subscribe events is stopped.");
-                this.lock().unwrap().on_disconnected();
+                SampleCollector::receive_events(this, stream);
             });
         }
         Ok(true)
     }
 
+    //replay: agent sends the samples of flight recorder before live samples
+    fn send_subscribe_request(stream: &mut TcpStream, replay: bool) -> io::Result<()> {
+        let mut request = vec![Value::String("subscribe-events".to_string())];
+        if replay {
+            request.push(Value::String("replay".to_string()));
+            request.push(Value::Integer(1));
+        }
+        let cmdValue = resp::Value::Array(request);
+        let cmd = cmdValue.encode();
+        stream.write_all(cmd.as_slice())?;
+        println!("start subscribe events, awaiting reply: {}", cmdValue.to_encoded_string()?);
+        Ok(())
+    }
+
+    //receive sample data until the session is closed or reconnecting is failed
+    fn receive_events(this: Arc<Mutex<SampleCollector>>, stream: TcpStream) {
+        let mut stream = stream;
+        loop {
+            let mut decoder = resp::Decoder::with_buf_bulk(BufReader::new(stream));
+            while match decoder.decode() {
+                Ok(data) => {
+                    this.lock().unwrap().on_sample_data(data)
+                },
+                Err(e) => {
+                    println!("Failed to receive data: {}", e);
+                    false
+                }
+            }{}
+            println!("subscribe events is stopped.");
+            match SampleCollector::reconnect(&this) {
+                Some(new_stream) => stream = new_stream,
+                None => break
+            }
+        }
+        this.lock().unwrap().on_disconnected();
+    }
+
+    //reconnect to agent with exponential backoff, the agent resends sample info and method cache after subscribing
+    fn reconnect(this: &Arc<Mutex<SampleCollector>>) -> Option<TcpStream> {
+        let policy = get_reconnect_policy();
+        let agent_addr = {
+            let mut collector = this.lock().unwrap();
+            if !collector.running || collector.sample_type != "attach" || policy.max_retries == 0 {
+                return None;
+            }
+            collector.agent_stream = None;
+            collector.release_pending_events(true);
+            collector.reconnecting = true;
+            if collector.gap_start_time == 0 {
+                //no sample is recorded yet, the gap starts at the disconnection
+                collector.gap_start_time = if collector.last_record_time > 0 { collector.last_record_time } else { Local::now().timestamp_millis() };
+            }
+            collector.last_save_time = 0;
+            collector.save_summary_info();
+            collector.publish_event(SampleEvent::Reconnecting);
+            collector.agent_addr.clone()
+        };
+
+        let mut delay_ms = policy.initial_delay_ms;
+        for attempt in 1..=policy.max_retries {
+            println!("reconnecting to agent: {} after {} ms, attempt: {}/{}", agent_addr, delay_ms, attempt, policy.max_retries);
+            let deadline = Instant::now() + std::time::Duration::from_millis(delay_ms);
+            while Instant::now() < deadline {
+                if !this.lock().unwrap().running {
+                    return None;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+
+            //connect without holding the collector lock
+            let result = connect_with_timeout(&agent_addr, std::time::Duration::from_millis(max(delay_ms, 3000)))
+                .and_then(|mut stream| {
+                    //samples before disconnected are saved, the lost time range is recorded as a gap
+                    SampleCollector::send_subscribe_request(&mut stream, false)?;
+                    Ok(stream)
+                });
+            match result {
+                Ok(stream) => {
+                    let mut collector = this.lock().unwrap();
+                    if !collector.running {
+                        stream.shutdown(Shutdown::Both);
+                        return None;
+                    }
+                    match stream.try_clone() {
+                        Ok(agent_stream) => collector.agent_stream = Some(agent_stream),
+                        Err(e) => println!("clone agent stream failed: {}", e)
+                    }
+                    collector.reconnecting = false;
+                    println!("reconnect to agent: {} successful", agent_addr);
+                    return Some(stream);
+                },
+                Err(e) => {
+                    println!("reconnect to agent failed: {}, error: {}", agent_addr, e);
+                }
+            }
+            delay_ms = min(delay_ms * 2, policy.max_delay_ms);
+        }
+        println!("give up reconnecting to agent: {}", agent_addr);
+        this.lock().unwrap().reconnecting = false;
+        None
+    }
+
     fn on_disconnected(&mut self) {
+        self.release_pending_events(true);
         self.running = false;
         self.disconnected = true;
+        self.publish_event(SampleEvent::Disconnected);
+        //subscribers see disconnected even if the event is dropped
+        self.event_listeners.clear();
+    }
+
+    fn on_gap_closed(&mut self, end_time: i64) {
+        let gap = TimeGap { start_time: self.gap_start_time, end_time };
+        println!("samples are missing in time range: {} - {}, {} ms", gap.start_time, gap.end_time, gap.end_time - gap.start_time);
+        self.gap_start_time = 0;
+        self.gaps.push(gap);
+        self.last_save_time = 0;
+        self.save_summary_info();
+        self.publish_event(SampleEvent::Gap(gap));
+    }
+
+    /// Set conditional recording rules of the live session, None saves all samples again.
+    pub fn set_trigger_policy(&mut self, policy: Option<TriggerPolicy>) -> io::Result<()> {
+        if self.readonly || !self.running {
+            return Err(new_invalid_input_error("trigger rules require a live session"));
+        }
+        self.trigger_engine = None;
+        if let Some(policy) = policy {
+            policy.validate()?;
+            let mut engine = TriggerEngine::new(policy);
+            //methods received before the rules
+            if engine.has_method_rules() {
+                //method names may be still in write buffer
+                if let Some(method_idx_file) = self.sample_method_idx_file.as_mut() {
+                    method_idx_file.flush()?;
+                }
+                let method_keys: Vec<i64> = self.method_key_map.values().cloned().collect();
+                for method_key in method_keys {
+                    let method_name = self.get_method_name(method_key);
+                    engine.on_method_info(method_key, &method_name);
+                }
+            }
+            println!("set trigger rules: {}, rules: {}", self.agent_addr, engine.get_policy().rules.len());
+            self.trigger_engine = Some(engine);
+        }
+        Ok(())
+    }
+
+    pub fn get_trigger_policy(&self) -> Option<TriggerPolicy> {
+        self.trigger_engine.as_ref().map(|engine| engine.get_policy().clone())
+    }
+
+    pub fn get_trigger_markers(&self) -> Vec<TriggerMarker> {
+        self.markers.clone()
+    }
+
+    fn on_trigger_fired(&mut self, marker: TriggerMarker) {
+        self.markers.push(marker.clone());
+        self.last_save_time = 0;
+        self.publish_event(SampleEvent::Trigger(marker));
+    }
+
+    /// Receive incremental updates of the live session, the receiver is disconnected when the session is closed.
+    pub fn add_event_listener(&mut self) -> Receiver<SampleEvent> {
+        let (sender, receiver) = sync_channel(MAX_LISTENER_EVENTS);
+        if self.disconnected {
+            //the sender is dropped, the receiver sees disconnected after the event
+            sender.try_send(SampleEvent::Disconnected);
+        } else {
+            self.event_listeners.push(EventListener { sender, lagging: false, retained: VecDeque::new() });
+        }
+        receiver
+    }
+
+    fn publish_event(&mut self, event: SampleEvent) {
+        //remove closed receivers
+        let mut closed = vec![];
+        for (i, listener) in self.event_listeners.iter_mut().enumerate() {
+            if !listener.send(event.clone()) {
+                closed.push(i);
+            }
+        }
+        for i in closed.into_iter().rev() {
+            self.event_listeners.remove(i);
+        }
     }
 
     fn on_sample_data(&mut self, sample_data: resp::Value) -> bool {
@@ -449,14 +883,21 @@ impl SampleCollector {
         }
         //println!("events: \n{}", sample_data.to_string_pretty());
         if let resp::Value::Array(data_vec) = sample_data {
-            if let Value::String(cmd) = &data_vec[0] {
-                if cmd == "method" {
-                    self.on_method_data(&data_vec);
-                } else if cmd == "thread" {
-                    self.on_thread_data(&data_vec);
-                } else if cmd == "sample_info" {
-                    self.on_sample_info_data(&data_vec);
-                }
+            let cmd = match &data_vec[0] {
+                Value::String(cmd) => cmd.clone(),
+                _ => String::new()
+            };
+            if cmd == "method" {
+                self.on_method_data(&data_vec);
+                self.release_pending_events(false);
+            } else if cmd == "sample_info" {
+                //method ids of the pending samples may belong to the old jvm
+                self.release_pending_events(true);
+                self.on_sample_info_data(&data_vec);
+            } else if cmd == "thread" || cmd == "thread_start" || cmd == "thread_end" {
+                //thread events are kept in order with the pending samples
+                self.pending_events.push_back((Local::now().timestamp_millis(), data_vec));
+                self.release_pending_events(false);
             }
         }
 
@@ -464,21 +905,115 @@ impl SampleCollector {
         true
     }
 
+    //process the pending events in order until a sample is waiting for method info
+    fn release_pending_events(&mut self, force: bool) {
+        let now = Local::now().timestamp_millis();
+        while let Some((receive_time, data_vec)) = self.pending_events.front() {
+            let ready = force || now - receive_time > METHOD_RESOLVE_WAIT_MS
+                || self.pending_events.len() > MAX_PENDING_EVENTS
+                || SampleCollector::is_methods_resolved(&self.method_key_map, &mut self.unresolved_methods, data_vec);
+            if !ready {
+                self.request_resolve_methods(now);
+                break;
+            }
+            let (_, data_vec) = self.pending_events.pop_front().unwrap();
+            match &data_vec[0] {
+                Value::String(cmd) if cmd == "thread" => {
+                    if let Err(e) = self.on_thread_data(&data_vec) {
+                        println!("save thread sample failed: {}", e);
+                    }
+                },
+                Value::String(cmd) if cmd == "thread_start" => self.on_thread_start_data(&data_vec),
+                Value::String(cmd) if cmd == "thread_end" => self.on_thread_end_data(&data_vec),
+                _ => {}
+            }
+        }
+    }
+
+    //check the method keys of sample stacktrace, the missing method ids are requested from agent
+    fn is_methods_resolved(method_key_map: &HashMap<JavaMethod, i64>, unresolved_methods: &mut HashSet<JavaMethod>, data_vec: &Vec<Value>) -> bool {
+        let mut resolved = true;
+        if let Some(Value::Array(stacktrace)) = get_resp_property(data_vec, "stacktrace", 1) {
+            for frame in stacktrace {
+                if let Value::Integer(method_id) = frame {
+                    if !method_key_map.contains_key(method_id) {
+                        unresolved_methods.insert(*method_id);
+                        resolved = false;
+                    }
+                }
+            }
+        }
+        resolved
+    }
+
     fn on_sample_info_data(&mut self, data_vec: &Vec<Value>) {
         let start_time= get_resp_property_as_int(data_vec, "start_time", 1, 0);
         let sample_interval= get_resp_property_as_int(data_vec, "sample_interval", 1, 0);
         let last_sample_time= get_resp_property_as_int(data_vec, "last_sample_time", 1, 0);
+        let jvm_pid = get_resp_property_as_int(data_vec, "pid", 1, 0);
+        let jvm_changed = self.jvm_pid != 0 && jvm_pid != self.jvm_pid;
+        if jvm_changed {
+            //jvm is restarted while reconnecting, jmethodIDs of the old jvm are invalid
+            println!("agent jvm is changed: {} -> {}, reset method ids", self.jvm_pid, jvm_pid);
+            self.method_key_map.clear();
+            self.method_cache.write().unwrap().clear();
+            self.unresolved_methods.clear();
+            self.raw_methods.clear();
+            //thread ids and cpu time of the new jvm start over, save them to a new segment
+            let roll_time = if last_sample_time > 0 { last_sample_time } else { Local::now().timestamp_millis() };
+            if let Err(e) = self.roll_data_dir(roll_time) {
+                println!("roll sample data dir failed: {}", e);
+            }
+            self.threads.clear();
+            //per thread states of trigger rules belong to the old jvm, methods are matched again when resent
+            if let Some(engine) = self.trigger_engine.take() {
+                self.trigger_engine = Some(TriggerEngine::new(engine.get_policy().clone()));
+            }
+        }
         self.sample_start_time = start_time;
         self.sample_interval = sample_interval;
+        self.jvm_pid = jvm_pid;
+        self.main_class = get_resp_property_as_str(data_vec, "main_class", 1, "").to_string();
         println!("on sample info: start_time:{}, sample_interval:{}", start_time, sample_interval);
 
-        self.check_and_roll_data_dir(last_sample_time);
+        if jvm_changed {
+            self.last_save_time = 0;
+            self.save_summary_info();
+        } else {
+            self.check_and_roll_data_dir(last_sample_time);
+        }
     }
 
     fn on_method_data(&mut self, data_vec: &Vec<Value>) {
         if let Some(Value::Integer(method_id)) = get_resp_property(data_vec, "id", 1) {
             if let Some(Value::String(method_name)) = get_resp_property(data_vec, "name", 1) {
-                self.save_method_info(*method_id, method_name);
+                let class_name = get_resp_property_as_str(data_vec, "class", 1, "");
+                let method = get_resp_property_as_str(data_vec, "method", 1, "");
+                let signature = get_resp_property_as_str(data_vec, "signature", 1, "");
+                //jmethodID changes between jvm runs and may be reused after class unloading,
+                //so samples are saved with the stable method key
+                self.unresolved_methods.remove(method_id);
+                if self.raw_methods.remove(method_id) {
+                    //samples saved before method info arrived use raw method id
+                    self.save_method_info(*method_id, method_name);
+                    self.method_cache.write().unwrap().remove(method_id);
+                }
+                let method_key = if class_name.is_empty() {
+                    //old agent without method components
+                    hash_method_key(method_name, "", "")
+                } else {
+                    hash_method_key(class_name, method, signature)
+                };
+                if let Some(engine) = self.trigger_engine.as_mut() {
+                    engine.on_method_info(method_key, method_name);
+                    engine.on_method_info(*method_id, method_name);
+                }
+                match self.method_key_map.insert(*method_id, method_key) {
+                    Some(old_key) if old_key == method_key => return,
+                    Some(old_key) => println!("method id {} is reused, method key: {} -> {}, name: {}", method_id, old_key, method_key, method_name),
+                    None => {}
+                }
+                self.save_method_info(method_key, method_name);
             }else {
                 println!("parse method name failed")
             }
@@ -487,6 +1022,49 @@ impl SampleCollector {
         }
     }
 
+    fn on_thread_start_data(&mut self, data_vec: &Vec<Value>) {
+        let start_time = get_resp_property_as_int(data_vec, "time", 1, 0);
+        let thread_id = get_resp_property_as_int(data_vec, "id", 1, 0);
+        let name = get_resp_property_as_str(data_vec, "name", 1, "");
+        let priority = get_resp_property_as_int(data_vec, "priority", 1, 0);
+        let daemon = get_resp_property_as_int(data_vec, "daemon", 1, 0);
+
+        let thread_data = self.threads.entry(thread_id).or_insert_with(|| {
+            ThreadData::new(thread_id, name)
+        });
+        thread_data.name = name.to_string();
+        thread_data.priority = priority as u32;
+        thread_data.daemon = daemon != 0;
+        thread_data.start_time = start_time;
+        thread_data.end_time = 0;
+        thread_data.lifetime = 0;
+    }
+
+    fn on_thread_end_data(&mut self, data_vec: &Vec<Value>) {
+        let end_time = get_resp_property_as_int(data_vec, "time", 1, 0);
+        let thread_id = get_resp_property_as_int(data_vec, "id", 1, 0);
+        let lifetime = get_resp_property_as_int(data_vec, "lifetime", 1, 0);
+
+        let mut remove = false;
+        if let Some(thread_data) = self.threads.get_mut(&thread_id) {
+            //thread is started before agent loaded, use the first sample time
+            if thread_data.start_time == 0 {
+                thread_data.start_time = if lifetime > 0 { end_time - lifetime } else { thread_data.sample_time };
+            }
+            thread_data.end_time = end_time;
+            thread_data.lifetime = end_time - thread_data.start_time;
+            thread_data.state = "TERMINATED".to_string();
+            //short-lived thread without any sample data
+            remove = thread_data.sample_count <= 0;
+        }
+        if remove {
+            self.threads.remove(&thread_id);
+        }
+        if let Some(engine) = self.trigger_engine.as_mut() {
+            engine.on_thread_end(thread_id);
+        }
+    }
+
     fn on_thread_data(&mut self, data_vec: &Vec<Value>) -> io::Result<()> {
         //let map = parse_resp_properties(data_vec, 1);
         let sample_time= get_resp_property_as_int(data_vec, "time", 1, 0);
@@ -504,22 +1082,13 @@ impl SampleCollector {
         let mut is_new = false;
         let thread_data = self.threads.entry(thread_id).or_insert_with(||{
             is_new = true;
-            ThreadData {
-                id: thread_id,
-                name: name.to_string(),
-                priority: 0,
-                daemon: false,
-                state: state.to_string(),
-                cpu_time: cpu_time,
-                cpu_time_delta: cpu_time_delta,
-                sample_time: sample_time,
-                sample_count: 0,
-                stacktrace: vec![],
-                duration: 0,
-                self_duration: 0,
-                self_cpu_time: 0
-            }
+            let mut thread_data = ThreadData::new(thread_id, name);
+            thread_data.start_time = sample_time;
+            thread_data
         });
+        if thread_data.start_time == 0 {
+            thread_data.start_time = sample_time;
+        }
         thread_data.sample_time = sample_time;
         thread_data.sample_count += 1;
         thread_data.cpu_time = cpu_time;
@@ -531,24 +1100,107 @@ impl SampleCollector {
         let mut stack_frames = Vec::with_capacity(stacktrace.len());
         for frame in stacktrace {
             if let Value::Integer(method_id) = frame {
-                stack_frames.push(*method_id);
+                //translate jmethodID to stable method key
+                let method_key = match self.method_key_map.get(method_id) {
+                    Some(key) => *key,
+                    None => {
+                        //method info is not received in time, save raw id
+                        self.unresolved_methods.insert(*method_id);
+                        self.raw_methods.insert(*method_id);
+                        *method_id
+                    }
+                };
+                stack_frames.push(method_key);
             }
         }
         thread_data.stacktrace = stack_frames;
         //clone: break mut ref of self
         let thread_data = thread_data.clone();
 
+        //conditional recording, samples outside trigger windows are downsampled
+        let live_threads = if self.trigger_engine.is_some() {
+            self.threads.values().filter(|t| t.is_alive() && t.sample_count > 0).count()
+        } else { 0 };
+        let (sample, backfill, markers, high_res_end_time) = match self.trigger_engine.as_mut() {
+            Some(engine) => {
+                let markers = engine.on_thread_sample(&thread_data, live_threads);
+                let (sample, backfill) = engine.filter_sample(thread_data.clone());
+                (sample, backfill, markers, engine.get_high_res_end_time())
+            },
+            None => (Some(thread_data.clone()), vec![], vec![], 0)
+        };
+        for marker in markers {
+            self.on_trigger_fired(marker);
+        }
+        for marker in self.markers.iter_mut() {
+            //the window is extended while the condition holds
+            if marker.window_end >= sample_time && marker.window_end < high_res_end_time {
+                marker.window_end = high_res_end_time;
+            }
+        }
+
         //prepare data dir
-        self.check_and_roll_data_dir(sample_time);
+        let mut rolled = false;
+        if let Some(sample) = &sample {
+            rolled = self.save_thread_sample(sample)?;
+        }
+        if !backfill.is_empty() {
+            for sample in &backfill {
+                self.save_backfill_sample(sample)?;
+            }
+            //backfill is written in one batch, flush it to be visible to queries
+            if let Some(segment) = self.segments.active_segment_mut() {
+                for idx_file in segment.backfill_map.values_mut() {
+                    if let Some(idx_file) = idx_file {
+                        idx_file.flush()?;
+                    }
+                }
+            }
+            self.data_version += 1;
+        }
+        if self.gap_start_time > 0 {
+            self.on_gap_closed(sample_time);
+        }
         self.last_record_time = sample_time;
-        if is_new {
+        if is_new || rolled {
             self.save_summary_info();
         }
+        self.request_resolve_methods(sample_time);
+
+        if !self.event_listeners.is_empty() {
+            let mut summary = thread_data;
+            summary.stacktrace.clear();
+            self.publish_event(SampleEvent::ThreadSample(summary, is_new));
+        }
+        Ok(())
+    }
+
+    //write sample to thread files of active segment, return true if the data dir is rolled
+    fn save_thread_sample(&mut self, thread_data: &ThreadData) -> io::Result<bool> {
+        let thread_id = thread_data.id;
+        let sample_time = thread_data.sample_time;
+        let cpu_time_delta = thread_data.cpu_time_delta;
+        let rolled = self.check_and_roll_data_dir(sample_time)?;
+
+        //samples replayed from agent flight recorder are older than the data dir
+        let replayed = sample_time < self.segment_start_time;
+        if replayed {
+            self.segment_start_time = sample_time;
+            self.record_start_time = min(self.record_start_time, sample_time);
+        }
+        let segment = match self.segments.active_segment_mut() {
+            Some(segment) => segment,
+            None => return Err(new_error(ErrorKind::NotFound, "active sample segment not found"))
+        };
+        if replayed {
+            segment.start_time = min(segment.start_time, sample_time);
+        }
+        segment.end_time = max(segment.end_time, sample_time);
 
         //save thread cpu time
         let sample_interval = self.sample_interval as i32;
         let sample_data_dir = &self.sample_data_dir;
-        let cpu_ts = self.sample_cpu_ts_map.entry(thread_id).or_insert_with(||{
+        let cpu_ts = segment.cpu_ts_map.entry(thread_id).or_insert_with(||{
             let path = format!("{}/thread_{}_cpu_time", sample_data_dir, thread_id);
             match TimeSeriesFileWriter::new(ValueType::INT32, sample_interval , sample_time, &path) {
                 Ok(ts) => Some(Box::new(ts)),
@@ -566,7 +1218,7 @@ impl SampleCollector {
         }
 
         //save thread stack data
-        let thread_stack_idx = self.sample_stacktrace_map.entry(thread_id).or_insert_with(||{
+        let thread_stack_idx = segment.stacktrace_map.entry(thread_id).or_insert_with(||{
             let path = format!("{}/thread_{}_stack", sample_data_dir, thread_id);
             match TupleIndexedFile::new_writer(&path, ValueType::UINT32) {
                 Ok(idx_file) => Some(idx_file),
@@ -581,13 +1233,64 @@ impl SampleCollector {
 //            let stack_data = Value::Array(stacktrace.clone());
 //            idx_file.add_value(TupleValue::uint32(ts_steps), stack_data.encode().as_slice());
 
-            let data = serde_json::to_vec(&thread_data)?;
+            let data = serde_json::to_vec(thread_data)?;
             idx_file.add_value(TupleValue::uint32(ts_steps), &data);
         }
+        Ok(rolled)
+    }
 
+    //write a dropped sample of trigger pre window to the backfill file of active segment
+    fn save_backfill_sample(&mut self, thread_data: &ThreadData) -> io::Result<()> {
+        let thread_id = thread_data.id;
+        let sample_data_dir = &self.sample_data_dir;
+        let segment = match self.segments.active_segment_mut() {
+            Some(segment) => segment,
+            None => return Err(new_error(ErrorKind::NotFound, "active sample segment not found"))
+        };
+        //samples of the rolled dir are not backfilled
+        if thread_data.sample_time < segment.start_time {
+            return Ok(());
+        }
+        let ts_steps = match segment.cpu_ts_map.get(&thread_id) {
+            Some(Some(ts)) => ts.time_to_step(thread_data.sample_time),
+            _ => return Ok(())
+        };
+        let backfill_idx = segment.backfill_map.entry(thread_id).or_insert_with(||{
+            let path = format!("{}/thread_{}_stack_backfill", sample_data_dir, thread_id);
+            match TupleIndexedFile::new_writer(&path, ValueType::UINT32) {
+                Ok(idx_file) => Some(idx_file),
+                Err(e) => {
+                    println!("create thread backfill file failed: thread_id: {}, err: {}", thread_id, e);
+                    None
+                }
+            }
+        });
+        if let Some(idx_file) = backfill_idx {
+            let data = serde_json::to_vec(thread_data)?;
+            idx_file.add_value(TupleValue::uint32(ts_steps), &data)?;
+        }
         Ok(())
     }
 
+    //ask agent for the method symbols not received in time
+    fn request_resolve_methods(&mut self, now: i64) {
+        if self.unresolved_methods.is_empty() || now - self.resolve_request_time < 1000 {
+            return;
+        }
+        self.resolve_request_time = now;
+        if let Some(stream) = self.agent_stream.as_mut() {
+            let ids = self.unresolved_methods.iter().take(1000).map(|id| Value::Integer(*id)).collect();
+            let request = Value::Array(vec![
+                Value::String("resolve-methods".to_string()),
+                Value::String("ids".to_string()),
+                Value::Array(ids),
+            ]);
+            if let Err(e) = stream.write_all(request.encode().as_slice()) {
+                println!("send resolve-methods request failed: {}", e);
+            }
+        }
+    }
+
     fn save_method_info(&mut self, method_id: i64, method_name: &String) {
         if let Some(idx) = self.sample_method_idx_file.as_mut() {
             idx.add_value(TupleValue::int64(method_id), method_name.as_bytes());
@@ -619,6 +1322,10 @@ impl SampleCollector {
         info
     }
 
+    pub fn get_data_version(&self) -> u64 {
+        self.data_version
+    }
+
     pub fn get_sample_info(&self) -> SampleInfo {
         SampleInfo {
             sample_start_time: self.sample_start_time,
@@ -626,7 +1333,14 @@ impl SampleCollector {
             last_record_time: self.last_record_time,
             sample_interval: self.sample_interval,
             agent_addr: self.agent_addr.clone(),
-            sample_data_dir: self.sample_data_dir.clone()
+            sample_data_dir: self.sample_data_dir.clone(),
+            recording_id: self.recording_id.clone(),
+            segment_start_time: self.segment_start_time,
+            jvm_pid: self.jvm_pid,
+            main_class: self.main_class.clone(),
+            gaps: self.gaps.clone(),
+            reconnecting: self.reconnecting,
+            markers: self.markers.clone(),
         }
     }
 
@@ -635,370 +1349,348 @@ impl SampleCollector {
     }
 
     pub fn get_thread_cpu_time(&mut self, thread_id: &i64, start_time: i64, end_time: i64, unit_time_ms: i64) -> Option<Arc<TSResult>> {
-//        match self.sample_cpu_ts_map.get(thread_id) {
-//            Some(ts) => {
-//                match ts {
-//                    Some(ts) => {
-//                        Some(ts.get_range_value(start_time, end_time, unit_time_ms as i32))
-//                    },
-//                    None => None,
-//                }
-//            },
-//            None => None
-//        }
-
-        let ts = self.sample_cpu_ts_map.get(thread_id).unwrap_or(&None);
-        if ts.is_none() {
-            return None;
-        }
-        //只有打开取样文件才缓存CPU统计数据
-        if self.sample_type == "file" {
-            let cache_key = format!("thread_cpu_ts_{}_{}_{}_{}", thread_id, unit_time_ms, start_time, end_time);
-            self.sample_cpu_ts_cache.entry(cache_key).or_insert_with(||{
-                if let Some(tsf) = ts {
-                    Some(Arc::new(tsf.get_range_value(start_time, end_time, unit_time_ms as i32)))
-                }else {
-                    None
-                }
-            }).clone()
-        } else {
-            if let Some(tsf) = ts {
-                Some(Arc::new(tsf.get_range_value(start_time, end_time, unit_time_ms as i32)))
-            }else {
-                None
-            }
-        }
+        //result is cached by profiler range cache
+        self.segments.get_thread_cpu_time(*thread_id, start_time, end_time, unit_time_ms as i32).map(|ts| Arc::new(ts))
     }
 
-    pub fn get_collapsed_call_stacks(&mut self, thread_id: i64, start_time: i64, end_time: i64, stats_type: StatsType) -> io::Result<Vec<String>> {
-        let mut start_step = 0;
-        let mut end_step = 0;
+    pub fn get_collapsed_call_stacks(&mut self, thread_id: i64, start_time: i64, end_time: i64, stats_type: StatsType, aggregation: AggregationLevel) -> io::Result<Vec<String>> {
         let mut sw = Stopwatch::start_new();
         sw.start();
-        if let Some(ts_file) = self.sample_cpu_ts_map.get(&thread_id).unwrap_or(&None) {
-            start_step = ts_file.time_to_step(start_time);
-            end_step = ts_file.time_to_step(end_time);
-        }else {
-            return Err(new_error(ErrorKind::NotFound, "thread cpu time file not found"));
-        }
-        println!("thread: {}, convert time to step cost:{}, steps:{}", thread_id, sw.lap(), end_step-start_step);
 
-        //TODO 可能单次读取的数据比较多，导致内存消耗太大
-        let mut thread_data_vec = vec![];
+        //collapse samples while visiting, the same stacks are summed
+        let mut stack_values: HashMap<String, i64> = HashMap::new();
+        let mut sample_count = 0;
         let mut last_sample_time = 0;
-        self.sample_stacktrace_map.get_mut(&thread_id).unwrap_or(&mut None).as_mut().map(|idx_file| {
-            idx_file.get_range_value(&TupleValue::uint32(start_step), &TupleValue::uint32(end_step), |bytes|{
-                //parse stack data
-                if let Ok(mut thread_data) = serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
-                    if last_sample_time != 0 {
-                        thread_data.self_duration = thread_data.sample_time - last_sample_time;
-                    }
-                    last_sample_time = thread_data.sample_time;
-                    thread_data_vec.push(thread_data);
+        //frame label of method at aggregation level
+        let mut frame_labels: HashMap<JavaMethod, String> = HashMap::new();
+        let method_cache = &self.method_cache;
+        let method_idx = self.sample_method_idx_file.as_ref();
+        let found = self.segments.visit_thread_samples(thread_id, start_time, end_time, |bytes|{
+            if is_cancelled() {
+                return false;
+            }
+            //parse stack data
+            let thread_data = match serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
+                Ok(thread_data) => thread_data,
+                Err(_) => return true
+            };
+            let self_duration = if last_sample_time != 0 { thread_data.sample_time - last_sample_time } else { 0 };
+            last_sample_time = thread_data.sample_time;
+            sample_count += 1;
+
+            for method in &thread_data.stacktrace {
+                if !frame_labels.contains_key(method) {
+                    let method_name = match SampleCollector::load_method_info(method_cache, method_idx, *method) {
+                        Some(method_info) => method_info.full_name,
+                        None => method.to_string()
+                    };
+                    frame_labels.insert(*method, aggregation.get_label(&method_name));
                 }
-            });
-        });
-        println!("thread: {}, load stacktrace cost:{}, count:{}", thread_id, sw.lap(), thread_data_vec.len());
-
-        let mut collapsed_stacks = vec![];
-        let mut last_cpu_time = 0;
-        for thread_data in &thread_data_vec {
+            }
             let mut collapsed_stack = String::new();
+            let mut last_label = "";
             for method in thread_data.stacktrace.iter().rev() {
-                if let Some(method_info) = self.get_method_info(*method) {
-                    if collapsed_stack.len() > 0 {
-                        collapsed_stack += ";";
-                    }
-                    collapsed_stack += &method_info.full_name;
-                }else {
+                let label = frame_labels[method].as_str();
+                //consecutive frames of the same class or package are merged
+                if aggregation != AggregationLevel::METHOD && label == last_label {
+                    continue;
+                }
+                last_label = label;
+                if collapsed_stack.len() > 0 {
                     collapsed_stack += ";";
-                    collapsed_stack += &method.to_string();
                 }
+                collapsed_stack += label;
             }
             //get stats value
             let stats_value = match stats_type {
-                StatsType::DURATION => thread_data.self_duration,
-                StatsType::CPU_TIME => thread_data.self_cpu_time,
+                StatsType::DURATION => self_duration,
+                StatsType::CPU_TIME => thread_data.cpu_time_delta / 1000,
                 StatsType::SAMPLES => 1,
             };
-            collapsed_stack += " ";
-            collapsed_stack += &stats_value.to_string();
-            collapsed_stacks.push(collapsed_stack);
+            *stack_values.entry(collapsed_stack).or_insert(0) += stats_value;
+            true
+        });
+        if !found {
+            return Err(new_error(ErrorKind::NotFound, "thread cpu time file not found"));
         }
+        check_cancelled()?;
+        println!("thread: {}, collapse stacktrace cost:{}, count:{}, stacks:{}", thread_id, sw.lap(), sample_count, stack_values.len());
 
+        let mut collapsed_stacks: Vec<String> = stack_values.into_iter()
+            .map(|(stack, value)| format!("{} {}", stack, value)).collect();
+        collapsed_stacks.sort();
         Ok(collapsed_stacks)
     }
 
+    /// Visit samples of threads in time range. Stacktrace is ordered from leaf to root,
+    /// self_duration is the time since last sample, self_cpu_time is the cpu time delta (micros).
+    pub fn visit_stack_samples<F>(&mut self, thread_ids: &[i64], start_time: i64, end_time: i64, mut handler: F) -> io::Result<()>
+        where F: FnMut(&ThreadData) {
+        for (i, thread_id) in thread_ids.iter().enumerate() {
+            check_cancelled()?;
+            report_progress(i, thread_ids.len(), "visiting thread samples");
+            let mut last_sample_time = 0;
+            self.segments.visit_thread_samples(*thread_id, start_time, end_time, |bytes| {
+                //stop reading the remaining samples
+                if is_cancelled() {
+                    return false;
+                }
+                if let Ok(mut thread_data) = serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
+                    if last_sample_time != 0 {
+                        thread_data.self_duration = thread_data.sample_time - last_sample_time;
+                    }
+                    thread_data.self_cpu_time = thread_data.cpu_time_delta / 1000;
+                    last_sample_time = thread_data.sample_time;
+                    handler(&thread_data);
+                }
+                true
+            });
+        }
+        check_cancelled()
+    }
+
+    //callers tree, leaf methods are under root
+    pub fn get_bottom_up_tree(&mut self, thread_ids: &[i64], start_time: i64, end_time: i64) -> io::Result<Box<tree::TreeNode>> {
+        let mut sw = Stopwatch::start_new();
+        let mut builder = tree::BottomUpTreeBuilder::new(get_max_tree_nodes(SEQUENCED_TREE_NODE_SIZE));
+        self.visit_stack_samples(thread_ids, start_time, end_time, |thread_data| {
+            builder.add_thread_data(thread_data);
+        })?;
+        let mut root = builder.finish();
+        self.fill_method_names(&mut root);
+        println!("threads: {:?}, build bottom up tree cost:{}", thread_ids, sw.lap());
+        Ok(root)
+    }
+
+    pub fn get_method_butterfly(&mut self, thread_ids: &[i64], start_time: i64, end_time: i64, method_id: i64) -> io::Result<tree::MethodButterfly> {
+        let mut butterfly = tree::MethodButterfly::new(method_id);
+        self.visit_stack_samples(thread_ids, start_time, end_time, |thread_data| {
+            butterfly.add_thread_data(thread_data);
+        })?;
+        Ok(butterfly)
+    }
+
+    pub fn get_flat_profile(&mut self, thread_ids: &[i64], start_time: i64, end_time: i64) -> io::Result<tree::FlatProfileBuilder> {
+        let mut builder = tree::FlatProfileBuilder::new();
+        self.visit_stack_samples(thread_ids, start_time, end_time, |thread_data| {
+            builder.add_thread_data(thread_data);
+        })?;
+        Ok(builder)
+    }
+
     //获取顺序排列（时间顺序）的方法调用树
     pub fn get_sequenced_call_tree(&mut self, thread_id: i64, start_time: &mut i64, end_time: &mut i64, fill_method_name: bool) -> io::Result<Box<tree::TreeNode>> {
-        let mut start_step = 0;
-        let mut end_step = 0;
         let mut sw = Stopwatch::start_new();
         sw.start();
-        let mut ts_file_begin_time = 0;
-        if let Some(ts_file) = self.sample_cpu_ts_map.get(&thread_id).unwrap_or(&None) {
-            ts_file_begin_time = ts_file.get_begin_time();
-            start_step = ts_file.time_to_step(*start_time);
-            end_step = ts_file.time_to_step(*end_time);
-        } else {
-            return Err(new_error(ErrorKind::NotFound, "thread cpu time file not found"));
-        }
-        println!("thread: {}, convert time to step cost:{}, steps:{}", thread_id, sw.lap(), end_step - start_step);
-        let last_time = *end_time - ts_file_begin_time;
+        let last_time = if *end_time < 0 { self.last_record_time } else { *end_time };
 
-        //TODO 可能单次读取的数据比较多，导致内存消耗太大
-        //TODO fix range
-        let mut thread_data_vec = vec![];
+        //build tree in samples visitor, avoid loading all samples of range into memory
+        let mut builder = tree::SequencedTreeBuilder::new(get_max_tree_nodes(SEQUENCED_TREE_NODE_SIZE));
+        let mut sample_count = 0;
         let mut last_thread_data: Option<ThreadData> = None;
-        self.sample_stacktrace_map.get_mut(&thread_id).unwrap_or(&mut None).as_mut().map(|idx_file| {
-            idx_file.get_range_value(&TupleValue::uint32(start_step), &TupleValue::uint32(end_step), |bytes| {
-                //parse stack data
-                if let Ok(mut thread_data) = serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
-                    if last_thread_data.is_some() {
-                        let mut last_call = last_thread_data.take().unwrap();
-                        last_call.self_duration = thread_data.sample_time - last_call.sample_time;
-                        thread_data_vec.push(last_call);
-                    }
-                    last_thread_data = Some(thread_data);
+        let found = self.segments.visit_thread_samples(thread_id, *start_time, *end_time, |bytes| {
+            if is_cancelled() {
+                return false;
+            }
+            //parse stack data
+            if let Ok(mut thread_data) = serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
+                if let Some(mut last_call) = last_thread_data.take() {
+                    last_call.self_duration = thread_data.sample_time - last_call.sample_time;
+                    builder.add_thread_data(&last_call);
+                    sample_count += 1;
                 }
-            });
+                last_thread_data = Some(thread_data);
+            }
+            true
         });
+        if !found {
+            return Err(new_error(ErrorKind::NotFound, "thread cpu time file not found"));
+        }
+        check_cancelled()?;
         //last method call
         if let Some(mut last_call) = last_thread_data {
             // how long of last method call duration?
             if last_time > last_call.sample_time {
                 last_call.self_duration = last_time - last_call.sample_time;
-                thread_data_vec.push(last_call);
+                builder.add_thread_data(&last_call);
+                sample_count += 1;
             }else {
                 //last sample time is out of range, drop it
             }
         }
-        println!("thread: {}, load stacktrace cost:{}, count:{}", thread_id, sw.lap(), thread_data_vec.len());
 
-        thread_data_vec.first_mut().map(|thread_data|{
-            *start_time = thread_data.sample_time;
-        });
-        thread_data_vec.last_mut().map(|thread_data|{
-            *end_time = thread_data.sample_time + thread_data.duration;
-        });
+        if sample_count > 0 {
+            let (range_start_time, range_end_time) = builder.get_range();
+            *start_time = range_start_time;
+            *end_time = range_end_time;
+        }
+        let mut root = builder.finish();
+        println!("thread: {}, load stacktrace and build call tree cost:{}, count:{}", thread_id, sw.lap(), sample_count);
 
-        //merge build
-        let result = self.build_sequenced_tree(&thread_data_vec, *start_time, *end_time, fill_method_name);
-        println!("thread: {}, build call tree cost:{}, count:{}", thread_id, sw.lap(), thread_data_vec.len());
-        result
-    }
-
-    //火焰图的顺序树
-    //每一层与最后一个节点相同时进行合并，不同时append新节点
-    //处理前后半个采样间隔的问题
-    pub fn build_sequenced_tree(&mut self, thread_data_vec: &Vec<ThreadData>, range_start_time: i64, range_end_time: i64, fill_method_name: bool) -> io::Result<Box<tree::TreeNode>> {
-        let mut root = Box::new(tree::TreeNode::new(0, "root"));
-
-        for thread_data in thread_data_vec {
-            root.duration += thread_data.self_duration;
-            root.cpu += thread_data.self_cpu_time;
-            root.calls += 1;
-            let mut node = &mut root;
-            let mut start_time = thread_data.sample_time - range_start_time;
-            if start_time < 0 {
-                start_time = 0;
-            }
-            for method in thread_data.stacktrace.iter().rev() {
-                //merge_last_child fn return bool instead of node reference for avoiding second borrow mutable node
-                if node.merge_last_child(*method, thread_data.self_duration, thread_data.self_cpu_time, 1) {
-                    //merge success, next is just last child
-                    node = node.last_child().unwrap();
-                } else {
-                    //不需要每次都获取方法名，减少搜索方法时构建调用树的时间
-                    let tmp;
-                    let method_name :&str;
-                    if fill_method_name {
-                        method_name = if let Some(method_info) = self.get_method_info(*method) {
-                            &method_info.full_name
-                        }else {
-                            tmp = method.to_string();
-                            &tmp
-                        };
-                    } else {
-                        method_name = "";
-                    }
-                    let child_depth = node.depth+1;
-                    node = node.append_child(tree::TreeNode{
-                        parent: None,
-                        children: vec![],
-                        depth: child_depth,
-                        id: *method,
-                        label: method_name.to_string(),
-                        calls: 1,
-                        cpu: thread_data.self_cpu_time,
-                        duration: thread_data.self_duration,
-                        start_time
-                    })
-                }
-            }
+        if fill_method_name {
+            self.fill_method_names(&mut root);
         }
         Ok(root)
     }
 
+    //不需要每次都获取方法名，减少搜索方法时构建调用树的时间
+    fn fill_method_names(&mut self, node: &mut tree::TreeNode) {
+        for child in &mut node.children {
+            child.label = match self.get_method_info(child.id) {
+                Some(method_info) => method_info.full_name.clone(),
+                None => child.id.to_string()
+            };
+            self.fill_method_names(child);
+        }
+    }
+
     pub fn get_call_tree(&mut self, thread_ids: &[i64], start_time: i64, end_time: i64) -> io::Result<CallStackTree> {
-        //TODO
-        let mut stack_tree = CallStackTree::new(0, "CallStack");
+        let mut builder = CallTreeBuilder::new(get_max_tree_nodes(CALL_TREE_NODE_SIZE));
         let mut sw = Stopwatch::start_new();
 
-        for thread_id in thread_ids {
-            let mut start_step = 0;
-            let mut end_step = 0;
+        for (i, thread_id) in thread_ids.iter().enumerate() {
+            check_cancelled()?;
+            report_progress(i, thread_ids.len(), "building call tree");
             sw.start();
-            if let Some(ts_file) = self.sample_cpu_ts_map.get(thread_id).unwrap_or(&None) {
-                start_step = ts_file.time_to_step(start_time);
-                end_step = ts_file.time_to_step(end_time);
-            }else {
-                continue;
-            }
-            println!("thread: {}, convert time to step cost:{}, steps:{}", thread_id, sw.lap(), end_step-start_step);
 
-            //TODO 可能单次读取的数据比较多，导致内存消耗太大
-            let mut thread_data_vec = vec![];
+            //build tree in samples visitor, only keep samples between two cpu time updates
+            let mut sample_count = 0;
             let mut last_sample_time = 0;
-            self.sample_stacktrace_map.get_mut(thread_id).unwrap_or(&mut None).as_mut().map(|idx_file| {
-                idx_file.get_range_value(&TupleValue::uint32(start_step), &TupleValue::uint32(end_step), |bytes|{
-                    //parse stack data
-                    if let Ok(mut thread_data) = serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
-                        if last_sample_time != 0 {
-                            thread_data.duration = thread_data.sample_time - last_sample_time;
-                        }
-                        last_sample_time = thread_data.sample_time;
-                        thread_data_vec.push(thread_data);
-                    }
-                });
-            });
-            println!("thread: {}, load stacktrace cost:{}, count:{}", thread_id, sw.lap(), thread_data_vec.len());
-
-            //thread cpu_time 延时更新，暂时将增量时间平均分配到两次更新CPU时间中的方法调用上
             let mut last_divide_cpu_time = 0;
-            let mut start = 0;
-            let mut end = 0;
-            let mut pending_thread_data_vec: Vec<&mut ThreadData> = vec![];
-            for (i,thread_data) in thread_data_vec.iter_mut().enumerate() {
-                if last_divide_cpu_time == 0 {
-                    last_divide_cpu_time = thread_data.cpu_time;
+            let mut pending_thread_data_vec: Vec<ThreadData> = vec![];
+            self.segments.visit_thread_samples(*thread_id, start_time, end_time, |bytes|{
+                if is_cancelled() {
+                    return false;
                 }
-                if thread_data.cpu_time != last_divide_cpu_time {
-                    end = i;
-                    let curr_cpu_time = thread_data.cpu_time;
-                    self.divide_cpu_time(pending_thread_data_vec, last_divide_cpu_time, curr_cpu_time);
-                    pending_thread_data_vec = vec![];
-                    last_divide_cpu_time = curr_cpu_time;
-                    start = end;
+                //parse stack data
+                if let Ok(mut thread_data) = serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
+                    if last_sample_time != 0 {
+                        thread_data.duration = thread_data.sample_time - last_sample_time;
+                    }
+                    last_sample_time = thread_data.sample_time;
+                    sample_count += 1;
+
+                    //thread cpu_time 延时更新，暂时将增量时间平均分配到两次更新CPU时间中的方法调用上
+                    if last_divide_cpu_time == 0 {
+                        last_divide_cpu_time = thread_data.cpu_time;
+                    }
+                    if thread_data.cpu_time != last_divide_cpu_time {
+                        let curr_cpu_time = thread_data.cpu_time;
+                        SampleCollector::divide_cpu_time(&mut pending_thread_data_vec, last_divide_cpu_time, curr_cpu_time);
+                        for pending_thread_data in pending_thread_data_vec.drain(..) {
+                            builder.add_stack_trace(&pending_thread_data);
+                        }
+                        last_divide_cpu_time = curr_cpu_time;
+                    }
+                    pending_thread_data_vec.push(thread_data);
                 }
-                pending_thread_data_vec.push(thread_data);
+                true
+            });
+            for pending_thread_data in pending_thread_data_vec.drain(..) {
+                builder.add_stack_trace(&pending_thread_data);
             }
+            println!("thread: {}, load stacktrace and build tree cost:{}, count:{}", thread_id, sw.lap(), sample_count);
+        }
 
-            for thread_data in &thread_data_vec {
-                self.add_stack_trace(&mut stack_tree, thread_data);
+        check_cancelled()?;
+        let (mut stack_tree, naming_nodes) = builder.finish();
+        //get method call_name of node
+        for (node_id, method_id) in naming_nodes {
+            if let Some(method_info) = self.get_method_info(method_id) {
+                stack_tree.get_mut_node(&node_id).data.name = method_info.full_name.clone();
             }
-            println!("thread: {}, build tree cost:{}", thread_id, sw.lap());
-
-//            if let Some(method_idx) = self.sample_method_idx_file.as_mut() {
-//                for method in stack_frames {
-//                    let bytes = method_idx.get_value(&TupleValue::int64(method))?;
-//                    let method_name = std::str::from_utf8(bytes.as_slice())?;
-//                }
-//            }
-
         }
         println!("total threads: {}, total cost:{}", thread_ids.len(), sw.elapsed_ms());
 
         Ok(stack_tree)
     }
 
-    fn divide_cpu_time(&mut self, mut thread_data_batch: Vec<&mut ThreadData>, last_cpu_time: i64, curr_cpu_time: i64){
+    fn divide_cpu_time(thread_data_batch: &mut Vec<ThreadData>, last_cpu_time: i64, curr_cpu_time: i64){
+        if thread_data_batch.is_empty() {
+            return;
+        }
         let cpu_time_per_trace = (curr_cpu_time - last_cpu_time) / thread_data_batch.len() as i64;
         let mut thread_cpu_time = last_cpu_time;
         let mut i=0;
         let len = thread_data_batch.len();
-        for thread_data in thread_data_batch.as_mut_slice() {
+        for thread_data in thread_data_batch.iter_mut() {
             if i < len {
                 thread_cpu_time += cpu_time_per_trace;
             }else {
                 thread_cpu_time = curr_cpu_time;
             }
             i += 1;
-            (*thread_data).cpu_time = thread_cpu_time;
+            thread_data.cpu_time = thread_cpu_time;
             //thread_data.cpu_time_delta =
         }
     }
 
-    fn add_stack_trace(&mut self, call_tree: &mut CallStackTree, thread_data: &ThreadData) {
-
-        call_tree.reset_top_call_stack_node();
-        let (delta_duration, delta_cpu_time) = call_tree.start_call_stack(thread_data.sample_time, thread_data.cpu_time);
-
-        //save nodes in temp vec, process it after build call tree, avoid second borrow muttable *self
-        let mut naming_nodes: Vec<(NodeId, JavaMethod)> = vec![];
-
-        //reverse call
-        for method_id in thread_data.stacktrace.iter().rev() {
-            if !call_tree.begin_call(method_id, delta_duration, delta_cpu_time) {
-                naming_nodes.push((call_tree.get_top_node().data.node_id, method_id.clone()));
-            }
+    pub fn get_method_name(&mut self, method: JavaMethod) -> String {
+        match self.get_method_info(method) {
+            Some(method_info) => method_info.full_name.clone(),
+            None => method.to_string()
         }
+    }
 
-        //call_tree.end_last_call(cpu_time);
-        //println!("add call stack: {} cpu_time:{}", thread_info.name, cpu_time);
-
-        //get method call_name of node
-        for (node_id, method_id) in naming_nodes {
-            if let Some(method_info) = self.get_method_info(method_id) {
-                call_tree.get_mut_node(&node_id).data.name = method_info.full_name.clone();
-
-            }
-        }
+    pub fn get_method_info(&mut self, method: JavaMethod) -> Option<MethodInfo> {
+        SampleCollector::load_method_info(&self.method_cache, self.sample_method_idx_file.as_ref(), method)
     }
 
-    pub fn get_method_info(&mut self, method: JavaMethod) -> &Option<MethodInfo> {
-        let method_idx_file = self.sample_method_idx_file.as_mut();
-        self.method_cache.entry(method).or_insert_with(|| {
-            if let Some(method_idx) = method_idx_file {
-                if let Ok(bytes) = method_idx.get_value(&TupleValue::int64(method)){
-                    let mut method_name = std::str::from_utf8(bytes.as_slice()).unwrap_or("").to_string();
-                    if method_name == "" {
-                        method_name = method.to_string();
-                    }
-                    return Some(MethodInfo {
-                        method_id: method,
-                        full_name: method_name.to_string(),
-                        hits_count: 0
-                    });
-                }
-            }
-            return None;
-        })
+    //borrow the fields only, so that method names can be loaded while visiting samples
+    fn load_method_info(method_cache: &RwLock<HashMap<JavaMethod, MethodInfo>>, method_idx: Option<&TupleIndexedFile>, method: JavaMethod) -> Option<MethodInfo> {
+        if let Some(method_info) = method_cache.read().unwrap().get(&method) {
+            return Some(method_info.clone());
+        }
+        //only resolved methods are cached, the method info may be saved later
+        let method_idx = method_idx?;
+        let bytes = method_idx.get_value(&TupleValue::int64(method)).ok()?;
+        let mut method_name = std::str::from_utf8(bytes.as_slice()).unwrap_or("").to_string();
+        if method_name == "" {
+            method_name = method.to_string();
+        }
+        let method_info = MethodInfo {
+            method_id: method,
+            full_name: method_name,
+            hits_count: 0
+        };
+        method_cache.write().unwrap().insert(method, method_info.clone());
+        Some(method_info)
     }
 
     pub fn list_methods_by_filter(&mut self, method_name_filter: &str) -> io::Result<Vec<MethodInfo>> {
         let mut method_infos = vec![];
         if let Some(method_idx_file) = &mut self.sample_method_idx_file {
 
-            //TODO cache method infos
             let now = Local::now().timestamp_millis();
-            if self.method_info_update_time > self.method_entry_cache_time || self.method_entries.is_empty() {
+            let mut cached_entries = {
+                let cache = self.method_entry_cache.read().unwrap();
+                if self.method_info_update_time > cache.update_time || cache.entries.is_empty() { None } else { Some(cache.entries.clone()) }
+            };
+            if cached_entries.is_none() {
                 println!("get all method entries ...");
                 let entries = method_idx_file.get_all_entries()?;
+                let mut method_entries = Vec::with_capacity(entries.len());
                 for (method,bytes) in &entries {
                     let method_name;
                     unsafe {
                         method_name = std::str::from_utf8_unchecked(&bytes);
                     }
-                    self.method_entries.push(MethodInfo {
+                    method_entries.push(MethodInfo {
                         method_id: *method,
                         full_name: method_name.to_string(),
                         hits_count: 0
                     });
                 }
-                self.method_entry_cache_time = now;
+                let method_entries = Arc::new(method_entries);
+                //the entries may be loaded by an older snapshot
+                let mut cache = self.method_entry_cache.write().unwrap();
+                if self.method_info_update_time >= cache.update_time {
+                    cache.entries = method_entries.clone();
+                    cache.update_time = self.method_info_update_time;
+                }
+                cached_entries = Some(method_entries);
             }
-            for method_info in &self.method_entries {
+            for method_info in cached_entries.unwrap().iter() {
                 let method_name = &method_info.full_name;
                 if method_name.contains(method_name_filter) {
                     method_infos.push(method_info.clone());
@@ -1021,15 +1713,6 @@ impl SampleCollector {
             thread_name = thread.name.clone();
         }
 
-        //cache
-//        let mut call_tree = self.call_tree_cahce.get(&thread_id);
-//        if call_tree.is_none() {
-//            let tree = self.get_sequenced_call_tree(thread_id, &mut start_time, &mut end_time)?;
-//            self.call_tree_cahce.insert(thread_id, tree);
-//            call_tree = self.call_tree_cahce.get(&thread_id);
-//        }
-//        self.search_call_tree(&mut method_calls,  call_tree.unwrap(), thread_id, &thread_name, method_ids, min_duration, max_duration);
-
         let mut call_tree = self.get_sequenced_call_tree(thread_id, &mut start_time, &mut end_time, false)?;
         self.search_call_tree(&mut method_calls,  &call_tree, thread_id, &thread_name, method_ids, min_duration, max_duration);
 
@@ -1094,6 +1777,83 @@ impl SampleCollector {
         }
     }
 
+    /// Write samples in time range to a new standalone sample dir, rolled dirs are merged into one.
+    pub fn export_sample_dir(&mut self, target_dir: &str, start_time: i64, end_time: i64, privacy: &PrivacyOptions) -> io::Result<SummaryInfo> {
+        std::fs::create_dir_all(target_dir)?;
+        let sample_interval = self.sample_interval as i32;
+        let mut thread_ids: Vec<JavaLong> = self.threads.keys().cloned().collect();
+        thread_ids.sort();
+
+        let mut threads = vec![];
+        let mut first_sample_time = 0;
+        let mut last_sample_time = 0;
+        for thread_id in thread_ids {
+            let mut cpu_ts: Option<TimeSeriesFileWriter> = None;
+            let mut stack_file: Option<TupleIndexedFile> = None;
+            let mut sample_count = 0;
+            let mut result = Ok(());
+            self.segments.visit_thread_samples(thread_id, start_time, end_time, |bytes| {
+                let mut thread_data = match serde_json::from_slice::<ThreadData>(bytes.as_slice()) {
+                    Ok(thread_data) => thread_data,
+                    Err(_) => return true
+                };
+                if (start_time >= 0 && thread_data.sample_time < start_time) || (end_time >= 0 && thread_data.sample_time > end_time) {
+                    return true;
+                }
+                thread_data.name = privacy.get_thread_name(thread_id, &thread_data.name);
+                result = (|| -> io::Result<()> {
+                    if cpu_ts.is_none() {
+                        let path = format!("{}/thread_{}_cpu_time", target_dir, thread_id);
+                        cpu_ts = Some(TimeSeriesFileWriter::new(ValueType::INT32, sample_interval, thread_data.sample_time, &path)?);
+                        let path = format!("{}/thread_{}_stack", target_dir, thread_id);
+                        stack_file = Some(TupleIndexedFile::new_writer(&path, ValueType::UINT32)?);
+                    }
+                    let steps = cpu_ts.as_mut().unwrap().add_value(thread_data.sample_time, TSValue::int32((thread_data.cpu_time_delta/1000 as i64) as i32))?;
+                    stack_file.as_mut().unwrap().add_value(TupleValue::uint32(steps), &serde_json::to_vec(&thread_data)?)?;
+                    Ok(())
+                })();
+                if first_sample_time == 0 || thread_data.sample_time < first_sample_time {
+                    first_sample_time = thread_data.sample_time;
+                }
+                last_sample_time = max(last_sample_time, thread_data.sample_time);
+                sample_count += 1;
+                //stop exporting on error
+                result.is_ok()
+            });
+            result?;
+
+            if sample_count > 0 {
+                let mut thread = self.threads[&thread_id].clone();
+                thread.name = privacy.get_thread_name(thread_id, &thread.name);
+                thread.sample_count = sample_count;
+                thread.stacktrace = vec![];
+                threads.push(thread);
+            }
+        }
+
+        //method info
+        if let Some(method_idx_file) = self.sample_method_idx_file.as_mut() {
+            let mut export_method_file = TupleIndexedFile::new_writer(&format!("{}/method_info", target_dir), ValueType::INT64)?;
+            for (method_id, bytes) in method_idx_file.get_all_entries()? {
+                let method_name = privacy.get_method_name(&String::from_utf8_lossy(&bytes));
+                export_method_file.add_value(TupleValue::int64(method_id), method_name.as_bytes())?;
+            }
+        }
+
+        let mut sample_info = self.get_sample_info();
+        sample_info.sample_data_dir = target_dir.to_string();
+        sample_info.recording_id = Path::new(target_dir).file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+        sample_info.record_start_time = first_sample_time;
+        sample_info.segment_start_time = first_sample_time;
+        sample_info.last_record_time = last_sample_time;
+        let summary = SummaryInfo {
+            sample_info,
+            threads
+        };
+        std::fs::write(format!("{}/summary_info.json", target_dir), serde_json::to_string_pretty(&summary)?)?;
+        Ok(summary)
+    }
+
     pub fn get_threads(&self) -> io::Result<Vec<ThreadData>> {
         Ok(self.threads.iter().map(|(_, thread)| thread.clone() ).collect())
     }
@@ -1142,6 +1902,9 @@ impl SampleCollector {
 
 impl Drop for SampleCollector {
     fn drop(&mut self) {
+        if self.snapshot {
+            return;
+        }
         println!("dropping sample collector: {} ..", self.sample_data_dir);
         self.save_summary_info();
         self.close();
@@ -116,6 +116,9 @@ pub fn resp_decode_thread_data(data_vec: &Vec<resp::Value>) -> ThreadData {
         sample_time: sample_time,
         sample_count: 0,
         stacktrace: stacktrace,
+        start_time: 0,
+        end_time: 0,
+        lifetime: 0,
         duration: 0,
         self_duration: 0,
         self_cpu_time: 0
@@ -0,0 +1,314 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use flare_utils::timeseries::{TimeSeries, TimeSeriesFileReader, TSResult, TSRangeValue};
+use flare_utils::tuple_indexed::{TupleIndexedFile, TupleValue};
+use sample::SummaryInfo;
+
+type JavaLong = i64;
+
+//max time period of a sample data dir, rolling to a new dir when exceeded
+pub const DEFAULT_ROLL_PERIOD_MS: i64 = 900_000;
+static ROLL_PERIOD_MS: AtomicI64 = AtomicI64::new(DEFAULT_ROLL_PERIOD_MS);
+
+pub fn set_roll_period(period_ms: i64) {
+    ROLL_PERIOD_MS.store(period_ms, Ordering::Relaxed);
+}
+
+pub fn get_roll_period() -> i64 {
+    ROLL_PERIOD_MS.load(Ordering::Relaxed)
+}
+
+/// Sample data of one rolled dir, the thread files of the active segment are writers.
+pub struct SampleSegment {
+    pub data_dir: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    //all thread files are readers, the segment is shared by snapshots
+    pub sealed: bool,
+    pub cpu_ts_map: HashMap<JavaLong, Option<Box<TimeSeries+Send+Sync>>>,
+    pub stacktrace_map: HashMap<JavaLong, Option<TupleIndexedFile>>,
+    //samples of trigger pre windows saved after the downsampled stream, merged by time when visiting
+    pub backfill_map: HashMap<JavaLong, Option<TupleIndexedFile>>,
+}
+
+impl SampleSegment {
+    pub fn new(data_dir: &str, start_time: i64) -> SampleSegment {
+        SampleSegment {
+            data_dir: data_dir.to_string(),
+            start_time,
+            end_time: start_time,
+            sealed: false,
+            cpu_ts_map: HashMap::new(),
+            stacktrace_map: HashMap::new(),
+            backfill_map: HashMap::new(),
+        }
+    }
+
+    //open thread data files of a sample dir in read mode
+    pub fn open(data_dir: &str, summary: &SummaryInfo) -> SampleSegment {
+        let sample_info = &summary.sample_info;
+        let start_time = if sample_info.segment_start_time > 0 { sample_info.segment_start_time } else { sample_info.record_start_time };
+        let mut segment = SampleSegment::new(data_dir, start_time);
+        segment.end_time = sample_info.last_record_time;
+        segment.sealed = true;
+        let thread_ids: Vec<JavaLong> = summary.threads.iter().map(|t| t.id).collect();
+        segment.open_thread_files(&thread_ids);
+        segment
+    }
+
+    /// Readers of the data flushed so far, the ingestion keeps writing the original segment.
+    pub fn snapshot(&self) -> SampleSegment {
+        let mut segment = SampleSegment::new(&self.data_dir, self.start_time);
+        segment.end_time = self.end_time;
+        segment.sealed = true;
+        for (thread_id, ts_file) in &self.cpu_ts_map {
+            if let Some(ts_file) = ts_file {
+                segment.cpu_ts_map.insert(*thread_id, Some(Box::new(TimeSeriesFileReader::from_header_info(ts_file.get_header_info()))));
+            }
+        }
+        for (thread_id, idx_file) in &self.stacktrace_map {
+            if let Some(idx_file) = idx_file {
+                segment.stacktrace_map.insert(*thread_id, Some(idx_file.new_snapshot_reader()));
+            }
+        }
+        for (thread_id, idx_file) in &self.backfill_map {
+            if let Some(idx_file) = idx_file {
+                segment.backfill_map.insert(*thread_id, Some(idx_file.new_snapshot_reader()));
+            }
+        }
+        segment
+    }
+
+    fn open_thread_files(&mut self, thread_ids: &[JavaLong]) {
+        for thread_id in thread_ids {
+            //thread has no sample in this segment
+            let thread_cpu_ts_file = format!("{}/thread_{}_cpu_time", self.data_dir, thread_id);
+            if !Path::new(&format!("{}.fts", thread_cpu_ts_file)).exists() {
+                continue;
+            }
+            match TimeSeriesFileReader::new(&thread_cpu_ts_file) {
+                Ok(ts) => {
+                    self.cpu_ts_map.insert(*thread_id, Some(Box::new(ts)));
+                },
+                Err(e) => {
+                    println!("load thread cpu time file failed: {}, err: {}", thread_cpu_ts_file, e);
+                }
+            }
+
+            let thread_stack_file = format!("{}/thread_{}_stack", self.data_dir, thread_id);
+            match TupleIndexedFile::new_reader(&thread_stack_file) {
+                Ok(file) => {
+                    self.stacktrace_map.insert(*thread_id, Some(file));
+                },
+                Err(e) => {
+                    println!("load thread stacktrace file failed: {}, err: {}", thread_stack_file, e);
+                }
+            }
+
+            let thread_backfill_file = format!("{}/thread_{}_stack_backfill", self.data_dir, thread_id);
+            if Path::new(&format!("{}.fidx", thread_backfill_file)).exists() {
+                match TupleIndexedFile::new_reader(&thread_backfill_file) {
+                    Ok(file) => {
+                        self.backfill_map.insert(*thread_id, Some(file));
+                    },
+                    Err(e) => {
+                        println!("load thread backfill file failed: {}, err: {}", thread_backfill_file, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_overlapped(&self, start_time: i64, end_time: i64) -> bool {
+        start_time <= self.end_time && end_time >= self.start_time
+    }
+}
+
+/// All rolled sample dirs of one recording, ordered by time, treated as one timeline.
+/// The last segment is the active one of a live session.
+pub struct SegmentManager {
+    segments: Vec<Arc<SampleSegment>>,
+}
+
+impl SegmentManager {
+    pub fn new() -> SegmentManager {
+        SegmentManager {
+            segments: vec![],
+        }
+    }
+
+    pub fn add_segment(&mut self, segment: SampleSegment) {
+        self.segments.push(Arc::new(segment));
+        self.segments.sort_by_key(|s| s.start_time);
+    }
+
+    pub fn get_segments(&self) -> &Vec<Arc<SampleSegment>> {
+        &self.segments
+    }
+
+    //the active segment is never shared with snapshots
+    pub fn active_segment_mut(&mut self) -> Option<&mut SampleSegment> {
+        self.segments.last_mut().and_then(|s| Arc::get_mut(s))
+    }
+
+    /// Readonly view for queries, sealed segments are shared and the active segment is copied.
+    pub fn snapshot(&self) -> SegmentManager {
+        SegmentManager {
+            segments: self.segments.iter().map(|s| {
+                if s.sealed { s.clone() } else { Arc::new(s.snapshot()) }
+            }).collect()
+        }
+    }
+
+    /// Close writers of the active segment and reopen its thread files in read mode.
+    pub fn seal_active_segment(&mut self) {
+        if let Some(segment) = self.active_segment_mut() {
+            let thread_ids: Vec<JavaLong> = segment.cpu_ts_map.keys().cloned().collect();
+            //drop writers to flush data files
+            segment.cpu_ts_map.clear();
+            segment.stacktrace_map.clear();
+            segment.backfill_map.clear();
+            segment.open_thread_files(&thread_ids);
+            segment.sealed = true;
+        }
+    }
+
+    //negative time means unlimited
+    fn get_overlapped_range(segment: &SampleSegment, start_time: i64, end_time: i64) -> Option<(i64, i64)> {
+        let start_time = if start_time < 0 { segment.start_time } else { start_time };
+        let end_time = if end_time < 0 { segment.end_time } else { end_time };
+        if segment.is_overlapped(start_time, end_time) {
+            Some((start_time.max(segment.start_time), end_time.min(segment.end_time)))
+        } else {
+            None
+        }
+    }
+
+    /// Visit encoded thread samples in time range over all segments, return false if the thread is not found.
+    /// The handler returns false to stop visiting the remaining samples.
+    pub fn visit_thread_samples<F>(&self, thread_id: JavaLong, start_time: i64, end_time: i64, mut handler: F) -> bool
+        where F: FnMut(Vec<u8>) -> bool {
+        let mut found = false;
+        let mut stopped = false;
+        for segment in self.segments.iter() {
+            if stopped {
+                break;
+            }
+            let (seg_start_time, seg_end_time) = match SegmentManager::get_overlapped_range(segment, start_time, end_time) {
+                Some(range) => range,
+                None => continue
+            };
+            let (start_step, end_step) = match segment.cpu_ts_map.get(&thread_id).unwrap_or(&None) {
+                Some(ts_file) => (ts_file.time_to_step(seg_start_time), ts_file.time_to_step(seg_end_time)),
+                None => continue
+            };
+            if let Some(idx_file) = segment.stacktrace_map.get(&thread_id).unwrap_or(&None) {
+                found = true;
+                let start_index = TupleValue::uint32(start_step);
+                let end_index = TupleValue::uint32(end_step);
+                let mut handler = |bytes| {
+                    stopped = !handler(bytes);
+                    !stopped
+                };
+                let result = match segment.backfill_map.get(&thread_id).unwrap_or(&None) {
+                    Some(backfill_file) => SegmentManager::visit_merged_samples(idx_file, backfill_file, &start_index, &end_index, &mut handler),
+                    None => idx_file.get_range_value(&start_index, &end_index, &mut handler)
+                };
+                if let Err(e) = result {
+                    println!("read thread stacktrace failed: thread: {}, dir: {}, err: {}", thread_id, segment.data_dir, e);
+                }
+            }
+        }
+        found
+    }
+
+    //merge backfilled samples into the downsampled stream by time steps
+    fn visit_merged_samples<F>(idx_file: &TupleIndexedFile, backfill_file: &TupleIndexedFile, start_index: &TupleValue, end_index: &TupleValue, handler: &mut F) -> io::Result<()>
+        where F: FnMut(Vec<u8>) -> bool {
+        let mut backfill = VecDeque::new();
+        let (start_step, end_step) = (start_index.as_int(), end_index.as_int());
+        //nearest entries are returned if the range is out of the backfilled steps
+        if let Err(e) = backfill_file.get_range_entries(start_index, end_index, |index, bytes| {
+            if index.as_int() >= start_step && index.as_int() <= end_step {
+                backfill.push_back((index.as_int(), bytes));
+            }
+            true
+        }) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let mut stopped = false;
+        idx_file.get_range_entries(start_index, end_index, |index, bytes| {
+            while backfill.front().map_or(false, |(step, _)| *step < index.as_int()) {
+                if !handler(backfill.pop_front().unwrap().1) {
+                    stopped = true;
+                    return false;
+                }
+            }
+            stopped = !handler(bytes);
+            !stopped
+        })?;
+        if !stopped {
+            for (_, bytes) in backfill {
+                if !handler(bytes) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get thread cpu time in time range, the parts of segments are joined into one time series.
+    pub fn get_thread_cpu_time(&self, thread_id: JavaLong, start_time: i64, end_time: i64, unit_time_ms: i32) -> Option<TSResult> {
+        let mut parts = vec![];
+        for segment in &self.segments {
+            let (seg_start_time, seg_end_time) = match SegmentManager::get_overlapped_range(segment, start_time, end_time) {
+                Some(range) => range,
+                None => continue
+            };
+            if let Some(ts_file) = segment.cpu_ts_map.get(&thread_id).unwrap_or(&None) {
+                parts.push(ts_file.get_range_value(seg_start_time, seg_end_time, unit_time_ms));
+            }
+        }
+        if parts.len() <= 1 {
+            return parts.pop();
+        }
+
+        let begin_time = parts[0].begin_time;
+        let unit_time = parts[0].unit_time;
+        let mut data: Vec<i64> = vec![];
+        let mut total_cpu_time = 0;
+        let mut end_time = begin_time;
+        for part in &parts {
+            let values = part.data.as_int64().unwrap_or(vec![]);
+            let offset = ((part.begin_time - begin_time) / unit_time.max(1) as i64) as usize;
+            if data.len() < offset + values.len() {
+                data.resize(offset + values.len(), 0);
+            }
+            for (i, value) in values.iter().enumerate() {
+                data[offset + i] += *value;
+            }
+            total_cpu_time += part.total_cpu_time;
+            end_time = part.end_time;
+        }
+        Some(TSResult {
+            begin_time,
+            end_time,
+            unit_time,
+            steps: data.len() as i32,
+            total_cpu_time,
+            data: TSRangeValue::vec_int64(data),
+        })
+    }
+}
+
+pub fn load_summary_info(sample_data_dir: &str) -> io::Result<SummaryInfo> {
+    let path = format!("{}/summary_info.json", sample_data_dir);
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str::<SummaryInfo>(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
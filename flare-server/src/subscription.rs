@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use websocket::sender::Writer;
+use serde_json::json;
+use sample::{SampleEvent, ThreadData};
+use utils::*;
+
+//push rate of a subscription
+pub const DEFAULT_PUSH_INTERVAL_MS: i64 = 1000;
+pub const MIN_PUSH_INTERVAL_MS: i64 = 200;
+pub const MAX_PUSH_INTERVAL_MS: i64 = 60_000;
+
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Websocket client connection, responses and pushed updates share the writer.
+#[derive(Clone)]
+pub struct WsClient {
+    pub id: usize,
+    pub writer: Arc<Mutex<Writer<TcpStream>>>,
+}
+
+impl WsClient {
+    pub fn new(writer: Writer<TcpStream>) -> WsClient {
+        WsClient {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::SeqCst),
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SubscriptionType {
+    //thread list and state changes of dashboard
+    DASHBOARD,
+    //new cpu time points of threads
+    CPU_TIME,
+}
+
+impl SubscriptionType {
+    fn get_push_cmd(&self) -> &'static str {
+        match self {
+            SubscriptionType::DASHBOARD => "dashboard_update",
+            SubscriptionType::CPU_TIME => "cpu_time_update",
+        }
+    }
+}
+
+struct Subscription {
+    client_id: usize,
+    session_id: String,
+    //cleared to stop the push thread
+    active: Arc<AtomicBool>,
+}
+
+/// Live push subscriptions of websocket clients, each subscription has a push thread
+/// which merges the sample events received in the interval into one update message.
+pub struct SubscriptionManager {
+    next_id: usize,
+    subscriptions: HashMap<usize, Subscription>,
+}
+
+impl SubscriptionManager {
+    pub fn new() -> SubscriptionManager {
+        SubscriptionManager {
+            next_id: 1,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    /// Start pushing updates of the session to the client, return the subscription id.
+    pub fn subscribe(&mut self, client: &WsClient, session_id: &str, sub_type: SubscriptionType, receiver: Receiver<SampleEvent>,
+                     interval_ms: i64, thread_ids: Vec<i64>) -> usize {
+        //remove the subscriptions stopped by themselves
+        self.subscriptions.retain(|_, s| s.active.load(Ordering::SeqCst));
+
+        let subscription_id = self.next_id;
+        self.next_id += 1;
+        let active = Arc::new(AtomicBool::new(true));
+        self.subscriptions.insert(subscription_id, Subscription {
+            client_id: client.id,
+            session_id: session_id.to_string(),
+            active: active.clone(),
+        });
+
+        let mut push_task = PushTask {
+            subscription_id,
+            session_id: session_id.to_string(),
+            sub_type,
+            thread_ids,
+            writer: client.writer.clone(),
+            active,
+            receiver,
+        };
+        let interval = Duration::from_millis(interval_ms.max(MIN_PUSH_INTERVAL_MS).min(MAX_PUSH_INTERVAL_MS) as u64);
+        thread::spawn(move || {
+            push_task.run(interval);
+        });
+        println!("add subscription: {}, type: {:?}, session: {}, client: {}", subscription_id, sub_type, session_id, client.id);
+        subscription_id
+    }
+
+    /// Cancel a subscription of the client.
+    pub fn unsubscribe(&mut self, client_id: usize, subscription_id: usize) -> bool {
+        match self.subscriptions.get(&subscription_id) {
+            Some(s) if s.client_id == client_id => {},
+            _ => return false
+        }
+        if let Some(s) = self.subscriptions.remove(&subscription_id) {
+            s.active.store(false, Ordering::SeqCst);
+            println!("remove subscription: {}, session: {}", subscription_id, s.session_id);
+        }
+        true
+    }
+
+    /// Cancel all subscriptions of the closed client, return the count.
+    pub fn remove_client(&mut self, client_id: usize) -> usize {
+        let ids: Vec<usize> = self.subscriptions.iter()
+            .filter(|(_, s)| s.client_id == client_id)
+            .map(|(id, _)| *id).collect();
+        for id in &ids {
+            self.unsubscribe(client_id, *id);
+        }
+        ids.len()
+    }
+}
+
+struct PushTask {
+    subscription_id: usize,
+    session_id: String,
+    sub_type: SubscriptionType,
+    //empty means all threads
+    thread_ids: Vec<i64>,
+    writer: Arc<Mutex<Writer<TcpStream>>>,
+    active: Arc<AtomicBool>,
+    receiver: Receiver<SampleEvent>,
+}
+
+impl PushTask {
+    fn run(&mut self, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            if !self.active.load(Ordering::SeqCst) {
+                break;
+            }
+
+            //merge events received in the interval
+            let mut new_threads: Vec<ThreadData> = vec![];
+            let mut updated_threads: HashMap<i64, ThreadData> = HashMap::new();
+            let mut cpu_points: HashMap<i64, Vec<(i64, i64)>> = HashMap::new();
+            let mut disconnected = false;
+            let mut reconnecting = false;
+            let mut gaps = vec![];
+            let mut markers = vec![];
+            //updates are dropped, the client should refetch the session data
+            let mut resync = false;
+            loop {
+                match self.receiver.try_recv() {
+                    Ok(SampleEvent::ThreadSample(thread, is_new)) => {
+                        let subscribed = self.thread_ids.is_empty() || self.thread_ids.contains(&thread.id);
+                        if is_new && (subscribed || self.sub_type == SubscriptionType::DASHBOARD) {
+                            new_threads.push(thread.clone());
+                        }
+                        if subscribed {
+                            cpu_points.entry(thread.id).or_insert_with(|| vec![]).push((thread.sample_time, thread.cpu_time_delta / 1000));
+                        }
+                        updated_threads.insert(thread.id, thread);
+                    },
+                    Ok(SampleEvent::Reconnecting) => reconnecting = true,
+                    Ok(SampleEvent::Gap(gap)) => {
+                        reconnecting = false;
+                        gaps.push(gap);
+                    },
+                    Ok(SampleEvent::Trigger(marker)) => markers.push(marker),
+                    Ok(SampleEvent::Lagging) => resync = true,
+                    Ok(SampleEvent::Disconnected) | Err(TryRecvError::Disconnected) => {
+                        //agent disconnected or session closed
+                        disconnected = true;
+                        break;
+                    },
+                    Err(TryRecvError::Empty) => break
+                }
+            }
+            if updated_threads.is_empty() && !disconnected && !reconnecting && !resync && gaps.is_empty() && markers.is_empty() {
+                continue;
+            }
+
+            let last_record_time = updated_threads.values().map(|t| t.sample_time).max().unwrap_or(0);
+            let data = match self.sub_type {
+                SubscriptionType::DASHBOARD => {
+                    let mut threads: Vec<ThreadData> = updated_threads.into_iter().map(|(_, t)| t).collect();
+                    threads.sort_by_key(|t| t.id);
+                    json!({
+                        "subscription_id": self.subscription_id,
+                        "session_id": self.session_id,
+                        "last_record_time": last_record_time,
+                        "new_threads": new_threads,
+                        "threads": threads,
+                        "gaps": gaps,
+                        "markers": markers,
+                        "reconnecting": reconnecting,
+                        "resync": resync,
+                        "disconnected": disconnected
+                    })
+                },
+                SubscriptionType::CPU_TIME => {
+                    let mut thread_cpu_times: Vec<serde_json::Value> = cpu_points.into_iter().map(|(thread_id, points)| json!({
+                        "id": thread_id,
+                        "points": points
+                    })).collect();
+                    thread_cpu_times.sort_by_key(|x| x["id"].as_i64());
+                    let new_threads: Vec<serde_json::Value> = new_threads.iter().map(|t| json!({"id": t.id, "name": t.name})).collect();
+                    json!({
+                        "subscription_id": self.subscription_id,
+                        "session_id": self.session_id,
+                        "last_record_time": last_record_time,
+                        "new_threads": new_threads,
+                        "thread_cpu_times": thread_cpu_times,
+                        "gaps": gaps,
+                        "markers": markers,
+                        "reconnecting": reconnecting,
+                        "resync": resync,
+                        "disconnected": disconnected
+                    })
+                }
+            };
+            let message = wrap_response(self.sub_type.get_push_cmd(), &data);
+            if let Err(e) = self.writer.lock().unwrap().send_message(&message) {
+                println!("push subscription update failed: {}, err: {}", self.subscription_id, e);
+                break;
+            }
+            if disconnected {
+                break;
+            }
+        }
+        self.active.store(false, Ordering::SeqCst);
+        println!("subscription is stopped: {}, session: {}", self.subscription_id, self.session_id);
+    }
+}
@@ -0,0 +1,225 @@
+use std::io;
+use std::cmp::max;
+use regex::Regex;
+use tree::TreeNode;
+use aggregation::get_package_name;
+use utils::*;
+
+/// Frame transforms of trees and flame graphs, parsed from the 'transforms' option:
+/// {"hide_frames": ["^sun\\.reflect\\.", "\\$\\$EnhancerByCGLIB\\$\\$"], "collapse_recursion": true,
+///  "fold_packages": true, "focus_method": "com\\.foo\\.Bar\\.doWork", "max_depth": 30}
+/// Transforms are applied in order: hide, collapse recursion, fold packages, focus, trim depth.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TransformOptions {
+    //regexes of frames to hide, the children are moved up to the parent frame
+    #[serde(default)]
+    pub hide_frames: Vec<String>,
+    //merge directly recursive calls into the outermost frame
+    #[serde(default)]
+    pub collapse_recursion: bool,
+    //fold consecutive frames of the same package into the first frame
+    #[serde(default)]
+    pub fold_packages: bool,
+    //regex of method name, keep only stacks through it and re-root at it
+    #[serde(default)]
+    pub focus_method: String,
+    //remove frames deeper than max depth, zero means unlimited
+    #[serde(default)]
+    pub max_depth: i32,
+}
+
+pub struct TransformPipeline {
+    //normalized options json, used in range cache key
+    pub key: String,
+    options: TransformOptions,
+    hide_regexes: Vec<Regex>,
+    focus_regex: Option<Regex>,
+}
+
+impl TransformPipeline {
+    pub fn new(options: TransformOptions) -> io::Result<TransformPipeline> {
+        let mut hide_regexes = vec![];
+        for pattern in &options.hide_frames {
+            if pattern.is_empty() {
+                continue;
+            }
+            hide_regexes.push(compile_regex(pattern)?);
+        }
+        let focus_regex = if options.focus_method.is_empty() { None } else { Some(compile_regex(&options.focus_method)?) };
+        let mut pipeline = TransformPipeline {
+            key: String::new(),
+            options,
+            hide_regexes,
+            focus_regex,
+        };
+        if !pipeline.is_empty() {
+            pipeline.key = serde_json::to_string(&pipeline.options)?;
+        }
+        Ok(pipeline)
+    }
+
+    /// Parse the 'transforms' option, return an empty pipeline if it is absent.
+    pub fn parse(options: &serde_json::Map<String, serde_json::Value>) -> io::Result<TransformPipeline> {
+        let transform_options = match options.get("transforms") {
+            Some(value) if !value.is_null() => {
+                serde_json::from_value::<TransformOptions>(value.clone())
+                    .map_err(|e| new_invalid_input_error(&format!("invalid option 'transforms': {}", e)))?
+            },
+            _ => TransformOptions::default()
+        };
+        TransformPipeline::new(transform_options)
+    }
+
+    pub fn get_options(&self) -> &TransformOptions {
+        &self.options
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hide_regexes.is_empty() && !self.options.collapse_recursion && !self.options.fold_packages
+            && self.focus_regex.is_none() && self.options.max_depth <= 0
+    }
+
+    fn is_hidden(&self, label: &str) -> bool {
+        self.hide_regexes.iter().any(|r| r.is_match(label))
+    }
+
+    /// Transform the tree in place. Sibling frames of the sequenced tree are merged only when
+    /// they are adjacent in time, other trees merge all siblings with the same label.
+    pub fn apply_tree(&self, root: &mut TreeNode, sequenced: bool) {
+        if self.is_empty() {
+            return;
+        }
+        if !self.hide_regexes.is_empty() {
+            splice_children(root, sequenced, &|_, child| self.is_hidden(&child.label));
+        }
+        if self.options.collapse_recursion {
+            splice_children(root, sequenced, &|parent, child| parent.label == child.label);
+        }
+        if self.options.fold_packages {
+            splice_children(root, sequenced, &|parent, child| {
+                let package = get_package_name(&child.label);
+                !package.is_empty() && package == get_package_name(&parent.label)
+            });
+        }
+        if let Some(regex) = &self.focus_regex {
+            let mut focused = vec![];
+            for child in std::mem::replace(&mut root.children, vec![]) {
+                collect_focused_nodes(child, regex, &mut focused);
+            }
+            for node in focused {
+                add_child(root, node, sequenced);
+            }
+            //sequenced root keeps the time range of the flame graph
+            if !sequenced {
+                root.duration = root.children.iter().map(|x| x.duration).sum();
+                root.cpu = root.children.iter().map(|x| x.cpu).sum();
+                root.calls = root.children.iter().map(|x| x.calls).sum();
+            }
+        }
+        update_depth(root, 0);
+        if self.options.max_depth > 0 {
+            root.prune_depth(self.options.max_depth);
+        }
+    }
+
+    /// Transform a collapsed stack line "a;b;c value", return None if the stack is dropped.
+    pub fn apply_collapsed_stack(&self, line: &str) -> Option<String> {
+        if self.is_empty() {
+            return Some(line.to_string());
+        }
+        let (stack, value) = match line.rfind(' ') {
+            Some(pos) => (&line[..pos], &line[pos..]),
+            None => (line, "")
+        };
+        let mut frames: Vec<&str> = stack.split(';').filter(|frame| !self.is_hidden(frame)).collect();
+        if self.options.collapse_recursion {
+            frames.dedup();
+        }
+        if self.options.fold_packages {
+            frames.dedup_by(|frame, prev_frame| {
+                let package = get_package_name(frame);
+                !package.is_empty() && package == get_package_name(prev_frame)
+            });
+        }
+        if let Some(regex) = &self.focus_regex {
+            match frames.iter().position(|frame| regex.is_match(frame)) {
+                Some(pos) => { frames.drain(..pos); },
+                None => return None
+            }
+        }
+        if self.options.max_depth > 0 {
+            frames.truncate(self.options.max_depth as usize);
+        }
+        if frames.is_empty() {
+            return None;
+        }
+        Some(frames.join(";") + value)
+    }
+
+    pub fn apply_collapsed_stacks(&self, lines: &[String]) -> Vec<String> {
+        lines.iter().filter_map(|line| self.apply_collapsed_stack(line)).collect()
+    }
+}
+
+fn compile_regex(pattern: &str) -> io::Result<Regex> {
+    Regex::new(pattern).map_err(|e| new_invalid_input_error(&format!("invalid regex: {}, err: {}", pattern, e)))
+}
+
+//remove matched children (bottom up) and move their children up to the parent.
+//tree node stats are inclusive, so the parent stats are unchanged.
+pub fn splice_children<F>(node: &mut TreeNode, sequenced: bool, should_splice: &F)
+    where F: Fn(&TreeNode, &TreeNode) -> bool {
+    for mut child in std::mem::replace(&mut node.children, vec![]) {
+        splice_children(&mut child, sequenced, should_splice);
+        if should_splice(node, &child) {
+            for grandchild in std::mem::replace(&mut child.children, vec![]) {
+                add_child(node, grandchild, sequenced);
+            }
+        } else {
+            add_child(node, child, sequenced);
+        }
+    }
+}
+
+//append child, merge into the sibling with the same label
+pub fn add_child(node: &mut TreeNode, mut child: Box<TreeNode>, sequenced: bool) {
+    let sibling = if sequenced {
+        //only merge the last sibling adjacent in time
+        node.children.last_mut().filter(|x| x.label == child.label && child.start_time <= x.start_time + x.duration)
+    } else {
+        node.children.iter_mut().find(|x| x.label == child.label)
+    };
+    match sibling {
+        Some(sibling) => {
+            if sequenced {
+                sibling.duration = max(sibling.duration, child.start_time + child.duration - sibling.start_time);
+            } else {
+                sibling.duration += child.duration;
+            }
+            sibling.cpu += child.cpu;
+            sibling.calls += child.calls;
+            for grandchild in std::mem::replace(&mut child.children, vec![]) {
+                add_child(sibling, grandchild, sequenced);
+            }
+        },
+        None => node.children.push(child)
+    }
+}
+
+//outermost nodes matching the focus regex, in tree order
+fn collect_focused_nodes(mut node: Box<TreeNode>, regex: &Regex, focused: &mut Vec<Box<TreeNode>>) {
+    if regex.is_match(&node.label) {
+        focused.push(node);
+        return;
+    }
+    for child in std::mem::replace(&mut node.children, vec![]) {
+        collect_focused_nodes(child, regex, focused);
+    }
+}
+
+pub fn update_depth(node: &mut TreeNode, depth: i32) {
+    node.depth = depth;
+    for child in &mut node.children {
+        update_depth(child, depth + 1);
+    }
+}
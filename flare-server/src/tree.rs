@@ -1,9 +1,10 @@
 
 use std::sync::{Mutex, Arc};
-use std::cmp::min;
+use std::cmp::{min, max};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sample::ThreadData;
+use sample::{ThreadData, StatsType};
+use std::collections::{HashMap, HashSet};
 
 
 #[derive(Serialize, Deserialize)]
@@ -60,4 +61,356 @@ impl TreeNode {
         }
         return false;
     }
+
+    //find node by path of child indexes
+    pub fn get_node_by_path(&self, node_path: &[i64]) -> Option<&TreeNode> {
+        let mut node = self;
+        for index in node_path {
+            if *index < 0 {
+                return None;
+            }
+            match node.children.get(*index as usize) {
+                Some(child) => node = child,
+                None => return None
+            }
+        }
+        Some(node)
+    }
+
+    pub fn get_stats_value(&self, stats_type: &StatsType) -> i64 {
+        match stats_type {
+            StatsType::DURATION => self.duration,
+            StatsType::CPU_TIME => self.cpu,
+            StatsType::SAMPLES => self.calls,
+        }
+    }
+
+    //node json without children, for lazy loading
+    pub fn to_summary_json(&self, node_path: &[i64]) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "label": self.label,
+            "calls": self.calls,
+            "cpu": self.cpu,
+            "duration": self.duration,
+            "start_time": self.start_time,
+            "depth": self.depth,
+            "path": node_path,
+            "children_count": self.children.len()
+        })
+    }
+
+    pub fn get_max_depth(&self) -> i32 {
+        let mut depth = self.depth;
+        for child in &self.children {
+            depth = max(depth, child.get_max_depth());
+        }
+        depth
+    }
+
+    //remove nodes deeper than max_depth, return node count of this subtree
+    pub fn prune_depth(&mut self, max_depth: i32) -> usize {
+        if self.depth >= max_depth {
+            self.children.clear();
+            return 1;
+        }
+        let mut count = 1;
+        for child in &mut self.children {
+            count += child.prune_depth(max_depth);
+        }
+        count
+    }
+}
+
+//min tree depth when coarsening
+pub const MIN_COARSEN_TREE_DEPTH: i32 = 8;
+
+/// Build sequenced tree (flame graph order) incrementally from thread samples.
+/// When node count exceeds max_nodes, the tree is coarsened by pruning the deep half of the tree,
+/// at MIN_COARSEN_TREE_DEPTH no more new nodes are appended, samples only merge into the last nodes.
+pub struct SequencedTreeBuilder {
+    root: Box<TreeNode>,
+    range_start_time: i64,
+    range_end_time: i64,
+    node_count: usize,
+    max_nodes: usize,
+    max_depth: i32,
+    frozen: bool,
+}
+
+impl SequencedTreeBuilder {
+    pub fn new(max_nodes: usize) -> SequencedTreeBuilder {
+        SequencedTreeBuilder {
+            root: Box::new(TreeNode::new(0, "root")),
+            range_start_time: -1,
+            range_end_time: -1,
+            node_count: 1,
+            max_nodes,
+            max_depth: std::i32::MAX,
+            frozen: false,
+        }
+    }
+
+    //每一层与最后一个节点相同时进行合并，不同时append新节点
+    pub fn add_thread_data(&mut self, thread_data: &ThreadData) {
+        if self.range_start_time < 0 {
+            self.range_start_time = thread_data.sample_time;
+        }
+        self.range_end_time = thread_data.sample_time + thread_data.duration;
+        if !self.frozen && self.node_count + thread_data.stacktrace.len() > self.max_nodes {
+            self.coarsen();
+        }
+
+        let mut appended = 0;
+        let root = &mut self.root;
+        root.duration += thread_data.self_duration;
+        root.cpu += thread_data.self_cpu_time;
+        root.calls += 1;
+        let mut node = root;
+        let mut start_time = thread_data.sample_time - self.range_start_time;
+        if start_time < 0 {
+            start_time = 0;
+        }
+        for method in thread_data.stacktrace.iter().rev() {
+            if node.depth >= self.max_depth {
+                break;
+            }
+            //merge_last_child fn return bool instead of node reference for avoiding second borrow mutable node
+            if node.merge_last_child(*method, thread_data.self_duration, thread_data.self_cpu_time, 1) {
+                //merge success, next is just last child
+                node = node.last_child().unwrap();
+            } else {
+                if self.frozen {
+                    break;
+                }
+                let child_depth = node.depth+1;
+                node = node.append_child(TreeNode{
+                    parent: None,
+                    children: vec![],
+                    depth: child_depth,
+                    id: *method,
+                    //method name is filled after building
+                    label: String::new(),
+                    calls: 1,
+                    cpu: thread_data.self_cpu_time,
+                    duration: thread_data.self_duration,
+                    start_time
+                });
+                appended += 1;
+            }
+        }
+        self.node_count += appended;
+    }
+
+    fn coarsen(&mut self) {
+        let depth = min(self.root.get_max_depth(), self.max_depth);
+        let new_max_depth = max(depth / 2, MIN_COARSEN_TREE_DEPTH);
+        if new_max_depth < self.max_depth {
+            self.max_depth = new_max_depth;
+            self.node_count = self.root.prune_depth(new_max_depth);
+            println!("sequenced tree exceeds max nodes: {}, coarsen to depth: {}, nodes: {}", self.max_nodes, new_max_depth, self.node_count);
+        }
+        if self.node_count >= self.max_nodes || new_max_depth >= depth {
+            self.frozen = true;
+            println!("sequenced tree exceeds max nodes: {}, stop appending new nodes", self.max_nodes);
+        }
+    }
+
+    pub fn is_coarsened(&self) -> bool {
+        self.frozen || self.max_depth != std::i32::MAX
+    }
+
+    pub fn get_range(&self) -> (i64, i64) {
+        (self.range_start_time, self.range_end_time)
+    }
+
+    pub fn finish(mut self) -> Box<TreeNode> {
+        if self.is_coarsened() {
+            self.root.label = get_coarsened_label("root", self.max_depth, self.frozen);
+        }
+        self.root
+    }
+}
+
+/// Build bottom-up (callers) tree: self time of a sample is attributed to the leaf method
+/// under root, and expanded toward the thread root method. When the node count exceeds max_nodes,
+/// no more new nodes are appended, samples are counted to the deepest existing node.
+pub struct BottomUpTreeBuilder {
+    root: Box<TreeNode>,
+    node_count: usize,
+    max_nodes: usize,
+    truncated: bool,
+}
+
+impl BottomUpTreeBuilder {
+    pub fn new(max_nodes: usize) -> BottomUpTreeBuilder {
+        BottomUpTreeBuilder {
+            root: Box::new(TreeNode::new(0, "root")),
+            node_count: 1,
+            max_nodes,
+            truncated: false,
+        }
+    }
+
+    //stacktrace of thread data is ordered from leaf to root
+    pub fn add_thread_data(&mut self, thread_data: &ThreadData) {
+        let mut node = &mut self.root;
+        node.calls += 1;
+        node.duration += thread_data.self_duration;
+        node.cpu += thread_data.self_cpu_time;
+        for method in &thread_data.stacktrace {
+            let index = match node.children.iter().position(|child| child.id == *method) {
+                Some(index) => index,
+                None => {
+                    if self.node_count >= self.max_nodes {
+                        self.truncated = true;
+                        break;
+                    }
+                    let mut child = TreeNode::new(*method, "");
+                    child.depth = node.depth + 1;
+                    node.children.push(Box::new(child));
+                    self.node_count += 1;
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[index];
+            node.calls += 1;
+            node.duration += thread_data.self_duration;
+            node.cpu += thread_data.self_cpu_time;
+        }
+    }
+
+    pub fn finish(mut self) -> Box<TreeNode> {
+        if self.truncated {
+            self.root.label = get_coarsened_label("root", std::i32::MAX, true);
+        }
+        self.root
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct MethodStats {
+    pub duration: i64,
+    pub cpu: i64,
+    pub samples: i64,
+}
+
+impl MethodStats {
+    pub fn add(&mut self, thread_data: &ThreadData) {
+        self.duration += thread_data.self_duration;
+        self.cpu += thread_data.self_cpu_time;
+        self.samples += 1;
+    }
+
+    pub fn merge(&mut self, other: &MethodStats) {
+        self.duration += other.duration;
+        self.cpu += other.cpu;
+        self.samples += other.samples;
+    }
+
+    pub fn get_stats_value(&self, stats_type: &StatsType) -> i64 {
+        match stats_type {
+            StatsType::DURATION => self.duration,
+            StatsType::CPU_TIME => self.cpu,
+            StatsType::SAMPLES => self.samples,
+        }
+    }
+}
+
+/// Callers and callees of a method aggregated from samples,
+/// each sample is counted once per method even if the method is recursive.
+pub struct MethodButterfly {
+    pub method_id: i64,
+    pub self_stats: MethodStats,
+    pub total_stats: MethodStats,
+    pub callers: HashMap<i64, MethodStats>,
+    pub callees: HashMap<i64, MethodStats>,
+}
+
+impl MethodButterfly {
+    pub fn new(method_id: i64) -> MethodButterfly {
+        MethodButterfly {
+            method_id,
+            self_stats: MethodStats::default(),
+            total_stats: MethodStats::default(),
+            callers: HashMap::new(),
+            callees: HashMap::new(),
+        }
+    }
+
+    //stacktrace of thread data is ordered from leaf to root
+    pub fn add_thread_data(&mut self, thread_data: &ThreadData) {
+        let stack = &thread_data.stacktrace;
+        if !stack.contains(&self.method_id) {
+            return;
+        }
+        self.total_stats.add(thread_data);
+        if stack[0] == self.method_id {
+            self.self_stats.add(thread_data);
+        }
+        let mut callers = HashSet::new();
+        let mut callees = HashSet::new();
+        for (i, method) in stack.iter().enumerate() {
+            if *method != self.method_id {
+                continue;
+            }
+            if i + 1 < stack.len() {
+                callers.insert(stack[i + 1]);
+            }
+            if i > 0 {
+                callees.insert(stack[i - 1]);
+            }
+        }
+        for caller in callers {
+            self.callers.entry(caller).or_insert_with(MethodStats::default).add(thread_data);
+        }
+        for callee in callees {
+            self.callees.entry(callee).or_insert_with(MethodStats::default).add(thread_data);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MethodProfile {
+    pub self_stats: MethodStats,
+    pub total_stats: MethodStats,
+}
+
+/// Flat profile of methods, inclusive stats of a recursive method are counted once per sample.
+pub struct FlatProfileBuilder {
+    pub methods: HashMap<i64, MethodProfile>,
+    pub total_stats: MethodStats,
+}
+
+impl FlatProfileBuilder {
+    pub fn new() -> FlatProfileBuilder {
+        FlatProfileBuilder {
+            methods: HashMap::new(),
+            total_stats: MethodStats::default(),
+        }
+    }
+
+    //stacktrace of thread data is ordered from leaf to root
+    pub fn add_thread_data(&mut self, thread_data: &ThreadData) {
+        self.total_stats.add(thread_data);
+        let stack = &thread_data.stacktrace;
+        if let Some(leaf) = stack.first() {
+            self.methods.entry(*leaf).or_insert_with(MethodProfile::default).self_stats.add(thread_data);
+        }
+        let mut counted = HashSet::with_capacity(stack.len());
+        for method in stack {
+            if counted.insert(*method) {
+                self.methods.entry(*method).or_insert_with(MethodProfile::default).total_stats.add(thread_data);
+            }
+        }
+    }
+}
+
+//mark coarsened tree in root label
+pub fn get_coarsened_label(name: &str, max_depth: i32, frozen: bool) -> String {
+    if max_depth != std::i32::MAX {
+        format!("{} (coarsened, max depth: {}{})", name, max_depth, if frozen { ", truncated" } else { "" })
+    } else {
+        format!("{} (coarsened, truncated)", name)
+    }
 }
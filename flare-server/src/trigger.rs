@@ -0,0 +1,410 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use sample::ThreadData;
+use utils::*;
+pub use flare_utils::sample_format::TriggerMarker;
+
+type JavaLong = i64;
+
+pub const DEFAULT_PRE_WINDOW_MS: i64 = 30_000;
+pub const DEFAULT_POST_WINDOW_MS: i64 = 30_000;
+pub const DEFAULT_DOWNSAMPLE_RATIO: u32 = 10;
+pub const DEFAULT_MAX_PENDING_MB: i64 = 64;
+//max time of samples kept in memory before a trigger
+const MAX_PRE_WINDOW_MS: i64 = 600_000;
+//method presence is not evaluated until the window has enough samples
+const MIN_PRESENCE_SAMPLES: usize = 10;
+//approximate memory of a pending sample besides name and stack frames
+const SAMPLE_BASE_SIZE: usize = 128;
+
+/// Condition of a trigger rule, evaluated on each sample of the live stream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TriggerCondition {
+    //cpu usage of any thread is above the percent for the duration
+    ThreadCpu { percent: f64, duration_ms: i64 },
+    //count of live threads is above the limit
+    ThreadCount { max_threads: usize },
+    //method name containing the pattern is in the stacks of more than percent of samples in the window
+    MethodPresence { method: String, percent: f64, window_ms: i64 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TriggerRule {
+    pub name: String,
+    pub condition: TriggerCondition,
+}
+
+/// Conditional recording of a live session: samples around triggers are saved in full resolution,
+/// one of `downsample_ratio` samples of each thread is saved outside trigger windows.
+/// The dropped samples of pre window are kept in memory up to `max_pending_bytes`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TriggerPolicy {
+    pub rules: Vec<TriggerRule>,
+    pub pre_window_ms: i64,
+    pub post_window_ms: i64,
+    //1 keeps all samples
+    pub downsample_ratio: u32,
+    pub max_pending_bytes: usize,
+}
+
+impl TriggerPolicy {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.rules.is_empty() {
+            return Err(new_invalid_input_error("trigger rules are required"));
+        }
+        for rule in &self.rules {
+            let valid = match &rule.condition {
+                TriggerCondition::ThreadCpu { percent, duration_ms } => *percent > 0.0 && *duration_ms >= 0,
+                TriggerCondition::ThreadCount { max_threads } => *max_threads > 0,
+                TriggerCondition::MethodPresence { method, percent, window_ms } => !method.is_empty() && *percent > 0.0 && *percent <= 100.0 && *window_ms > 0,
+            };
+            if rule.name.is_empty() || !valid {
+                return Err(new_invalid_input_error(&format!("invalid trigger rule: {:?}", rule)));
+            }
+        }
+        if self.pre_window_ms < 0 || self.pre_window_ms > MAX_PRE_WINDOW_MS {
+            return Err(new_invalid_input_error(&format!("pre_window_ms must be in range 0 - {}: {}", MAX_PRE_WINDOW_MS, self.pre_window_ms)));
+        }
+        if self.post_window_ms < 0 {
+            return Err(new_invalid_input_error(&format!("post_window_ms must not be negative: {}", self.post_window_ms)));
+        }
+        if self.downsample_ratio == 0 {
+            return Err(new_invalid_input_error("downsample_ratio must be positive"));
+        }
+        if self.max_pending_bytes == 0 {
+            return Err(new_invalid_input_error("max_pending_mb must be positive"));
+        }
+        Ok(())
+    }
+}
+
+struct RuleState {
+    //condition holds, a new marker is fired after it is cleared
+    active: bool,
+    //thread cpu: start time of continuous high cpu usage of threads
+    high_cpu_since: HashMap<JavaLong, i64>,
+    //method presence: method keys matched the pattern, and (sample time, matched) in the window
+    method_keys: HashSet<i64>,
+    samples: VecDeque<(i64, bool)>,
+    matched_count: usize,
+}
+
+//cpu time delta of the dropped samples is added to the next saved sample of the thread
+struct DownsampleState {
+    skipped: u32,
+    cpu_time_delta: i64,
+}
+
+/// Evaluate trigger rules and decide which samples are saved.
+pub struct TriggerEngine {
+    policy: TriggerPolicy,
+    rule_states: Vec<RuleState>,
+    last_sample_times: HashMap<JavaLong, i64>,
+    //samples dropped by downsampling in pre window, backfilled if a trigger is fired in time
+    pending_samples: VecDeque<ThreadData>,
+    pending_bytes: usize,
+    high_res_end_time: i64,
+    downsample_states: HashMap<JavaLong, DownsampleState>,
+}
+
+impl TriggerEngine {
+    pub fn new(policy: TriggerPolicy) -> TriggerEngine {
+        let rule_states = policy.rules.iter().map(|_| RuleState {
+            active: false,
+            high_cpu_since: HashMap::new(),
+            method_keys: HashSet::new(),
+            samples: VecDeque::new(),
+            matched_count: 0,
+        }).collect();
+        TriggerEngine {
+            policy,
+            rule_states,
+            last_sample_times: HashMap::new(),
+            pending_samples: VecDeque::new(),
+            pending_bytes: 0,
+            high_res_end_time: 0,
+            downsample_states: HashMap::new(),
+        }
+    }
+
+    pub fn get_policy(&self) -> &TriggerPolicy {
+        &self.policy
+    }
+
+    pub fn has_method_rules(&self) -> bool {
+        self.policy.rules.iter().any(|rule| match rule.condition {
+            TriggerCondition::MethodPresence { .. } => true,
+            _ => false
+        })
+    }
+
+    pub fn on_method_info(&mut self, method_key: i64, method_name: &str) {
+        for (rule, state) in self.policy.rules.iter().zip(self.rule_states.iter_mut()) {
+            if let TriggerCondition::MethodPresence { method, .. } = &rule.condition {
+                if method_name.contains(method.as_str()) {
+                    state.method_keys.insert(method_key);
+                }
+            }
+        }
+    }
+
+    /// Evaluate rules on the sample, return the fired markers.
+    pub fn on_thread_sample(&mut self, thread: &ThreadData, live_threads: usize) -> Vec<TriggerMarker> {
+        let now = thread.sample_time;
+        let last_sample_time = self.last_sample_times.insert(thread.id, now).unwrap_or(0);
+        //cpu_time_delta is nanos
+        let cpu_percent = if last_sample_time > 0 && now > last_sample_time {
+            thread.cpu_time_delta as f64 / ((now - last_sample_time) * 10_000) as f64
+        } else {
+            0.0
+        };
+
+        let mut markers = vec![];
+        for (rule, state) in self.policy.rules.iter().zip(self.rule_states.iter_mut()) {
+            let fired = match &rule.condition {
+                TriggerCondition::ThreadCpu { percent, duration_ms } => {
+                    if cpu_percent > *percent {
+                        state.high_cpu_since.entry(thread.id).or_insert(last_sample_time);
+                    } else {
+                        state.high_cpu_since.remove(&thread.id);
+                    }
+                    state.high_cpu_since.iter()
+                        .filter(|(_, since)| now - **since >= *duration_ms)
+                        .min_by_key(|(_, since)| **since)
+                        .map(|(thread_id, since)| (*thread_id, format!("thread {} cpu above {}% for {}ms", thread_id, percent, now - since)))
+                },
+                TriggerCondition::ThreadCount { max_threads } => {
+                    if live_threads > *max_threads {
+                        Some((0, format!("thread count {} above {}", live_threads, max_threads)))
+                    } else {
+                        None
+                    }
+                },
+                TriggerCondition::MethodPresence { method, percent, window_ms } => {
+                    let matched = thread.stacktrace.iter().any(|x| state.method_keys.contains(x));
+                    state.samples.push_back((now, matched));
+                    if matched {
+                        state.matched_count += 1;
+                    }
+                    while let Some((time, matched)) = state.samples.front().cloned() {
+                        if time > now - window_ms {
+                            break;
+                        }
+                        state.samples.pop_front();
+                        if matched {
+                            state.matched_count -= 1;
+                        }
+                    }
+                    let presence = state.matched_count as f64 * 100.0 / state.samples.len() as f64;
+                    if state.samples.len() >= MIN_PRESENCE_SAMPLES && presence > *percent {
+                        Some((0, format!("method {} in {:.1}% of samples", method, presence)))
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            match fired {
+                Some((thread_id, message)) => {
+                    //keep full resolution until the condition is cleared
+                    self.high_res_end_time = self.high_res_end_time.max(now + self.policy.post_window_ms);
+                    if !state.active {
+                        state.active = true;
+                        println!("trigger rule is fired: {}, {}", rule.name, message);
+                        markers.push(TriggerMarker {
+                            rule_name: rule.name.clone(),
+                            time: now,
+                            thread_id,
+                            message,
+                            window_start: now - self.policy.pre_window_ms,
+                            window_end: now + self.policy.post_window_ms,
+                        });
+                    }
+                },
+                None => state.active = false
+            }
+        }
+        markers
+    }
+
+    //drop states of the ended thread
+    pub fn on_thread_end(&mut self, thread_id: JavaLong) {
+        self.last_sample_times.remove(&thread_id);
+        self.downsample_states.remove(&thread_id);
+        for state in self.rule_states.iter_mut() {
+            state.high_cpu_since.remove(&thread_id);
+        }
+    }
+
+    //end time of current full resolution window
+    pub fn get_high_res_end_time(&self) -> i64 {
+        self.high_res_end_time
+    }
+
+    /// Return the sample to be saved now and the dropped samples of pre window to be backfilled,
+    /// the downsampled stream is saved without delay.
+    pub fn filter_sample(&mut self, thread: ThreadData) -> (Option<ThreadData>, Vec<ThreadData>) {
+        let now = thread.sample_time;
+        if now <= self.high_res_end_time {
+            self.evict_pending_samples(now);
+            let backfill = self.pending_samples.drain(..).collect();
+            self.pending_bytes = 0;
+            return (self.take_sample(thread, false), backfill);
+        }
+        let sample = self.take_sample(thread, true);
+        self.evict_pending_samples(now);
+        (sample, vec![])
+    }
+
+    //remove the samples out of pre window or exceeded max pending bytes
+    fn evict_pending_samples(&mut self, now: i64) {
+        while let Some(first) = self.pending_samples.front() {
+            if first.sample_time >= now - self.policy.pre_window_ms && self.pending_bytes <= self.policy.max_pending_bytes {
+                break;
+            }
+            self.pending_bytes -= estimate_sample_size(first);
+            self.pending_samples.pop_front();
+        }
+    }
+
+    fn take_sample(&mut self, mut thread: ThreadData, downsample: bool) -> Option<ThreadData> {
+        let ratio = self.policy.downsample_ratio;
+        //the first sample of a thread is always saved
+        let state = self.downsample_states.entry(thread.id).or_insert(DownsampleState { skipped: ratio, cpu_time_delta: 0 });
+        state.cpu_time_delta += thread.cpu_time_delta;
+        if downsample && state.skipped + 1 < ratio {
+            state.skipped += 1;
+            //cpu time delta is added to the next saved sample, not counted again if backfilled
+            thread.cpu_time_delta = 0;
+            self.pending_bytes += estimate_sample_size(&thread);
+            self.pending_samples.push_back(thread);
+            return None;
+        }
+        thread.cpu_time_delta = state.cpu_time_delta;
+        state.skipped = 0;
+        state.cpu_time_delta = 0;
+        Some(thread)
+    }
+}
+
+fn estimate_sample_size(thread: &ThreadData) -> usize {
+    SAMPLE_BASE_SIZE + thread.name.len() + thread.state.len() + thread.stacktrace.len() * 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_policy(downsample_ratio: u32, max_pending_bytes: usize) -> TriggerPolicy {
+        TriggerPolicy {
+            rules: vec![TriggerRule {
+                name: "threads".to_string(),
+                condition: TriggerCondition::ThreadCount { max_threads: 1 },
+            }],
+            pre_window_ms: 1000,
+            post_window_ms: 500,
+            downsample_ratio,
+            max_pending_bytes,
+        }
+    }
+
+    fn new_sample(thread_id: JavaLong, sample_time: i64) -> ThreadData {
+        let mut thread = ThreadData::new(thread_id, "worker");
+        thread.sample_time = sample_time;
+        thread.cpu_time_delta = 1000;
+        thread.stacktrace = vec![1, 2, 3];
+        thread
+    }
+
+    #[test]
+    fn test_downsampled_stream_is_saved_immediately() {
+        let mut engine = TriggerEngine::new(new_policy(5, 1024 * 1024));
+        let mut saved = vec![];
+        for i in 0..11 {
+            let thread = new_sample(1, i * 10);
+            assert!(engine.on_thread_sample(&thread, 1).is_empty());
+            let (sample, backfill) = engine.filter_sample(thread);
+            assert!(backfill.is_empty());
+            saved.extend(sample);
+        }
+        let times: Vec<i64> = saved.iter().map(|x| x.sample_time).collect();
+        assert_eq!(times, vec![0, 50, 100]);
+        //cpu time of the dropped samples is added to the saved one
+        assert_eq!(saved[1].cpu_time_delta, 5000);
+    }
+
+    #[test]
+    fn test_backfill_pre_window_when_fired() {
+        let mut engine = TriggerEngine::new(new_policy(5, 1024 * 1024));
+        for i in 0..200 {
+            let thread = new_sample(1, i * 10);
+            engine.on_thread_sample(&thread, 1);
+            engine.filter_sample(thread);
+        }
+        let thread = new_sample(1, 2000);
+        let markers = engine.on_thread_sample(&thread, 2);
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].window_start, 1000);
+        let (sample, backfill) = engine.filter_sample(thread);
+        assert_eq!(sample.map(|x| x.sample_time), Some(2000));
+        //dropped samples in pre window only, without cpu time counted in the downsampled stream
+        assert_eq!(backfill.len(), 80);
+        assert!(backfill.iter().all(|x| x.sample_time >= 1000 && x.sample_time % 50 != 0 && x.cpu_time_delta == 0));
+
+        //full resolution in post window
+        let (sample, backfill) = engine.filter_sample(new_sample(1, 2010));
+        assert!(sample.is_some());
+        assert!(backfill.is_empty());
+    }
+
+    #[test]
+    fn test_pending_samples_byte_cap() {
+        let sample_size = estimate_sample_size(&new_sample(1, 0));
+        let mut engine = TriggerEngine::new(new_policy(100, sample_size * 10));
+        for i in 0..100 {
+            let thread = new_sample(1, i);
+            engine.filter_sample(thread);
+        }
+        assert!(engine.pending_bytes <= sample_size * 10);
+        let thread = new_sample(1, 100);
+        engine.on_thread_sample(&thread, 2);
+        let (_, backfill) = engine.filter_sample(thread);
+        assert_eq!(backfill.len(), 10);
+        assert_eq!(backfill[0].sample_time, 90);
+        assert_eq!(engine.pending_bytes, 0);
+    }
+
+    #[test]
+    fn test_hot_thread_ended() {
+        let mut policy = new_policy(1, 1024);
+        policy.rules[0].condition = TriggerCondition::ThreadCpu { percent: 50.0, duration_ms: 100 };
+        let mut engine = TriggerEngine::new(policy);
+        for i in 0..6 {
+            let mut thread = new_sample(1, i * 10);
+            thread.cpu_time_delta = 10_000_000;
+            assert!(engine.on_thread_sample(&thread, 2).is_empty());
+            engine.filter_sample(thread);
+        }
+        engine.on_thread_end(1);
+        assert!(engine.last_sample_times.get(&1).is_none());
+        assert!(engine.downsample_states.get(&1).is_none());
+        assert!(engine.rule_states[0].high_cpu_since.is_empty());
+
+        //the ended thread is not reported by samples of other threads
+        for i in 10..30 {
+            let thread = new_sample(2, i * 10);
+            assert!(engine.on_thread_sample(&thread, 1).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_validate_policy() {
+        assert!(new_policy(10, 1024).validate().is_ok());
+        assert!(new_policy(0, 1024).validate().is_err());
+        assert!(new_policy(10, 0).validate().is_err());
+        let mut policy = new_policy(10, 1024);
+        policy.pre_window_ms = MAX_PRE_WINDOW_MS + 1;
+        assert!(policy.validate().is_err());
+    }
+}
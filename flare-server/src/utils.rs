@@ -65,19 +65,23 @@ pub fn wrap_response<T: ?Sized>(cmd: &str, value: &T) -> OwnedMessage
     where
         T: Serialize,
 {
-    let response = FlareResponse {
-        result: "success".to_string(),
-        cmd: cmd.to_string(),
-        data: Box::new(value)
-    };
-    OwnedMessage::Text(serde_json::to_string(&response).unwrap())
+    wrap_message("success", cmd, None, value)
 }
 
 pub fn wrap_error_response(cmd: &str, message: &str) -> OwnedMessage {
+    wrap_message("failure", cmd, None, &json!({ "message": message }))
+}
+
+//result: success, failure or progress, request_id is echoed if the request has it
+pub fn wrap_message<T: ?Sized>(result: &str, cmd: &str, request_id: Option<&serde_json::Value>, value: &T) -> OwnedMessage
+    where
+        T: Serialize,
+{
     let response = FlareResponse {
-        result: "failure".to_string(),
+        result: result.to_string(),
         cmd: cmd.to_string(),
-        data: Box::new(json!({ "message": message }))
+        request_id: request_id.cloned(),
+        data: Box::new(value)
     };
     OwnedMessage::Text(serde_json::to_string(&response).unwrap())
 }
@@ -127,6 +131,10 @@ pub fn get_option_as_int_array(options: &serde_json::Map<String, serde_json::Val
     if val.is_none() {
         return Err(new_invalid_input_error(&format!("missing option: {}", key)));
     }
+    //single value is treated as one element array, e.g. http query param "thread_ids=5"
+    if let Some(x) = val.unwrap().as_i64() {
+        return Ok(vec![x]);
+    }
     let val = val.unwrap().as_array();
     if val.is_none() {
         return Err(new_invalid_input_error(&format!("option '{}' is not int array ", key)));
@@ -146,10 +154,71 @@ pub fn get_option_as_int_array(options: &serde_json::Map<String, serde_json::Val
     Ok(data)
 }
 
+pub fn get_option_as_str_array(options: &serde_json::Map<String, serde_json::Value>, key: &str) -> io::Result<Vec<String>> {
+    let val = options.get(key);
+    if val.is_none() {
+        return Err(new_invalid_input_error(&format!("missing option: {}", key)));
+    }
+    if let Some(x) = val.unwrap().as_str() {
+        return Ok(vec![x.trim().to_string()]);
+    }
+    let val = val.unwrap().as_array();
+    if val.is_none() {
+        return Err(new_invalid_input_error(&format!("option '{}' is not string array ", key)));
+    }
+    let mut data = vec![];
+    for v in val.unwrap() {
+        match v.as_str() {
+            Some(x) => {
+                data.push(x.trim().to_string());
+            },
+            None => {
+                return Err(new_invalid_input_error(&format!("option '{}' contains none string value: {} ", key, v)));
+            },
+        }
+    }
+    Ok(data)
+}
+
+//stable method key, shared with agent flight recorder
+pub use flare_utils::hash_method_key;
+
+//simple wildcard match, '*' matches any chars, '?' matches one char
+pub fn match_wildcard(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
 pub fn new_error(kind: ErrorKind, msg: &str) -> io::Error {
     io::Error::new(kind, msg)
 }
 
 pub fn new_invalid_input_error(msg: &str) -> io::Error {
     io::Error::new(ErrorKind::InvalidInput, msg)
+}
+
+//parse value of command line flag
+pub fn parse_flag_value<T: std::str::FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| new_invalid_input_error(&format!("invalid value of flag {}: {}", name, value)))
 }
\ No newline at end of file
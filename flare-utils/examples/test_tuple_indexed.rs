@@ -42,6 +42,7 @@ fn main() -> io::Result<()> {
     println!("test get_range_value: {:?} - {:?}", start_index, end_index);
     tuple_file.get_range_value(&start_index, &end_index, |vec| {
         println!("value: {:?}", vec);
+        true
     });
 
     Ok(())
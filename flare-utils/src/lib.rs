@@ -18,6 +18,7 @@ pub mod tuple_indexed;
 pub mod file_utils;
 pub mod collections;
 pub mod stopwatch;
+pub mod sample_format;
 
 use byteorder::{WriteBytesExt, ReadBytesExt, NetworkEndian};
 use std::io;
@@ -48,4 +49,17 @@ fn get_unit_len(value_type: ValueType) -> i8{
         INT64 => 8,
 //        FLOAT64=> 8
     }
-}
\ No newline at end of file
+}
+//stable method key: FNV-1a hash of class name, method name and descriptor,
+//keep 53 bits so that the key is safe as javascript number
+pub fn hash_method_key(class_name: &str, method_name: &str, signature: &str) -> i64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in &[class_name, ".", method_name, signature] {
+        for b in part.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    let key = (hash & 0x1F_FFFF_FFFF_FFFF) as i64;
+    if key == 0 { 1 } else { key }
+}
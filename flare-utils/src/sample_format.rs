@@ -0,0 +1,119 @@
+//! Serialized data of sample dir, written by flare-server and flare-agent's flight recorder.
+use serde_derive::{Serialize, Deserialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ThreadData {
+    pub id: i64,
+    pub name: String,
+    pub priority: u32,
+    pub daemon: bool,
+    pub state: String,
+    pub cpu_time: i64,
+    pub cpu_time_delta: i64,
+    pub sample_time: i64,
+    #[serde(default = "default_sample_count")]
+    pub sample_count: i64,
+    pub stacktrace: Vec<i64>,
+
+    //thread lifecycle: start/end time (ms), lifetime = end_time - start_time
+    #[serde(default)]
+    pub start_time: i64,
+    #[serde(default)]
+    pub end_time: i64,
+    #[serde(default)]
+    pub lifetime: i64,
+
+    //dynamic calc attrs
+    #[serde(default)]
+    pub duration: i64,
+    #[serde(default)]
+    pub self_duration: i64,
+    #[serde(default)]
+    pub self_cpu_time: i64
+}
+
+fn default_sample_count() -> i64 {
+    1
+}
+
+impl ThreadData {
+    pub fn new(id: i64, name: &str) -> ThreadData {
+        ThreadData {
+            id,
+            name: name.to_string(),
+            priority: 0,
+            daemon: false,
+            state: "".to_string(),
+            cpu_time: 0,
+            cpu_time_delta: 0,
+            sample_time: 0,
+            sample_count: 0,
+            stacktrace: vec![],
+            start_time: 0,
+            end_time: 0,
+            lifetime: 0,
+            duration: 0,
+            self_duration: 0,
+            self_cpu_time: 0
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.end_time == 0
+    }
+}
+
+/// Time range without samples because the agent connection was lost.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TimeGap {
+    pub start_time: i64,
+    pub end_time: i64,
+}
+
+/// A fired trigger saved in the recording, samples of the window are kept in full resolution.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TriggerMarker {
+    pub rule_name: String,
+    pub time: i64,
+    //thread of thread cpu rule, otherwise 0
+    pub thread_id: i64,
+    pub message: String,
+    pub window_start: i64,
+    //extended while the condition holds
+    pub window_end: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SampleInfo {
+    pub sample_interval: i64,
+    pub sample_start_time: i64,
+    pub record_start_time: i64,
+    pub last_record_time: i64,
+    pub agent_addr: String,
+    pub sample_data_dir: String,
+    //name of the first sample dir, shared by all rolled dirs of one recording
+    #[serde(default)]
+    pub recording_id: String,
+    //start time of current sample dir
+    #[serde(default)]
+    pub segment_start_time: i64,
+    #[serde(default)]
+    pub jvm_pid: i64,
+    #[serde(default)]
+    pub main_class: String,
+    //time ranges of lost agent connection
+    #[serde(default)]
+    pub gaps: Vec<TimeGap>,
+    #[serde(default)]
+    pub reconnecting: bool,
+    //fired triggers of conditional recording
+    #[serde(default)]
+    pub markers: Vec<TriggerMarker>,
+}
+
+/// Content of summary_info.json of sample dir.
+#[derive(Serialize, Deserialize)]
+pub struct SummaryInfo {
+    pub sample_info: SampleInfo,
+    pub threads: Vec<ThreadData>
+}
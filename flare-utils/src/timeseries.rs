@@ -47,7 +47,7 @@ pub struct TSResult {
     pub data: TSRangeValue
 }
 
-#[derive( Debug )]
+#[derive( Debug, Clone )]
 pub struct TimeSeriesFile {
     //file path
     pub path: String,
@@ -296,6 +296,14 @@ impl TimeSeriesFileReader {
         }
     }
 
+    /// Reader of the data flushed by a writer, the header info is copied so later writes are not visible.
+    pub fn from_header_info(info: &TimeSeriesFile) -> TimeSeriesFileReader {
+        TimeSeriesFileReader {
+            info: info.clone(),
+            inited: true
+        }
+    }
+
     fn init(&mut self) -> Result<bool, Error> {
         if !self.inited {
             let info = &mut self.info;
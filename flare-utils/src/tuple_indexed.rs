@@ -10,6 +10,7 @@ use std::str::from_utf8;
 use num::{FromPrimitive, PrimInt};
 use std::collections::{HashMap, VecDeque};
 use std::cmp::*;
+use std::sync::{Arc, RwLock};
 
 use super::FileEndian;
 use super::file_utils::*;
@@ -79,6 +80,34 @@ fn get_value_type(value: &TupleValue) -> ValueType {
     }
 }
 
+//append-only index of the file, shared by the writer and its snapshot readers
+#[derive( Debug, Default )]
+struct IndexData {
+    //index values and bulk offsets in appended order
+    index_vec: Vec<TupleValue>,
+    offset_vec: Vec<TupleValue>,
+    //index value -> position of the last appended entry
+    index_map: HashMap<TupleValue, usize>,
+}
+
+impl IndexData {
+    fn push(&mut self, index: TupleValue, bulk_offset: TupleValue) {
+        self.index_map.insert(index.clone(), self.index_vec.len());
+        self.index_vec.push(index);
+        self.offset_vec.push(bulk_offset);
+    }
+
+    //position of the last entry of the index within the visible len
+    fn find(&self, index: &TupleValue, len: usize) -> Option<usize> {
+        match self.index_map.get(index) {
+            Some(pos) if *pos < len => Some(*pos),
+            //appended again after the snapshot
+            Some(_) => self.index_vec[..len].iter().rposition(|x| x == index),
+            None => None
+        }
+    }
+}
+
 ///
 /// save tuple values in indexed file
 #[derive( Debug )]
@@ -86,8 +115,9 @@ pub struct TupleIndexedFile {
     //state
     inited: bool,
     writable: bool,
-    index_map: HashMap<TupleValue, TupleValue>,
-    index_vec: Vec<TupleValue>,
+    index: Arc<RwLock<IndexData>>,
+    //published len of the index visible to a snapshot reader, None means all
+    snapshot_len: Option<usize>,
 
     //indexed file
     pub indexed_path: String,
@@ -136,6 +166,31 @@ impl TupleIndexedFile {
         Ok(tuple_file)
     }
 
+    /// Reader of the flushed values, the index is shared and the values added later are not visible.
+    pub fn new_snapshot_reader(&self) -> TupleIndexedFile {
+        TupleIndexedFile {
+            inited: self.inited,
+            writable: false,
+            index: self.index.clone(),
+            snapshot_len: Some(self.get_visible_len()),
+            indexed_path: self.indexed_path.clone(),
+            indexed_data_offset: self.indexed_data_offset,
+            extra_path: self.extra_path.clone(),
+            extra_data_offset: self.extra_data_offset,
+            bulk_buffer: VecDeque::new(),
+            last_flush_bulk_time: self.last_flush_bulk_time,
+            bulk_flush_interval_time: self.bulk_flush_interval_time,
+            bulk_buffer_bytes: 0,
+            bulk_buffer_bytes_limit: self.bulk_buffer_bytes_limit,
+            index_type: self.index_type,
+            bulk_offset_type: self.bulk_offset_type,
+            unit_len: self.unit_len,
+            begin_time: self.begin_time,
+            end_time: self.end_time,
+            amount: self.amount
+        }
+    }
+
     pub fn new_writer(path: &str, index_type: ValueType) -> Result<TupleIndexedFile, io::Error> {
 
         let mut tuple_file = TupleIndexedFile::new(path,
@@ -155,8 +210,8 @@ impl TupleIndexedFile {
         Ok(TupleIndexedFile {
             inited: false,
             writable,
-            index_map: HashMap::new(),
-            index_vec: vec![],
+            index: Arc::new(RwLock::new(IndexData::default())),
+            snapshot_len: None,
             indexed_path,
             indexed_data_offset: 0,
             extra_path,
@@ -339,8 +394,8 @@ impl TupleIndexedFile {
             let bulk_offset_value = TupleValue::uint32(bulk_offset as u32);
             self.write_indexed_value(&mut indexed_file,&bulk_offset_value, self.bulk_offset_type);
 
-            self.index_vec.push(index.clone());
-            self.index_map.insert(index, bulk_offset_value);
+            //the bulk data is written before it is visible to snapshot readers
+            self.index.write().unwrap().push(index, bulk_offset_value);
             self.amount += 1;
             self.bulk_buffer_bytes -= bulk_value.len();
         }
@@ -416,11 +471,11 @@ impl TupleIndexedFile {
         file.seek(SeekFrom::Start(self.indexed_data_offset));
 
         let mut reader = BufReader::new(file);
+        let mut index = self.index.write().unwrap();
         loop {
             if let Ok(index_value) = TupleIndexedFile::read_indexed_value(&mut reader, &self.index_type) {
                 if let Ok(bulk_offset_value) = TupleIndexedFile::read_indexed_value(&mut reader, &self.bulk_offset_type) {
-                    self.index_vec.push(index_value.clone());
-                    self.index_map.insert(index_value, bulk_offset_value);
+                    index.push(index_value, bulk_offset_value);
                 } else {
                     break;
                 }
@@ -434,13 +489,15 @@ impl TupleIndexedFile {
     ///
     /// read bulk value by index
     ///
-    pub fn get_value(&mut self, index: &TupleValue) -> io::Result<Vec<u8>> {
-        let mut bulk_offset = 0u32;
-        if let Some(TupleValue::uint32(offset)) = self.index_map.get(index) {
-            bulk_offset = *offset;
-        } else {
-            return Err(io::Error::new(ErrorKind::NotFound, "index not found"));
-        }
+    pub fn get_value(&self, index: &TupleValue) -> io::Result<Vec<u8>> {
+        let bulk_offset = {
+            let index_data = self.index.read().unwrap();
+            let pos = index_data.find(index, self.get_visible_len_of(&index_data));
+            match pos.map(|pos| &index_data.offset_vec[pos]) {
+                Some(TupleValue::uint32(offset)) => *offset,
+                _ => return Err(io::Error::new(ErrorKind::NotFound, "index not found"))
+            }
+        };
         let mut extra_file = self.get_extra_file()?;
         let (buf, offset) = TupleIndexedFile::read_bulk_data(&mut extra_file, bulk_offset)?;
         Ok(buf)
@@ -456,8 +513,9 @@ impl TupleIndexedFile {
         Ok((buf, new_offset as u32))
     }
 
-    fn read_range_bulk_data(extra_file: &mut File, start_offset: u32, end_offset: u32) -> Result<Vec<Vec<u8>>, Error> {
-        let mut result = Vec::with_capacity(1024);
+    //read the bulk data between offsets, stop if the handler returns false
+    fn read_range_bulk_data<F>(extra_file: &mut File, start_offset: u32, end_offset: u32, handler: &mut F) -> Result<(), Error>
+        where F: FnMut(Vec<u8>) -> bool {
         let mut read_pos = start_offset;
         extra_file.seek(SeekFrom::Start(start_offset as u64));
         //buf 32K
@@ -466,35 +524,36 @@ impl TupleIndexedFile {
             let bytes_to_read = reader.read_u16::<FileEndian>()? as usize;
             let mut buf = vec![0u8; bytes_to_read];
             reader.read_exact(&mut buf)?;
-            result.push(buf);
             read_pos += 2;
             read_pos += bytes_to_read as u32;
+            if !handler(buf) {
+                break;
+            }
         }
-        Ok(result)
+        Ok(())
     }
 
-    pub fn get_range_value<F>(&mut self, start_index: &TupleValue, end_index: &TupleValue, mut handler: F) -> io::Result<()>
-        where F: FnMut(Vec<u8>) {
+    /// Visit the values in index range, the handler returns false to stop reading.
+    pub fn get_range_value<F>(&self, start_index: &TupleValue, end_index: &TupleValue, mut handler: F) -> io::Result<()>
+        where F: FnMut(Vec<u8>) -> bool {
         //TODO 扩大范围，避免边界不完整
-        let new_start_index = self.search_index(start_index).clone();
-        let new_end_index = self.search_index(end_index).clone();
         let mut start_offset = 0u32;
         let mut end_offset = 0u32;
         let mut found = false;
-        if let Some(TupleValue::uint32(offset1)) = self.index_map.get(&new_start_index) {
-            start_offset = *offset1;
-            if let Some(TupleValue::uint32(offset2)) = self.index_map.get(&new_end_index) {
-                end_offset = *offset2;
-                found = true;
+        {
+            let index_data = self.index.read().unwrap();
+            if let Some((start_pos, end_pos)) = self.search_range(&index_data, start_index, end_index) {
+                if let (TupleValue::uint32(offset1), TupleValue::uint32(offset2)) = (&index_data.offset_vec[start_pos], &index_data.offset_vec[end_pos]) {
+                    start_offset = *offset1;
+                    end_offset = *offset2;
+                    found = true;
+                }
             }
         }
 
         if found {
             let mut extra_file = self.get_extra_file()?;
-            let buf_vec = TupleIndexedFile::read_range_bulk_data(&mut extra_file, start_offset, end_offset)?;
-            for buf in buf_vec {
-                handler(buf);
-            }
+            TupleIndexedFile::read_range_bulk_data(&mut extra_file, start_offset, end_offset, &mut handler)?;
 
 //            let mut offset = start_offset;
 //            loop {
@@ -512,38 +571,97 @@ impl TupleIndexedFile {
         }
     }
 
-    pub fn get_all_entries(&mut self) -> io::Result<Vec<(i64, Vec<u8>)>> {
-        let mut result = Vec::with_capacity(self.index_map.len());
+    /// Like get_range_value, the handler also receives the index value of each entry.
+    pub fn get_range_entries<F>(&self, start_index: &TupleValue, end_index: &TupleValue, mut handler: F) -> io::Result<()>
+        where F: FnMut(&TupleValue, Vec<u8>) -> bool {
+        let (start_offset, indexes) = {
+            let index_data = self.index.read().unwrap();
+            match self.search_range(&index_data, start_index, end_index) {
+                Some((start_pos, end_pos)) if start_pos <= end_pos => {
+                    (index_data.offset_vec[start_pos].as_int() as u64, index_data.index_vec[start_pos..=end_pos].to_vec())
+                },
+                Some(_) => return Ok(()),
+                None => return Err(io::Error::new(ErrorKind::NotFound, "index not found"))
+            }
+        };
+
+        //entries are appended in index order, read the bulk data sequentially
+        let mut extra_file = self.get_extra_file()?;
+        extra_file.seek(SeekFrom::Start(start_offset))?;
+        let mut reader = BufReader::with_capacity(32*1024, extra_file);
+        for index in &indexes {
+            let bytes_to_read = reader.read_u16::<FileEndian>()? as usize;
+            let mut buf = vec![0u8; bytes_to_read];
+            reader.read_exact(&mut buf)?;
+            if !handler(index, buf) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    //positions of the start and end entries in the visible index
+    fn search_range(&self, index_data: &IndexData, start_index: &TupleValue, end_index: &TupleValue) -> Option<(usize, usize)> {
+        let len = self.get_visible_len_of(index_data);
+        if len == 0 {
+            return None;
+        }
+        let index_vec = &index_data.index_vec[..len];
+        let start_pos = index_data.find(TupleIndexedFile::search_index(index_vec, start_index), len)?;
+        let end_pos = index_data.find(TupleIndexedFile::search_index(index_vec, end_index), len)?;
+        Some((start_pos, end_pos))
+    }
+
+    pub fn get_all_entries(&self) -> io::Result<Vec<(i64, Vec<u8>)>> {
+        //the last entry of each index
+        let mut entries: HashMap<i64, i64> = HashMap::new();
+        {
+            let index_data = self.index.read().unwrap();
+            let len = self.get_visible_len_of(&index_data);
+            for (k, v) in index_data.index_vec[..len].iter().zip(&index_data.offset_vec[..len]) {
+                entries.insert(k.as_int(), v.as_int());
+            }
+        }
+        let mut result = Vec::with_capacity(entries.len());
         let mut extra_file = self.get_extra_file()?;
-        for (k, v) in self.index_map.iter() {
+        for (k, v) in entries {
             //println!("entry: {:?} => {:?}", k, v);
-            result.push((k.as_int(), TupleIndexedFile::read_bulk_data(&mut extra_file, v.as_int() as u32)?.0));
+            result.push((k, TupleIndexedFile::read_bulk_data(&mut extra_file, v as u32)?.0));
         }
         Ok(result)
     }
 
-    fn search_index(&mut self, start_index: &TupleValue) -> &TupleValue {
-        match self.index_vec.binary_search(start_index) {
-            Ok(index) => &self.index_vec[index],
+    fn search_index<'a>(index_vec: &'a [TupleValue], start_index: &TupleValue) -> &'a TupleValue {
+        match index_vec.binary_search(start_index) {
+            Ok(index) => &index_vec[index],
             Err(index) => {
-                let index = min(index, self.index_vec.len() - 1);
-                &self.index_vec[index]
+                let index = min(index, index_vec.len() - 1);
+                &index_vec[index]
             },
         }
     }
 
     pub fn get_index_pairs(&self, start: usize, end: usize) -> Vec<(i64, i64)> {
-        let mut result = vec![];
-        for (i, idx) in self.index_vec.iter().enumerate() {
-            if i < start {
-                continue;
-            }
-            if i >= end {
-                break;
-            }
-            result.push((idx.as_int(), self.index_map.get(idx).as_ref().unwrap().as_int()));
+        let index_data = self.index.read().unwrap();
+        let len = self.get_visible_len_of(&index_data);
+        let end = min(end, len);
+        if start >= end {
+            return vec![];
+        }
+        index_data.index_vec[start..end].iter().zip(&index_data.offset_vec[start..end])
+            .map(|(idx, offset)| (idx.as_int(), offset.as_int())).collect()
+    }
+
+    fn get_visible_len(&self) -> usize {
+        let index_data = self.index.read().unwrap();
+        self.get_visible_len_of(&index_data)
+    }
+
+    fn get_visible_len_of(&self, index_data: &IndexData) -> usize {
+        match self.snapshot_len {
+            Some(len) => min(len, index_data.index_vec.len()),
+            None => index_data.index_vec.len()
         }
-        result
     }
 
 }